@@ -0,0 +1,189 @@
+/*
+ * Packet layer benchmarks, cargo bench [filter]
+ *
+ * Contexts draw keys and nonces from seeded generators, so a fresh context
+ * makes the same packets every iteration and one recorded handshake gives
+ * each side valid input from the other to work on.
+ */
+
+#[macro_use]
+extern crate criterion;
+extern crate curvecp;
+
+use std::hint::black_box;
+use std::sync::Arc;
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput};
+use curvecp::config::{ClientConfig, ServerConfig};
+use curvecp::libcurvecp::*;
+use curvecp::rng::{Rng, SeededRng};
+
+const SERVER_NAME:&str = "bench.curvecp.example";
+const SIZES:[usize; 6] = [16, 64, 256, 512, 1024, CCP_MAX_MESSAGE_SIZE];
+
+type Datagram = ([u8; CCP_MAX_PACKET_SIZE], usize);
+
+fn configs() -> (ClientConfig, ServerConfig) {
+    let (serverpk, serversk) = SeededRng::new(100).keypair();
+    let (clientpk, clientsk) = SeededRng::new(101).keypair();
+    let client = ClientConfig::new(serverpk)
+        .client_keypair(clientpk, clientsk)
+        .server_name(SERVER_NAME);
+    (client, ServerConfig::new(serverpk, serversk))
+}
+
+fn client() -> CCPContext {
+    CCPContext::with_rng(Arc::new(SeededRng::new(1)))
+}
+
+fn server() -> CCPContext {
+    CCPContext::with_rng(Arc::new(SeededRng::new(2)))
+}
+
+fn make<F>(f: F) -> Datagram
+    where F: FnOnce(&mut [u8; CCP_MAX_PACKET_SIZE]) -> isize {
+    let mut buf = [0; CCP_MAX_PACKET_SIZE];
+    let n = f(&mut buf);
+    assert!(n > 0, "packet not made: {}", n);
+    (buf, n as usize)
+}
+
+/*
+ * Both contexts established, and every packet it took
+ */
+struct Handshake {
+    client: CCPContext,
+    server: CCPContext,
+    hello: Datagram,
+    cookie: Datagram,
+    initiate: Datagram,
+    message: Datagram
+}
+
+fn handshake(client_config: &ClientConfig, server_config: &ServerConfig) -> Handshake {
+    let mut client = client();
+    let mut server = server();
+    let hello = make(|buf| client.mk_client_hello(buf, client_config));
+    assert!(server.parse_client_hello(&hello.0, hello.1, server_config) > 0);
+    let cookie = make(|buf| server.mk_server_cookie(buf));
+    assert!(client.parse_server_cookie(&cookie.0, cookie.1) > 0);
+    let initiate = make(|buf| client.mk_client_initiate(buf, SERVER_NAME, &[0; 64]));
+    assert!(server.parse_client_initiate(&initiate.0, initiate.1) > 0);
+    let message = make(|buf| server.mk_server_message(buf, &[0; 64]));
+    Handshake { client, server, hello, cookie, initiate, message }
+}
+
+/*
+ * Each side's part of one handshake, from a fresh context through the
+ * first message, vouch check included
+ */
+fn bench_handshake(c: &mut Criterion) {
+    let (client_config, server_config) = configs();
+    let h = handshake(&client_config, &server_config);
+    let mut group = c.benchmark_group("handshake");
+    group.bench_function("client", |b| b.iter(|| {
+        let mut ctx = client();
+        let mut buf = [0; CCP_MAX_PACKET_SIZE];
+        assert!(ctx.mk_client_hello(&mut buf, &client_config) > 0);
+        assert!(ctx.parse_server_cookie(&h.cookie.0, h.cookie.1) > 0);
+        assert!(ctx.mk_client_initiate(&mut buf, SERVER_NAME, &[0; 64]) > 0);
+        assert!(ctx.parse_server_message(&h.message.0, h.message.1) > 0);
+        black_box(ctx)
+    }));
+    group.bench_function("server", |b| b.iter(|| {
+        let mut ctx = server();
+        let mut buf = [0; CCP_MAX_PACKET_SIZE];
+        assert!(ctx.parse_client_hello(&h.hello.0, h.hello.1, &server_config) > 0);
+        assert!(ctx.mk_server_cookie(&mut buf) > 0);
+        assert!(ctx.parse_client_initiate(&h.initiate.0, h.initiate.1) > 0);
+        assert!(ctx.verify_client_vouch());
+        assert!(ctx.mk_server_message(&mut buf, &[0; 64]) > 0);
+        black_box(ctx)
+    }));
+    group.finish();
+}
+
+/*
+ * What anyone can make the server do with one datagram, before it has
+ * any state for them
+ */
+fn bench_hello(c: &mut Criterion) {
+    let (client_config, server_config) = configs();
+    let h = handshake(&client_config, &server_config);
+    let mut forged = h.hello;
+    forged.0[200] ^= 1;
+    let mut wrong_ext = h.hello;
+    wrong_ext.0[8] ^= 1;
+
+    let mut group = c.benchmark_group("hello");
+    group.bench_function("answered", |b| b.iter(|| {
+        let mut ctx = server();
+        let mut buf = [0; CCP_MAX_PACKET_SIZE];
+        assert!(ctx.parse_client_hello(&h.hello.0, h.hello.1, &server_config) > 0);
+        assert!(ctx.mk_server_cookie(&mut buf) > 0);
+        black_box(buf)
+    }));
+    group.bench_function("forged_box", |b| b.iter(|| {
+        let mut ctx = server();
+        assert_eq!(ctx.parse_client_hello(&forged.0, forged.1, &server_config), -3);
+    }));
+    group.bench_function("wrong_ext", |b| b.iter(|| {
+        let mut ctx = server();
+        assert_eq!(ctx.parse_client_hello(&wrong_ext.0, wrong_ext.1, &server_config), -2);
+    }));
+    group.finish();
+}
+
+/*
+ * Message packets on established contexts, by payload size; seal and
+ * open work in the datagram buffer, mk and parse copy the message in or out
+ */
+fn bench_messages(c: &mut Criterion) {
+    let (client_config, server_config) = configs();
+    let mut h = handshake(&client_config, &server_config);
+    let mut buf = [0; CCP_MAX_PACKET_SIZE];
+
+    let mut group = c.benchmark_group("client_message");
+    for &size in SIZES.iter() {
+        let msg = vec![0x5a; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("mk", size), &msg, |b, msg| b.iter(|| {
+            assert!(h.client.mk_client_message(&mut buf, msg) > 0);
+        }));
+        let packet = make(|buf| h.client.mk_client_message(buf, &msg));
+        group.bench_with_input(BenchmarkId::new("parse", size), &packet, |b, packet| b.iter(|| {
+            assert!(h.server.parse_client_message(&packet.0, packet.1) > 0);
+        }));
+        group.bench_function(BenchmarkId::new("seal", size), |b| b.iter(|| {
+            assert!(h.client.seal_client_message(&mut buf, size) > 0);
+        }));
+        group.bench_with_input(BenchmarkId::new("open", size), &packet, |b, packet| b.iter_batched_ref(
+            || packet.0,
+            |buf| assert!(h.server.open_client_message(&mut buf[..packet.1]) > 0),
+            BatchSize::SmallInput));
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("server_message");
+    for &size in SIZES.iter() {
+        let msg = vec![0xa5; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("mk", size), &msg, |b, msg| b.iter(|| {
+            assert!(h.server.mk_server_message(&mut buf, msg) > 0);
+        }));
+        let packet = make(|buf| h.server.mk_server_message(buf, &msg));
+        group.bench_with_input(BenchmarkId::new("parse", size), &packet, |b, packet| b.iter(|| {
+            assert!(h.client.parse_server_message(&packet.0, packet.1) > 0);
+        }));
+        group.bench_function(BenchmarkId::new("seal", size), |b| b.iter(|| {
+            assert!(h.server.seal_server_message(&mut buf, size) > 0);
+        }));
+        group.bench_with_input(BenchmarkId::new("open", size), &packet, |b, packet| b.iter_batched_ref(
+            || packet.0,
+            |buf| assert!(h.client.open_server_message(&mut buf[..packet.1]) > 0),
+            BatchSize::SmallInput));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_handshake, bench_hello, bench_messages);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate curvecp;
+extern crate curvecp_fuzz;
+
+use curvecp::libcurvecp::CCPContext;
+use curvecp_fuzz::*;
+
+fuzz_target!(|data: &[u8]| {
+    let (buf, size) = packet(data);
+    CCPContext::new().parse_client_hello(&buf, size, &server_config());
+});
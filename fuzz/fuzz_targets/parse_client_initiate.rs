@@ -0,0 +1,16 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate curvecp_fuzz;
+
+use curvecp_fuzz::*;
+
+// server that has answered a Hello with a Cookie; vouch and name are checked too
+fuzz_target!(|data: &[u8]| {
+    let (buf, size) = packet(data);
+    let mut server = handshake("parse_client_initiate").server;
+    if server.parse_client_initiate(&buf, size) > 0 {
+        server.verify_client_vouch();
+        server.servername();
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate curvecp;
+extern crate curvecp_fuzz;
+
+use curvecp::message::Message;
+use curvecp_fuzz::*;
+
+// established server, messages that open are decoded too
+fuzz_target!(|data: &[u8]| {
+    let (buf, size) = packet(data);
+    let mut server = handshake("parse_client_message").server;
+    if server.parse_client_message(&buf, size) > 0 {
+        if let Some(m) = Message::decode(server.message()) {
+            m.acked();
+        }
+    }
+});
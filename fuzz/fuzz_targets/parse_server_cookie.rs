@@ -0,0 +1,12 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate curvecp_fuzz;
+
+use curvecp_fuzz::*;
+
+// client that has sent its Hello
+fuzz_target!(|data: &[u8]| {
+    let (buf, size) = packet(data);
+    handshake("parse_server_cookie").client.parse_server_cookie(&buf, size);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate curvecp_fuzz;
+
+// datagram sequences from many addresses into one server, see records()
+fuzz_target!(|data: &[u8]| {
+    curvecp_fuzz::session_table(data);
+});
@@ -0,0 +1,179 @@
+/*
+ * Shared setup for the fuzz targets.
+ *
+ * Every target starts from a context in the state its parser expects,
+ * built from seeded generators, so the same keys and nonces come out on
+ * every run and the valid packets mkcorpus writes to seeds/ open in the
+ * targets. Run a target with its seeds as a read-only corpus:
+ *
+ *   cargo fuzz run parse_client_initiate corpus/parse_client_initiate seeds/parse_client_initiate
+ */
+
+extern crate curvecp;
+
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use curvecp::clock::ManualClock;
+use curvecp::config::{ClientConfig, ServerConfig};
+use curvecp::libcurvecp::*;
+use curvecp::rng::{Rng, SeededRng};
+use curvecp::session::{ClientSession, Server};
+
+pub const SERVER_EXT:[u8; 16] = *b"fuzz-server-ext!";
+pub const CLIENT_EXT:[u8; 16] = *b"fuzz-client-ext!";
+pub const SERVER_NAME:&str = "fuzz.curvecp.example";
+
+pub const TARGETS:[&str; 6] = [
+    "parse_client_hello",
+    "parse_server_cookie",
+    "parse_client_initiate",
+    "parse_client_message",
+    "parse_server_message",
+    "session_table"
+];
+
+pub fn client_config() -> ClientConfig {
+    let (serverpk, _) = SeededRng::new(100).keypair();
+    let (pk, sk) = SeededRng::new(101).keypair();
+    ClientConfig::new(serverpk)
+        .client_keypair(pk, sk)
+        .client_ext(CLIENT_EXT)
+        .server_ext(SERVER_EXT)
+        .server_name(SERVER_NAME)
+        .rng(Arc::new(SeededRng::new(1)))
+}
+
+pub fn server_config() -> ServerConfig {
+    let (pk, sk) = SeededRng::new(100).keypair();
+    ServerConfig::new(pk, sk)
+        .server_ext(SERVER_EXT)
+        .rng(Arc::new(SeededRng::new(2)))
+}
+
+/*
+ * Fuzz input as a datagram; sizes past the buffer are passed on as is
+ */
+pub fn packet(data: &[u8]) -> ([u8; CCP_MAX_PACKET_SIZE], usize) {
+    let mut buf = [0; CCP_MAX_PACKET_SIZE];
+    let n = data.len().min(CCP_MAX_PACKET_SIZE);
+    buf[..n].copy_from_slice(&data[..n]);
+    (buf, data.len())
+}
+
+/*
+ * Contexts of both sides and every packet of one handshake, in order
+ */
+pub struct Handshake {
+    pub client: CCPContext,
+    pub server: CCPContext,
+    pub hello: Vec<u8>,
+    pub cookie: Vec<u8>,
+    pub initiate: Vec<u8>,
+    pub clientmessage: Vec<u8>,
+    pub servermessage: Vec<u8>
+}
+
+/*
+ * Handshake run up to and including the packet named by upto (a parser
+ * name from TARGETS), so the contexts wait for exactly that packet
+ */
+pub fn handshake(upto: &str) -> Handshake {
+    let client_config = client_config();
+    let server_config = server_config();
+    let mut client = CCPContext::with_rng(client_config.rng.clone());
+    let mut server = CCPContext::with_rng(server_config.rng.clone());
+    let mut buf = [0; CCP_MAX_PACKET_SIZE];
+
+    let hello = take(client.mk_client_hello(&mut buf, &client_config), &buf);
+    if upto != "parse_client_hello" {
+        server.parse_client_hello(&buf, hello.len(), &server_config);
+    }
+    let cookie = take(server.mk_server_cookie(&mut buf), &buf);
+    if upto != "parse_client_hello" && upto != "parse_server_cookie" {
+        client.parse_server_cookie(&buf, cookie.len());
+    }
+    let initiate = take(client.mk_client_initiate(&mut buf, SERVER_NAME, &[0; 64]), &buf);
+    if upto == "parse_client_message" || upto == "parse_server_message" {
+        server.parse_client_initiate(&buf, initiate.len());
+    }
+    let clientmessage = take(client.mk_client_message(&mut buf, &[1; 64]), &buf);
+    let servermessage = take(server.mk_server_message(&mut buf, &[2; 64]), &buf);
+    Handshake { client, server, hello, cookie, initiate, clientmessage, servermessage }
+}
+
+fn take(n: isize, buf: &[u8; CCP_MAX_PACKET_SIZE]) -> Vec<u8> {
+    assert!(n > 0, "packet not made: {}", n);
+    buf[..n as usize].to_vec()
+}
+
+/*
+ * session_table input: records of a two-byte big-endian length, the
+ * low byte of the sender's port, clock ticks of 100ms to advance before
+ * delivery, then the datagram
+ */
+pub fn records(datagrams: &[(u8, u8, Vec<u8>)]) -> Vec<u8> {
+    let mut out = vec![];
+    for &(port, ticks, ref d) in datagrams {
+        out.push((d.len() >> 8) as u8);
+        out.push(d.len() as u8);
+        out.push(port);
+        out.push(ticks);
+        out.extend_from_slice(d);
+    }
+    out
+}
+
+/*
+ * Feed records to a server session table, echoing whatever the
+ * sessions receive so their streams have something to do
+ */
+pub fn session_table(mut data: &[u8]) {
+    let clock = Arc::new(ManualClock::new());
+    let mut server = Server::new(server_config().clock(clock.clone()));
+    let mut buf = [0; CCP_MAX_PACKET_SIZE];
+    let mut msg = [0; 4096];
+    while data.len() >= 4 {
+        let len = (data[0] as usize) << 8 | data[1] as usize;
+        let from = ([127, 0, 0, 1], 10000 + data[2] as u16).into();
+        clock.advance(Duration::from_millis(100) * data[3] as u32);
+        let (d, rest) = data[4..].split_at(len.min(data.len() - 4));
+        data = rest;
+
+        let (datagram, size) = packet(d);
+        server.handle_datagram(&datagram, size, from);
+        while server.accept().is_some() {}
+        for id in server.session_ids() {
+            let session = server.session(&id).unwrap();
+            if let Ok(n) = session.read(&mut msg) {
+                let _ = session.write(&msg[..n]);
+            }
+        }
+        while server.poll_transmit(&mut buf).is_some() {}
+    }
+}
+
+/*
+ * A client's datagrams from a clean run against server_config(), in
+ * session_table records
+ */
+pub fn session_records() -> Vec<u8> {
+    let clock = Arc::new(ManualClock::new());
+    let mut client = ClientSession::new(client_config().clock(clock.clone()));
+    let mut server = Server::new(server_config().clock(clock.clone()));
+    let from = ([127, 0, 0, 1], 10001).into();
+    let mut buf = [0; CCP_MAX_PACKET_SIZE];
+    let mut sent = vec![];
+    let _ = client.write(b"hello fuzzer");
+    for _ in 0..4 {
+        while let Some(n) = client.poll_transmit(&mut buf) {
+            sent.push((1, 0, buf[..n].to_vec()));
+            server.handle_datagram(&buf, n, from);
+        }
+        while server.accept().is_some() {}
+        while let Some((n, _)) = server.poll_transmit(&mut buf) {
+            client.handle_datagram(&buf, n);
+        }
+    }
+    records(&sent)
+}
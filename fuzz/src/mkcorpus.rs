@@ -0,0 +1,41 @@
+/*
+ * mkcorpus [dir]
+ *
+ * Write the seed corpus, valid packets for each fuzz target, under
+ * dir/<target>/ (default seeds/). The targets rebuild the same contexts
+ * from seeded generators, so these packets open there.
+ */
+
+extern crate curvecp_fuzz;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use curvecp_fuzz::*;
+
+fn write(dir: &Path, target: &str, name: &str, data: &[u8]) {
+    let dir = dir.join(target);
+    let ret = fs::create_dir_all(&dir).and_then(|_| fs::write(dir.join(name), data));
+    if let Err(e) = ret {
+        eprintln!("mkcorpus: fatal: unable to write {}: {}", dir.display(), e);
+        process::exit(111);
+    }
+}
+
+fn main() {
+    let dir = env::args().nth(1).unwrap_or_else(|| String::from("seeds"));
+    let dir = Path::new(&dir);
+    for target in TARGETS.iter() {
+        if *target == "session_table" {
+            write(dir, target, "session", &session_records());
+            continue;
+        }
+        let h = handshake(target);
+        write(dir, target, "hello", &h.hello);
+        write(dir, target, "cookie", &h.cookie);
+        write(dir, target, "initiate", &h.initiate);
+        write(dir, target, "clientmessage", &h.clientmessage);
+        write(dir, target, "servermessage", &h.servermessage);
+    }
+}
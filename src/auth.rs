@@ -0,0 +1,144 @@
+/*
+ * Deciding which clients may open a session.
+ *
+ * The server asks its ClientAuthorizer after a valid Initiate, once the
+ * vouch proved the client owns the long-term key.
+ */
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use rustc_serialize::hex::FromHex;
+use keys::PublicKey;
+
+pub trait ClientAuthorizer: Send + Sync {
+    fn authorize(&self, clientlongtermpk: &PublicKey, servername: &str, addr: &SocketAddr) -> bool;
+}
+
+impl<F> ClientAuthorizer for F
+    where F: Fn(&PublicKey, &str, &SocketAddr) -> bool + Send + Sync {
+    fn authorize(&self, clientlongtermpk: &PublicKey, servername: &str, addr: &SocketAddr) -> bool {
+        self(clientlongtermpk, servername, addr)
+    }
+}
+
+struct AllowListState {
+    stamp: Option<Vec<(PathBuf, SystemTime, u64)>>,
+    keys: HashSet<PublicKey>
+}
+
+/*
+ * Allow-list of client long-term public keys.
+ *
+ * The path is either a file with one hex key per line ('#' starts a
+ * comment), or a directory where every file holds one key, hex or the
+ * raw 32 bytes like a key directory's publickey file. It is re-read
+ * whenever the modification time or size of the path, or of any file
+ * in the directory, changes; if it can't be read nobody is allowed in.
+ */
+pub struct AllowList {
+    path: PathBuf,
+    state: Mutex<AllowListState>
+}
+
+impl AllowList {
+    pub fn new<P: AsRef<Path>>(path: P) -> AllowList {
+        AllowList {
+            path: path.as_ref().to_path_buf(),
+            state: Mutex::new(AllowListState {
+                stamp: None,
+                keys: HashSet::new()
+            })
+        }
+    }
+
+    pub fn contains(&self, clientlongtermpk: &PublicKey) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let stamp = stamp(&self.path).ok();
+        if stamp.is_none() {
+            state.keys.clear();
+        } else if stamp != state.stamp {
+            state.keys = load_keys(&self.path).unwrap_or_default();
+        }
+        state.stamp = stamp;
+        state.keys.contains(clientlongtermpk)
+    }
+}
+
+impl ClientAuthorizer for AllowList {
+    fn authorize(&self, clientlongtermpk: &PublicKey, _servername: &str, _addr: &SocketAddr) -> bool {
+        self.contains(clientlongtermpk)
+    }
+}
+
+/*
+ * 32 raw bytes or 64 hex digits
+ */
+pub fn parse_key(data: &[u8]) -> Option<PublicKey> {
+    if data.len() == 32 {
+        return Some(PublicKey(*array_ref![data, 0, 32]));
+    }
+    let text = match ::std::str::from_utf8(data) {
+        Ok(text) => text.trim(),
+        Err(_) => return None
+    };
+    match text.from_hex() {
+        Ok(ref key) if key.len() == 32 => Some(PublicKey(*array_ref![key, 0, 32])),
+        _ => None
+    }
+}
+
+/*
+ * Modification time and size of path and, for a directory, of every file
+ * in it; a file edited in place leaves the directory's mtime alone
+ */
+fn stamp(path: &Path) -> io::Result<Vec<(PathBuf, SystemTime, u64)>> {
+    let meta = fs::metadata(path)?;
+    let mut stamp = vec![(path.to_path_buf(), meta.modified()?, meta.len())];
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() {
+                stamp.push((entry.path(), meta.modified()?, meta.len()));
+            }
+        }
+        stamp.sort();
+    }
+    Ok(stamp)
+}
+
+fn load_keys(path: &Path) -> io::Result<HashSet<PublicKey>> {
+    let mut keys = HashSet::new();
+    if fs::metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let mut data = vec![];
+            fs::File::open(entry.path())?.read_to_end(&mut data)?;
+            if let Some(key) = parse_key(&data) {
+                keys.insert(key);
+            }
+        }
+    } else {
+        let mut text = String::new();
+        fs::File::open(path)?.read_to_string(&mut text)?;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.len() != 64 {
+                continue;
+            }
+            if let Some(key) = parse_key(line.as_bytes()) {
+                keys.insert(key);
+            }
+        }
+    }
+    Ok(keys)
+}
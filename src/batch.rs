@@ -0,0 +1,233 @@
+/*
+ * Datagrams received and sent a batch at a time, for busy servers.
+ *
+ * Built with the "mmsg" feature on Linux, Batch::recv() takes whatever
+ * is queued on the socket, up to BATCH_SIZE datagrams, with one
+ * recvmmsg() and Batch::send() hands the whole batch to one sendmmsg().
+ * Otherwise recv() gets one datagram per call from recv_from() and send()
+ * loops over send_to(), so callers look the same either way. UDP GSO and
+ * GRO are not used: a batch mostly goes to different peers.
+ */
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use libcurvecp::CCP_MAX_PACKET_SIZE;
+
+#[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+use std::mem;
+#[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+#[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+use std::os::unix::io::AsRawFd;
+#[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+use std::ptr;
+#[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+use libc;
+
+pub const BATCH_SIZE:usize = 32;
+
+pub struct Batch {
+    bufs: Vec<[u8; CCP_MAX_PACKET_SIZE]>,
+    // size and peer of the datagram in the buffer of the same index
+    datagrams: Vec<(usize, SocketAddr)>
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch {
+            bufs: vec![[0; CCP_MAX_PACKET_SIZE]; BATCH_SIZE],
+            datagrams: Vec::with_capacity(BATCH_SIZE)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.datagrams.len() == BATCH_SIZE
+    }
+
+    pub fn clear(&mut self) {
+        self.datagrams.clear();
+    }
+
+    /*
+     * Datagram i: its buffer, size and peer
+     */
+    pub fn get(&self, i: usize) -> (&[u8; CCP_MAX_PACKET_SIZE], usize, SocketAddr) {
+        let (size, addr) = self.datagrams[i];
+        (&self.bufs[i], size, addr)
+    }
+
+    /*
+     * Add a copy of data for addr, false if the batch is full
+     */
+    pub fn push(&mut self, data: &[u8], addr: SocketAddr) -> bool {
+        self.push_with(|buf| {
+            buf[..data.len()].copy_from_slice(data);
+            Some((data.len(), addr))
+        })
+    }
+
+    /*
+     * Add the datagram make writes into the next buffer, false if the
+     * batch is full or make had nothing
+     */
+    pub fn push_with<F>(&mut self, make: F) -> bool
+        where F: FnOnce(&mut [u8; CCP_MAX_PACKET_SIZE]) -> Option<(usize, SocketAddr)> {
+        if self.is_full() {
+            return false;
+        }
+        match make(&mut self.bufs[self.datagrams.len()]) {
+            Some(datagram) => {
+                self.datagrams.push(datagram);
+                true
+            },
+            None => false
+        }
+    }
+
+    /*
+     * Replace the batch with received datagrams, waiting for the first as
+     * long as the socket's read timeout; how many there are
+     */
+    #[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        self.datagrams.clear();
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for ((msg, iov), (name, buf)) in msgs.iter_mut().zip(iovs.iter_mut())
+            .zip(names.iter_mut().zip(self.bufs.iter_mut())) {
+            iov.iov_base = buf.as_mut_ptr() as *mut libc::c_void;
+            iov.iov_len = CCP_MAX_PACKET_SIZE;
+            msg.msg_hdr.msg_name = name as *mut libc::sockaddr_storage as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+        }
+
+        // blocks for the first datagram only
+        let n = unsafe {
+            libc::recvmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), BATCH_SIZE as _,
+                           libc::MSG_WAITFORONE as _, ptr::null_mut())
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for i in 0..n as usize {
+            // keep buffers and datagrams in step past anything not from an IP peer
+            if let Some(from) = from_sockaddr(&names[i]) {
+                let j = self.datagrams.len();
+                self.bufs.swap(i, j);
+                self.datagrams.push((msgs[i].msg_len as usize, from));
+            }
+        }
+        Ok(self.datagrams.len())
+    }
+
+    #[cfg(not(all(feature = "mmsg", any(target_os = "linux", target_os = "android"))))]
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        self.datagrams.clear();
+        let datagram = socket.recv_from(&mut self.bufs[0])?;
+        self.datagrams.push(datagram);
+        Ok(1)
+    }
+
+    /*
+     * Send every datagram in the batch, which is then empty even if
+     * sending failed
+     */
+    #[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+    pub fn send(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        let count = self.datagrams.len();
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for (((msg, iov), (name, buf)), &(size, to)) in msgs.iter_mut().zip(iovs.iter_mut())
+            .zip(names.iter_mut().zip(self.bufs.iter_mut()))
+            .zip(self.datagrams.iter()) {
+            iov.iov_base = buf.as_mut_ptr() as *mut libc::c_void;
+            iov.iov_len = size;
+            msg.msg_hdr.msg_namelen = to_sockaddr(&to, name);
+            msg.msg_hdr.msg_name = name as *mut libc::sockaddr_storage as *mut libc::c_void;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+        }
+        self.datagrams.clear();
+
+        let mut sent = 0;
+        while sent < count {
+            let n = unsafe {
+                libc::sendmmsg(socket.as_raw_fd(), msgs[sent..].as_mut_ptr(), (count - sent) as _, 0)
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            sent += n as usize;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(all(feature = "mmsg", any(target_os = "linux", target_os = "android"))))]
+    pub fn send(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        let ret = self.datagrams.iter().zip(self.bufs.iter())
+            .try_for_each(|(&(size, to), buf)| socket.send_to(&buf[..size], to).map(|_| ()));
+        self.datagrams.clear();
+        ret
+    }
+}
+
+impl Default for Batch {
+    fn default() -> Batch {
+        Batch::new()
+    }
+}
+
+#[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+fn from_sockaddr(name: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match name.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(name as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+        },
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(name as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(sin6.sin6_port),
+                                                  sin6.sin6_flowinfo, sin6.sin6_scope_id)))
+        },
+        _ => None
+    }
+}
+
+/*
+ * Write addr into name, which is zeroed; the length of what was written
+ */
+#[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+fn to_sockaddr(addr: &SocketAddr, name: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    match *addr {
+        SocketAddr::V4(ref a) => {
+            let sin = unsafe { &mut *(name as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        },
+        SocketAddr::V6(ref a) => {
+            let sin6 = unsafe { &mut *(name as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_scope_id = a.scope_id();
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
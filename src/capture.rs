@@ -0,0 +1,351 @@
+/*
+ * Making sense of captured CurveCP traffic.
+ *
+ * Client packets name their session outright: extensions plus client
+ * short-term key. Server packets carry only the extensions, so they go
+ * to the latest session with those extensions between the same two
+ * addresses. Every session counts its packets, notes anomalies (format
+ * violations, packets out of handshake order, nonces that don't grow)
+ * and, when its boxes open, keeps the messages as a transcript.
+ *
+ * Hello and Cookie boxes open with the long-term keys in Keys. The
+ * Initiate and Message boxes need a short-term secret key of either
+ * side, a list of those is tried on every session until one opens.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+use rustc_serialize::hex::ToHex;
+use dissect::*;
+use keys::*;
+use libcurvecp::PacketKind;
+use message::{Eof, Message};
+use pcap::Datagram;
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    // since the first datagram of the capture
+    pub time: Duration,
+    pub from_client: bool,
+    pub id: u32,
+    pub offset: u64,
+    pub eof: Eof,
+    pub data: Vec<u8>,
+    // same data was seen before in this direction
+    pub retransmit: bool
+}
+
+#[derive(Clone, Debug)]
+pub struct CaptureSession {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub clientext: [u8; 16],
+    pub serverext: [u8; 16],
+    pub clientshorttermpk: PublicKey,
+    pub servershorttermpk: Option<PublicKey>,
+    pub clientlongtermpk: Option<PublicKey>,
+    pub servername: Option<String>,
+    pub hellos: usize,
+    pub cookies: usize,
+    pub initiates: usize,
+    pub clientmessages: usize,
+    pub servermessages: usize,
+    pub anomalies: Vec<String>,
+    pub transcript: Vec<Entry>,
+    keys: Keys,
+    opened: bool,
+    clientnonce: Option<u64>,
+    servernonce: Option<u64>
+}
+
+impl CaptureSession {
+    /*
+     * Hello, Cookie and Initiate seen and the server answered with a Message
+     */
+    pub fn established(&self) -> bool {
+        self.hellos > 0 && self.cookies > 0 && self.initiates > 0 && self.servermessages > 0
+    }
+
+    fn dissect(&mut self, payload: &[u8], kind: PacketKind, shortterm: &[SecretKey]) -> Dissection {
+        let d = dissect_with(payload, &self.keys);
+        if self.opened || kind == PacketKind::ClientHello || kind == PacketKind::ServerCookie {
+            return d;
+        }
+        for sk in shortterm {
+            let mut keys = self.keys.clone();
+            if sk.public_key() == self.clientshorttermpk {
+                keys.clientshorttermsk = Some(sk.clone());
+            } else {
+                keys.servershorttermsk = Some(sk.clone());
+            }
+            let opened = dissect_with(payload, &keys);
+            if opened.message.is_some() {
+                self.keys = keys;
+                self.opened = true;
+                return opened;
+            }
+        }
+        d
+    }
+
+    fn nonce(&mut self, d: &Dissection, from_client: bool, time: Duration) {
+        let n = match d.field("noncecounter") {
+            Some(&Value::Number(n)) => n,
+            _ => return
+        };
+        let last = if from_client { &mut self.clientnonce } else { &mut self.servernonce };
+        if last.is_some_and(|last| n <= last) {
+            let who = if from_client { "client" } else { "server" };
+            self.anomalies.push(format!("{:?}: {} nonce {} does not grow", time, who, n));
+        }
+        *last = Some(n);
+    }
+
+    fn record(&mut self, d: &Dissection, from_client: bool, time: Duration) {
+        let text = match d.message {
+            Some(ref text) => text,
+            None => return
+        };
+        let m = match Message::decode(text) {
+            Some(m) => m,
+            None => return
+        };
+        if m.data.is_empty() && m.eof == Eof::None {
+            return;
+        }
+        let retransmit = self.transcript.iter().any(|e| {
+            e.from_client == from_client && e.offset == m.offset && e.data.len() == m.data.len()
+        });
+        self.transcript.push(Entry {
+            time,
+            from_client,
+            id: m.id,
+            offset: m.offset,
+            eof: m.eof,
+            data: m.data.to_vec(),
+            retransmit
+        });
+    }
+
+    /*
+     * Stream data of one direction in order, retransmissions left out
+     */
+    pub fn stream(&self, from_client: bool) -> Vec<u8> {
+        let mut out = vec![];
+        let mut entries: Vec<&Entry> = self.transcript.iter()
+            .filter(|e| e.from_client == from_client && !e.retransmit)
+            .collect();
+        entries.sort_by_key(|e| e.offset);
+        for e in entries {
+            let end = e.offset as usize + e.data.len();
+            if out.len() < end {
+                out.resize(end, 0);
+            }
+            out[e.offset as usize..end].copy_from_slice(&e.data);
+        }
+        out
+    }
+}
+
+impl fmt::Display for CaptureSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "session {} -> {}", self.client, self.server)?;
+        writeln!(f, "  clientext {} serverext {}", self.clientext.to_hex(), self.serverext.to_hex())?;
+        writeln!(f, "  clientshorttermpk {}", self.clientshorttermpk.0.to_hex())?;
+        if let Some(pk) = self.servershorttermpk {
+            writeln!(f, "  servershorttermpk {}", pk.0.to_hex())?;
+        }
+        if let Some(pk) = self.clientlongtermpk {
+            writeln!(f, "  clientlongtermpk {}", pk.0.to_hex())?;
+        }
+        if let Some(ref name) = self.servername {
+            writeln!(f, "  servername {}", name)?;
+        }
+        writeln!(f, "  hello {} cookie {} initiate {} messages {} > {} <, {}",
+                 self.hellos, self.cookies, self.initiates, self.clientmessages, self.servermessages,
+                 if self.established() { "established" } else { "handshake incomplete" })?;
+        for anomaly in &self.anomalies {
+            writeln!(f, "  anomaly: {}", anomaly)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Capture {
+    pub sessions: Vec<CaptureSession>,
+    // UDP datagrams that aren't CurveCP
+    pub other: usize,
+    // CurveCP packets that fit no session
+    pub anomalies: Vec<String>,
+    keys: Keys,
+    shortterm: Vec<SecretKey>,
+    start: Option<Duration>,
+    // client address, server address and extensions to the latest session
+    latest: HashMap<(SocketAddr, SocketAddr, [u8; 16], [u8; 16]), usize>
+}
+
+impl Capture {
+    /*
+     * Long-term keys come from keys, shortterm are secret keys of either side
+     */
+    pub fn new(keys: Keys, shortterm: Vec<SecretKey>) -> Capture {
+        Capture {
+            sessions: vec![],
+            other: 0,
+            anomalies: vec![],
+            keys,
+            shortterm,
+            start: None,
+            latest: HashMap::new()
+        }
+    }
+
+    pub fn add(&mut self, datagram: &Datagram) {
+        let start = *self.start.get_or_insert(datagram.time);
+        let time = datagram.time.checked_sub(start).unwrap_or_default();
+        let kind = match signature_kind(&datagram.payload) {
+            Some(kind) => kind,
+            None => {
+                self.other += 1;
+                return;
+            }
+        };
+        let from_client = !matches!(kind, PacketKind::ServerCookie | PacketKind::ServerMessage);
+        let (client, server) = if from_client {
+            (datagram.src, datagram.dst)
+        } else {
+            (datagram.dst, datagram.src)
+        };
+
+        let header = dissect(&datagram.payload);
+        let exts = match (header.bytes("clientext"), header.bytes("serverext")) {
+            (Some(c), Some(s)) => (*array_ref![c, 0, 16], *array_ref![s, 0, 16]),
+            _ => {
+                self.anomalies.push(format!("{:?}: {:?} from {} too short to place", time, kind, datagram.src));
+                return;
+            }
+        };
+        let index = if from_client {
+            let pk = match header.bytes("clientshorttermpk") {
+                Some(pk) => PublicKey(*array_ref![pk, 0, 32]),
+                None => {
+                    self.anomalies.push(format!("{:?}: {:?} from {} too short to place", time, kind, datagram.src));
+                    return;
+                }
+            };
+            self.client_session(client, server, exts, pk)
+        } else {
+            match self.latest.get(&(client, server, exts.0, exts.1)) {
+                Some(&index) => index,
+                None => {
+                    self.anomalies.push(format!("{:?}: {:?} from {} for no session", time, kind, datagram.src));
+                    return;
+                }
+            }
+        };
+
+        let shortterm = &self.shortterm;
+        let session = &mut self.sessions[index];
+        let d = session.dissect(&datagram.payload, kind, shortterm);
+        for problem in &d.problems {
+            session.anomalies.push(format!("{:?}: {:?}: {}", time, kind, problem));
+        }
+        if from_client && client != session.client {
+            session.anomalies.push(format!("{:?}: client moved from {} to {}", time, session.client, client));
+            session.client = client;
+        }
+        if from_client || kind == PacketKind::ServerMessage {
+            session.nonce(&d, from_client, time);
+        }
+        match kind {
+            PacketKind::ClientHello => session.hellos += 1,
+            PacketKind::ServerCookie => {
+                session.cookies += 1;
+                if session.hellos == 0 {
+                    session.anomalies.push(format!("{:?}: Cookie without a Hello", time));
+                }
+                if let Some(pk) = d.bytes("servershorttermpk") {
+                    let pk = PublicKey(*array_ref![pk, 0, 32]);
+                    session.servershorttermpk = Some(pk);
+                    session.keys.servershorttermpk = Some(pk);
+                }
+            },
+            PacketKind::ClientInitiate => {
+                session.initiates += 1;
+                if session.cookies == 0 {
+                    session.anomalies.push(format!("{:?}: Initiate without a Cookie", time));
+                }
+                if let Some(pk) = d.bytes("clientlongtermpk") {
+                    session.clientlongtermpk = Some(PublicKey(*array_ref![pk, 0, 32]));
+                }
+                if let Some(Value::Text(name)) = d.field("servername") {
+                    session.servername = Some(name.clone());
+                }
+            },
+            PacketKind::ClientMessage => {
+                session.clientmessages += 1;
+                if session.initiates == 0 {
+                    session.anomalies.push(format!("{:?}: client Message before Initiate", time));
+                }
+            },
+            PacketKind::ServerMessage => {
+                session.servermessages += 1;
+                if session.initiates == 0 {
+                    session.anomalies.push(format!("{:?}: server Message before Initiate", time));
+                }
+            }
+        }
+        session.record(&d, from_client, time);
+    }
+
+    fn client_session(&mut self, client: SocketAddr, server: SocketAddr, exts: ([u8; 16], [u8; 16]),
+                      pk: PublicKey) -> usize {
+        let found = self.sessions.iter().position(|s| {
+            s.clientshorttermpk == pk && s.clientext == exts.0 && s.serverext == exts.1
+        });
+        let index = match found {
+            Some(index) => index,
+            None => {
+                let mut keys = self.keys.clone();
+                keys.clientshorttermpk = Some(pk);
+                self.sessions.push(CaptureSession {
+                    client,
+                    server,
+                    clientext: exts.0,
+                    serverext: exts.1,
+                    clientshorttermpk: pk,
+                    servershorttermpk: None,
+                    clientlongtermpk: None,
+                    servername: None,
+                    hellos: 0,
+                    cookies: 0,
+                    initiates: 0,
+                    clientmessages: 0,
+                    servermessages: 0,
+                    anomalies: vec![],
+                    transcript: vec![],
+                    keys,
+                    opened: false,
+                    clientnonce: None,
+                    servernonce: None
+                });
+                self.sessions.len() - 1
+            }
+        };
+        self.latest.insert((client, server, exts.0, exts.1), index);
+        index
+    }
+}
+
+/*
+ * Sessions of a whole capture
+ */
+pub fn analyze(datagrams: &[Datagram], keys: Keys, shortterm: Vec<SecretKey>) -> Capture {
+    let mut capture = Capture::new(keys, shortterm);
+    for d in datagrams {
+        capture.add(d);
+    }
+    capture
+}
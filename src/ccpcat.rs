@@ -0,0 +1,156 @@
+/*
+ * ccpcat [options] [-l] [-d] [ip:port]
+ *
+ * netcat over CurveCP: listen for one client (-l) or connect to a
+ * server, then pipe stdin/stdout through the stream. The peer's
+ * long-term public key goes to stderr, and with -d every decrypted
+ * message packet is dumped there too. A listener without a keydir
+ * makes up a key pair and prints its public key for the client.
+ * --keylog appends the short-term secret keys to a file, for
+ * ccpdissect -r --keylog to decrypt captures with.
+ */
+
+extern crate curvecp;
+extern crate getopts;
+extern crate rustc_serialize;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::net::UdpSocket;
+use std::process;
+use std::sync::{Arc, Mutex};
+use rustc_serialize::hex::ToHex;
+use curvecp::cli::{hexdump, options, Settings};
+use curvecp::keys::{keypair, SecretKey};
+use curvecp::message::{Eof, Message, MessageTracer};
+use curvecp::relay::{run_client, serve, Relay};
+use curvecp::session::{ClientSession, Server};
+
+fn die(msg: &str) -> ! {
+    eprintln!("ccpcat: fatal: {}", msg);
+    process::exit(111);
+}
+
+struct Tracer {
+    dump: bool,
+    keylog: Option<Mutex<fs::File>>
+}
+
+impl MessageTracer for Tracer {
+    fn trace(&self, sent: bool, message: &[u8]) {
+        if self.dump {
+            dump(sent, message);
+        }
+    }
+
+    fn shortterm_key(&self, sk: &SecretKey) {
+        if let Some(ref keylog) = self.keylog {
+            let _ = writeln!(keylog.lock().unwrap(), "{}", sk.as_bytes().to_hex());
+        }
+    }
+}
+
+/*
+ * None if there is nothing to trace
+ */
+fn tracer(dump: bool, keylog: Option<String>) -> Option<Arc<dyn MessageTracer>> {
+    let keylog = keylog.map(|path| {
+        let f = fs::OpenOptions::new().append(true).create(true).open(&path);
+        Mutex::new(f.unwrap_or_else(|e| die(&format!("unable to open {}: {}", path, e))))
+    });
+    if !dump && keylog.is_none() {
+        return None;
+    }
+    Some(Arc::new(Tracer { dump, keylog }))
+}
+
+/*
+ * > for messages we sent, < for received ones
+ */
+fn dump(sent: bool, message: &[u8]) {
+    let dir = if sent { ">" } else { "<" };
+    let mut out = match Message::decode(message) {
+        Some(m) => {
+            let eof = match m.eof {
+                Eof::None => "",
+                Eof::Success => " eof",
+                Eof::Failure => " fail"
+            };
+            format!("ccpcat: {} id {} acked {} upto {} offset {} len {}{}\n",
+                    dir, m.id, m.acked_id, m.acked_first, m.offset, m.data.len(), eof)
+        },
+        None => format!("ccpcat: {} undecodable message\n", dir)
+    };
+    out.push_str(&hexdump(message));
+    eprint!("{}", out);
+}
+
+fn listen(settings: &Settings, trace: Option<Arc<dyn MessageTracer>>) {
+    let addr = settings.addr.unwrap_or_else(|| die("addr is required"));
+    let mut config = match settings.keydir {
+        Some(_) => settings.server_config().unwrap_or_else(|e| die(&e.to_string())),
+        None => {
+            let (pk, sk) = keypair();
+            settings.server_config_with(pk, sk)
+        }
+    };
+    config.limits.max_sessions = 1;
+    config.tracer = trace;
+
+    let socket = UdpSocket::bind(addr).unwrap_or_else(|e| die(&format!("unable to bind {}: {}", addr, e)));
+    eprintln!("ccpcat: listening on {} key {}", socket.local_addr().unwrap(), config.serverlongtermpk.0.to_hex());
+    let mut server = Server::new(config);
+    let ret = serve(&mut server, &socket, Some(1), |_, session| {
+        eprintln!("ccpcat: client {} key {}", session.peer_addr(), session.clientlongtermpk().0.to_hex());
+        Some(Relay::new(io::stdin(), io::stdout()))
+    });
+    if let Err(e) = ret {
+        die(&e.to_string());
+    }
+}
+
+fn connect(settings: &Settings, trace: Option<Arc<dyn MessageTracer>>) {
+    let addr = settings.addr.unwrap_or_else(|| die("addr is required"));
+    let mut config = settings.client_config().unwrap_or_else(|e| die(&e.to_string()));
+    config.tracer = trace;
+
+    let bind = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind).unwrap_or_else(|e| die(&format!("unable to bind: {}", e)));
+    socket.connect(addr).unwrap_or_else(|e| die(&format!("unable to connect: {}", e)));
+
+    eprintln!("ccpcat: connecting to {} key {}", addr, config.serverlongtermpk.0.to_hex());
+    let mut session = ClientSession::new(config);
+    let mut relay = Relay::new(io::stdin(), io::stdout());
+    match run_client(&mut session, &socket, &mut relay) {
+        Ok(true) => relay.finish(),
+        Ok(false) => die(&format!("no answer from {}", addr)),
+        Err(e) => die(&e.to_string())
+    }
+}
+
+fn main() {
+    let mut opts = options();
+    opts.optflag("l", "listen", "wait for a client instead of connecting");
+    opts.optflag("d", "dump", "hexdump every message packet to stderr");
+    opts.optopt("", "keylog", "append short-term secret keys to this file", "FILE");
+    let matches = opts.parse(env::args().skip(1)).unwrap_or_else(|e| die(&e.to_string()));
+    if matches.opt_present("help") {
+        print!("{}", opts.usage("Usage: ccpcat [options] [-l] [-d] [ip:port]"));
+        return;
+    }
+    let mut settings = Settings::from_matches(&matches).unwrap_or_else(|e| die(&e.to_string()));
+    match matches.free.len() {
+        0 => {},
+        1 => settings.set("addr", &matches.free[0]).unwrap_or_else(|e| die(&e.to_string())),
+        _ => die("too many arguments")
+    }
+
+    let trace = tracer(matches.opt_present("dump"), matches.opt_str("keylog"));
+    if matches.opt_present("listen") {
+        listen(&settings, trace);
+    } else {
+        connect(&settings, trace);
+    }
+}
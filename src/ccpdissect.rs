@@ -0,0 +1,193 @@
+/*
+ * ccpdissect [options] [file ...]
+ *
+ * Print every field of the CurveCP packets in the files (or stdin) and
+ * whatever is wrong with them. Each file is one raw datagram; with -x
+ * each line is a datagram in hex. Given keys, boxes are opened too:
+ *
+ *   -k keydir                server long-term keys, opens Hello, Cookie
+ *                            and checks vouches
+ *   -p hex                   server long-term public key
+ *   --clientshorttermsk hex  with -p opens Hello and Cookie, with
+ *                            --servershorttermpk Initiate and Messages
+ *   --servershorttermsk hex  opens Initiate and Messages
+ *   --servershorttermpk hex, --clientshorttermpk hex
+ *                            public halves that aren't in the packet
+ *
+ * With -r the input is a .pcap or .pcapng capture instead: its CurveCP
+ * datagrams are sorted into sessions and each session is summarized
+ * with its anomalies. -t adds a decrypted transcript for sessions whose
+ * boxes open, -w writes just the CurveCP datagrams to a new pcap file.
+ *
+ *   --keylog file            short-term secret keys of either side, one
+ *                            hex key per line, tried on every session
+ */
+
+extern crate curvecp;
+extern crate getopts;
+extern crate rustc_serialize;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{BufWriter, Read};
+use std::process;
+use getopts::{Matches, Options};
+use rustc_serialize::hex::FromHex;
+use curvecp::capture::analyze;
+use curvecp::cli::hexdump;
+use curvecp::dissect::{dissect_with, signature_kind, Keys};
+use curvecp::keys::{KeyDir, PublicKey, SecretKey};
+use curvecp::message::Eof;
+use curvecp::pcap::{read_capture, PcapWriter};
+
+fn die(msg: &str) -> ! {
+    eprintln!("ccpdissect: fatal: {}", msg);
+    process::exit(111);
+}
+
+fn key(matches: &Matches, name: &str) -> Option<[u8; 32]> {
+    let hex = matches.opt_str(name)?;
+    match hex.from_hex() {
+        Ok(ref bytes) if bytes.len() == 32 => {
+            let mut key = [0; 32];
+            key.copy_from_slice(bytes);
+            Some(key)
+        },
+        _ => die(&format!("{} must be 64 hex digits", name))
+    }
+}
+
+fn keys(matches: &Matches) -> Keys {
+    let mut keys = Keys {
+        serverlongtermpk: key(matches, "peerkey").map(PublicKey),
+        serverlongtermsk: None,
+        servershorttermpk: key(matches, "servershorttermpk").map(PublicKey),
+        servershorttermsk: key(matches, "servershorttermsk").map(SecretKey::new),
+        clientshorttermpk: key(matches, "clientshorttermpk").map(PublicKey),
+        clientshorttermsk: key(matches, "clientshorttermsk").map(SecretKey::new)
+    };
+    if let Some(path) = matches.opt_str("keydir") {
+        let keydir = KeyDir::load(&path).unwrap_or_else(|e| die(&format!("unable to read key directory {}: {}", path, e)));
+        keys.serverlongtermpk = Some(keydir.publickey);
+        keys.serverlongtermsk = Some(keydir.secretkey.clone());
+    }
+    keys
+}
+
+fn keylog(path: &str) -> Vec<SecretKey> {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| die(&format!("unable to read {}: {}", path, e)));
+    text.lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.from_hex() {
+            Ok(ref bytes) if bytes.len() == 32 => {
+                let mut key = [0; 32];
+                key.copy_from_slice(bytes);
+                SecretKey::new(key)
+            },
+            _ => die(&format!("{}: not a 64 hex digit key: {}", path, line))
+        })
+        .collect()
+}
+
+fn pcap(path: &str, keys: Keys, matches: &Matches) {
+    let data = fs::read(path).unwrap_or_else(|e| die(&format!("unable to read {}: {}", path, e)));
+    let datagrams = read_capture(&data).unwrap_or_else(|e| die(&format!("{}: {}", path, e)));
+    let shortterm = matches.opt_str("keylog").map_or(vec![], |path| keylog(&path));
+
+    if let Some(out) = matches.opt_str("write") {
+        let f = fs::File::create(&out).unwrap_or_else(|e| die(&format!("unable to create {}: {}", out, e)));
+        let mut w = PcapWriter::new(BufWriter::new(f)).unwrap_or_else(|e| die(&e.to_string()));
+        for d in datagrams.iter().filter(|d| signature_kind(&d.payload).is_some()) {
+            w.write(d).unwrap_or_else(|e| die(&format!("unable to write {}: {}", out, e)));
+        }
+    }
+
+    let capture = analyze(&datagrams, keys, shortterm);
+    println!("{} UDP datagrams, {} not CurveCP, {} sessions", datagrams.len(), capture.other, capture.sessions.len());
+    for anomaly in &capture.anomalies {
+        println!("anomaly: {}", anomaly);
+    }
+    let mut bad = !capture.anomalies.is_empty();
+    for session in &capture.sessions {
+        bad = bad || !session.anomalies.is_empty();
+        print!("{}", session);
+        if !matches.opt_present("transcript") {
+            continue;
+        }
+        for e in &session.transcript {
+            let eof = match e.eof {
+                Eof::None => "",
+                Eof::Success => " eof",
+                Eof::Failure => " fail"
+            };
+            println!("  {}.{:06} {} id {} offset {} len {}{}{}", e.time.as_secs(), e.time.subsec_micros(),
+                     if e.from_client { ">" } else { "<" }, e.id, e.offset, e.data.len(), eof,
+                     if e.retransmit { " retransmit" } else { "" });
+            print!("{}", hexdump(&e.data));
+        }
+    }
+    if bad {
+        process::exit(1);
+    }
+}
+
+fn datagrams(data: Vec<u8>, hex: bool) -> Vec<Vec<u8>> {
+    if !hex {
+        return vec![data];
+    }
+    String::from_utf8_lossy(&data).lines()
+        .map(|line| line.split_whitespace().collect::<String>())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.from_hex().unwrap_or_else(|_| die(&format!("not hex: {}", line))))
+        .collect()
+}
+
+fn main() {
+    let mut opts = Options::new();
+    opts.optflag("x", "hex", "input is hex, one datagram per line");
+    opts.optopt("r", "read", "summarize the sessions in a pcap or pcapng file", "FILE");
+    opts.optflag("t", "transcript", "with -r, print the decrypted messages");
+    opts.optopt("w", "write", "with -r, write the CurveCP datagrams to a pcap file", "FILE");
+    opts.optopt("", "keylog", "short-term secret keys, one per line", "FILE");
+    opts.optopt("k", "keydir", "server key directory", "DIR");
+    opts.optopt("p", "peerkey", "server long-term public key", "HEX");
+    opts.optopt("", "clientshorttermsk", "client short-term secret key", "HEX");
+    opts.optopt("", "clientshorttermpk", "client short-term public key", "HEX");
+    opts.optopt("", "servershorttermsk", "server short-term secret key", "HEX");
+    opts.optopt("", "servershorttermpk", "server short-term public key", "HEX");
+    opts.optflag("h", "help", "print this help");
+    let matches = opts.parse(env::args().skip(1)).unwrap_or_else(|e| die(&e.to_string()));
+    if matches.opt_present("help") {
+        print!("{}", opts.usage("Usage: ccpdissect [options] [file ...]"));
+        return;
+    }
+    let keys = keys(&matches);
+    if let Some(path) = matches.opt_str("read") {
+        return pcap(&path, keys, &matches);
+    }
+    let hex = matches.opt_present("hex");
+
+    let mut inputs = vec![];
+    if matches.free.is_empty() {
+        let mut data = vec![];
+        io::stdin().read_to_end(&mut data).unwrap_or_else(|e| die(&e.to_string()));
+        inputs.push(data);
+    }
+    for path in &matches.free {
+        inputs.push(fs::read(path).unwrap_or_else(|e| die(&format!("unable to read {}: {}", path, e))));
+    }
+
+    let mut bad = false;
+    for data in inputs {
+        for datagram in datagrams(data, hex) {
+            let d = dissect_with(&datagram, &keys);
+            bad = bad || !d.problems.is_empty();
+            print!("{}", d);
+        }
+    }
+    if bad {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,80 @@
+/*
+ * ccpsocks [options] --local IP:PORT
+ * ccpsocks [options]
+ *
+ * SOCKS5 over CurveCP. The local end (--local) is a SOCKS5 listener for
+ * browsers and tools, each connection is tunnelled to the remote end at
+ * --addr, which must hold the key given with --peerkey. Without --local
+ * this is the remote end: it listens on --addr with the keys in
+ * --keydir, lets in only the clients on its --allow list and connects
+ * them wherever they ask. The remote end needs Unix socket pairs; elsewhere
+ * only the local end is available.
+ */
+
+extern crate curvecp;
+extern crate getopts;
+extern crate rustc_serialize;
+
+use std::env;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::net::UdpSocket;
+use std::process;
+use rustc_serialize::hex::ToHex;
+use curvecp::cli::{options, Settings};
+#[cfg(unix)]
+use curvecp::session::Server;
+#[cfg(unix)]
+use curvecp::socks::forward_socks;
+use curvecp::tunnel::forward_local;
+
+fn die(msg: &str) -> ! {
+    eprintln!("ccpsocks: fatal: {}", msg);
+    process::exit(111);
+}
+
+fn local(settings: &Settings) {
+    let local = settings.local.unwrap();
+    let addr = settings.addr.unwrap_or_else(|| die("addr is required"));
+    let config = settings.client_config().unwrap_or_else(|e| die(&e.to_string()));
+    let listener = TcpListener::bind(local).unwrap_or_else(|e| die(&format!("unable to bind {}: {}", local, e)));
+    eprintln!("ccpsocks: SOCKS5 on {} through {} key {}", local, addr, config.serverlongtermpk.0.to_hex());
+    if let Err(e) = forward_local(&listener, addr, &config) {
+        die(&e.to_string());
+    }
+}
+
+#[cfg(unix)]
+fn remote(settings: &Settings) {
+    let addr = settings.addr.unwrap_or_else(|| die("addr is required"));
+    if settings.allow.is_none() {
+        die("allow is required, the proxy would be open to anyone");
+    }
+    let config = settings.server_config().unwrap_or_else(|e| die(&e.to_string()));
+    let socket = UdpSocket::bind(addr).unwrap_or_else(|e| die(&format!("unable to bind {}: {}", addr, e)));
+    eprintln!("ccpsocks: proxying on {} key {}", addr, config.serverlongtermpk.0.to_hex());
+    let mut server = Server::new(config);
+    if let Err(e) = forward_socks(&mut server, &socket) {
+        die(&e.to_string());
+    }
+}
+
+#[cfg(not(unix))]
+fn remote(_settings: &Settings) {
+    die("the remote end is only supported on Unix, use --local");
+}
+
+fn main() {
+    let mut opts = options();
+    opts.optopt("L", "local", "local end: SOCKS5 listener, usually on 127.0.0.1", "IP:PORT");
+    let matches = opts.parse(env::args().skip(1)).unwrap_or_else(|e| die(&e.to_string()));
+    if matches.opt_present("help") {
+        print!("{}", opts.usage("Usage: ccpsocks [options] [--local IP:PORT]"));
+        return;
+    }
+    let settings = Settings::from_matches(&matches).unwrap_or_else(|e| die(&e.to_string()));
+    match settings.local {
+        Some(_) => local(&settings),
+        None => remote(&settings)
+    }
+}
@@ -0,0 +1,71 @@
+/*
+ * ccptunnel [options] --local IP:PORT
+ * ccptunnel [options] --target HOST:PORT
+ *
+ * TCP port forwarding over CurveCP. The local end (--local) accepts TCP
+ * connections and tunnels each one to the remote end at --addr, which
+ * must hold the key given with --peerkey. The remote end (--target)
+ * listens on --addr with the keys in --keydir, lets in only the clients
+ * on its --allow list and connects each one to the target. Each tunnel
+ * is one process with its own settings, see cli.rs for the TOML form.
+ */
+
+extern crate curvecp;
+extern crate getopts;
+extern crate rustc_serialize;
+
+use std::env;
+use std::net::{TcpListener, UdpSocket};
+use std::process;
+use rustc_serialize::hex::ToHex;
+use curvecp::cli::{options, Settings};
+use curvecp::session::Server;
+use curvecp::tunnel::{forward_local, forward_remote};
+
+fn die(msg: &str) -> ! {
+    eprintln!("ccptunnel: fatal: {}", msg);
+    process::exit(111);
+}
+
+fn local(settings: &Settings) {
+    let local = settings.local.unwrap();
+    let addr = settings.addr.unwrap_or_else(|| die("addr is required"));
+    let config = settings.client_config().unwrap_or_else(|e| die(&e.to_string()));
+    let listener = TcpListener::bind(local).unwrap_or_else(|e| die(&format!("unable to bind {}: {}", local, e)));
+    eprintln!("ccptunnel: forwarding {} to {} key {}", local, addr, config.serverlongtermpk.0.to_hex());
+    if let Err(e) = forward_local(&listener, addr, &config) {
+        die(&e.to_string());
+    }
+}
+
+fn remote(settings: &Settings) {
+    let target = settings.target.unwrap();
+    let addr = settings.addr.unwrap_or_else(|| die("addr is required"));
+    if settings.allow.is_none() {
+        die("allow is required, the target would be open to anyone");
+    }
+    let config = settings.server_config().unwrap_or_else(|e| die(&e.to_string()));
+    let socket = UdpSocket::bind(addr).unwrap_or_else(|e| die(&format!("unable to bind {}: {}", addr, e)));
+    eprintln!("ccptunnel: forwarding {} key {} to {}", addr, config.serverlongtermpk.0.to_hex(), target);
+    let mut server = Server::new(config);
+    if let Err(e) = forward_remote(&mut server, &socket, target) {
+        die(&e.to_string());
+    }
+}
+
+fn main() {
+    let mut opts = options();
+    opts.optopt("L", "local", "local end: accept TCP connections here", "IP:PORT");
+    opts.optopt("T", "target", "remote end: connect sessions to this TCP address", "HOST:PORT");
+    let matches = opts.parse(env::args().skip(1)).unwrap_or_else(|e| die(&e.to_string()));
+    if matches.opt_present("help") {
+        print!("{}", opts.usage("Usage: ccptunnel [options] --local IP:PORT | --target HOST:PORT"));
+        return;
+    }
+    let settings = Settings::from_matches(&matches).unwrap_or_else(|e| die(&e.to_string()));
+    match (settings.local, settings.target) {
+        (Some(_), None) => local(&settings),
+        (None, Some(_)) => remote(&settings),
+        _ => die("exactly one of local and target is required")
+    }
+}
@@ -0,0 +1,200 @@
+/*
+ * Settings shared by the command-line tools.
+ *
+ * Every setting can be given as a flag or in the TOML file named by
+ * --config, flags win. Flag long names and file keys are the same:
+ *
+ *   addr = "127.0.0.1:12345"      # address to listen on or connect to
+ *   keydir = "keys"               # own key directory
+ *   peerkey = "0a0294b7..."       # server public key, 64 hex digits
+ *   name = "machine.example.com"  # server name
+ *   serverext = "31415926..."     # 32 hex digits
+ *   clientext = "..."             # 32 hex digits, random if not given
+ *   allow = "clients"             # allow-list of client keys, see auth.rs
+ *
+ * Tunnel ends also read these, only ccptunnel has flags for them:
+ *
+ *   local = "127.0.0.1:8080"      # TCP address the local end listens on
+ *   target = "127.0.0.1:80"       # TCP address the remote end connects to
+ */
+
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use getopts::{Matches, Options};
+use rustc_serialize::hex::FromHex;
+use toml;
+use auth::AllowList;
+use config::{ClientConfig, ServerConfig};
+use keys::*;
+
+const KEYS: [&str; 9] = ["addr", "keydir", "peerkey", "name", "serverext", "clientext", "allow",
+                         "local", "target"];
+
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    pub addr: Option<SocketAddr>,
+    pub keydir: Option<String>,
+    pub peerkey: Option<PublicKey>,
+    pub name: Option<String>,
+    pub serverext: Option<[u8; 16]>,
+    pub clientext: Option<[u8; 16]>,
+    pub allow: Option<String>,
+    pub local: Option<SocketAddr>,
+    pub target: Option<SocketAddr>
+}
+
+/*
+ * Flags for all settings plus --config and --help, tools add their own
+ */
+pub fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("f", "config", "read settings from a TOML file", "FILE");
+    opts.optopt("a", "addr", "address to listen on or connect to", "IP:PORT");
+    opts.optopt("k", "keydir", "own key directory", "DIR");
+    opts.optopt("p", "peerkey", "server public key", "HEX");
+    opts.optopt("n", "name", "server name", "NAME");
+    opts.optopt("e", "serverext", "server extension", "HEX");
+    opts.optopt("x", "clientext", "client extension", "HEX");
+    opts.optopt("", "allow", "only let in clients from this allow-list", "PATH");
+    opts.optflag("h", "help", "print this help");
+    opts
+}
+
+impl Settings {
+    pub fn from_matches(matches: &Matches) -> io::Result<Settings> {
+        let mut settings = match matches.opt_str("config") {
+            Some(path) => Settings::from_toml(&fs::read_to_string(&path)?)?,
+            None => Settings::default()
+        };
+        for key in KEYS.iter().filter(|key| matches.opt_defined(key)) {
+            if let Some(value) = matches.opt_str(key) {
+                settings.set(key, &value)?;
+            }
+        }
+        Ok(settings)
+    }
+
+    pub fn from_toml(text: &str) -> io::Result<Settings> {
+        let value = text.parse::<toml::Value>().map_err(|e| invalid(&e.to_string()))?;
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => return Err(invalid("config is not a table"))
+        };
+        let mut settings = Settings::default();
+        for (key, value) in table {
+            match value.as_str() {
+                Some(value) => settings.set(key, value)?,
+                None => return Err(invalid(&format!("{} must be a string", key)))
+            }
+        }
+        Ok(settings)
+    }
+
+    /*
+     * Set one setting from its text form
+     */
+    pub fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        match key {
+            "addr" => self.addr = Some(resolve(key, value)?),
+            "keydir" => self.keydir = Some(String::from(value)),
+            "peerkey" => self.peerkey = Some(PublicKey(fromhex(key, value)?)),
+            "name" => self.name = Some(String::from(value)),
+            "serverext" => self.serverext = Some(fromhex(key, value)?),
+            "clientext" => self.clientext = Some(fromhex(key, value)?),
+            "allow" => self.allow = Some(String::from(value)),
+            "local" => self.local = Some(resolve(key, value)?),
+            "target" => self.target = Some(resolve(key, value)?),
+            _ => return Err(invalid(&format!("unknown setting {}", key)))
+        }
+        Ok(())
+    }
+
+    /*
+     * Client side needs peerkey; keydir, name and extensions are optional
+     */
+    pub fn client_config(&self) -> io::Result<ClientConfig> {
+        let peerkey = self.peerkey.ok_or_else(|| invalid("peerkey is required"))?;
+        let mut config = ClientConfig::new(peerkey)
+            .server_ext(self.serverext.unwrap_or([0; 16]))
+            .server_name(self.name.as_ref().map_or("", |name| name.as_str()));
+        config = match self.clientext {
+            Some(ext) => config.client_ext(ext),
+            None => config.random_client_ext()
+        };
+        if let Some(ref path) = self.keydir {
+            let keydir = KeyDir::load(path)?;
+            config = config.client_keypair(keydir.publickey, keydir.secretkey.clone());
+        }
+        Ok(config)
+    }
+
+    /*
+     * Server side needs keydir; without a name any name is accepted
+     */
+    pub fn server_config(&self) -> io::Result<ServerConfig> {
+        let path = self.keydir.as_ref().ok_or_else(|| invalid("keydir is required"))?;
+        let keydir = KeyDir::load(path)?;
+        Ok(self.server_config_with(keydir.publickey, keydir.secretkey.clone()))
+    }
+
+    /*
+     * Server side with a key pair from elsewhere, keydir is ignored. With
+     * allow only clients on the allow-list get in.
+     */
+    pub fn server_config_with(&self, pk: PublicKey, sk: SecretKey) -> ServerConfig {
+        let mut config = ServerConfig::new(pk, sk)
+            .server_ext(self.serverext.unwrap_or([0; 16]));
+        if let Some(ref name) = self.name {
+            config = config.accept_name(name);
+        }
+        if let Some(ref path) = self.allow {
+            config = config.authorizer(Arc::new(AllowList::new(path)));
+        }
+        config
+    }
+}
+
+/*
+ * Classic hexdump: offset, 16 bytes in hex, then the printable ones
+ */
+pub fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        out.push_str(&format!("{:08x} ", i * 16));
+        for j in 0..16 {
+            match line.get(j) {
+                Some(b) => out.push_str(&format!(" {:02x}", b)),
+                None => out.push_str("   ")
+            }
+            if j == 7 {
+                out.push(' ');
+            }
+        }
+        out.push_str("  |");
+        for &b in line {
+            out.push(if (0x20..0x7f).contains(&b) { b as char } else { '.' });
+        }
+        out.push_str("|\n");
+    }
+    out
+}
+
+fn resolve(key: &str, value: &str) -> io::Result<SocketAddr> {
+    let addr = value.to_socket_addrs()?.next();
+    addr.ok_or_else(|| invalid(&format!("{} resolves to nothing", key)))
+}
+
+fn fromhex<T: AsMut<[u8]> + Default>(key: &str, value: &str) -> io::Result<T> {
+    let mut out = T::default();
+    match value.from_hex() {
+        Ok(ref bytes) if bytes.len() == out.as_mut().len() => out.as_mut().copy_from_slice(bytes),
+        _ => return Err(invalid(&format!("{} must be {} hex digits", key, out.as_mut().len() * 2)))
+    }
+    Ok(out)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
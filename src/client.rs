@@ -1,92 +1,46 @@
-#[macro_use]
-extern crate arrayref;
-extern crate rustc_serialize;
-extern crate rust_sodium_sys;
-extern crate rust_sodium;
-
+/*
+ * client [options]
+ *
+ * Connect to a server and connect the session to stdin/stdout.
+ * Settings come from flags or a TOML file, see cli.rs.
+ */
+
+extern crate curvecp;
+extern crate getopts;
+
+use std::env;
+use std::io;
 use std::net::UdpSocket;
-mod libcurvecp;
-use libcurvecp::*;
-
-#[cfg(test)]
-mod tests;
-
-const SECRETKEY:[u8; 32] = [
-    0x70, 0x2d, 0x76, 0x4d, 0xe0, 0x54, 0x7c, 0x94,
-    0x86, 0x4c, 0x28, 0x97, 0x39, 0xc8, 0xaa, 0xd4,
-    0x80, 0x08, 0x08, 0xd9, 0x1f, 0xdf, 0x70, 0xf6,
-    0xe4, 0x37, 0x7b, 0x13, 0x7d, 0x0c, 0x13, 0x8d
-];
-const PUBLICKEY:[u8; 32] = [
-    0x0a, 0x02, 0x94, 0xb7, 0x69, 0x86, 0x30, 0x42,
-    0x28, 0xa3, 0x34, 0x11, 0x23, 0x92, 0x70, 0x95,
-    0x88, 0xf2, 0xe0, 0x04, 0xf3, 0xd8, 0xe0, 0xdd,
-    0x13, 0x9b, 0x90, 0x95, 0x96, 0xe4, 0xf9, 0x48
-];
-const SERVER_EXT:[u8; 16] = [
-    0x31, 0x41, 0x59, 0x26, 0x53, 0x58, 0x97, 0x93,
-    0x23, 0x84, 0x62, 0x64, 0x33, 0x83, 0x27, 0x95
-];
-
-const SERVER_ADDR:&'static str = "127.0.0.1:12345";
-const SERVER_NAME:&'static str = "machine.example.com";
+use std::process;
+use curvecp::cli::{options, Settings};
+use curvecp::relay::{run_client, Relay};
+use curvecp::session::ClientSession;
+
+fn die(msg: &str) -> ! {
+    eprintln!("client: fatal: {}", msg);
+    process::exit(111);
+}
 
 fn main() {
-    let socket = UdpSocket::bind("0.0.0.0:0").expect("err");
-    let mut ctx: CCPContext = CCPContext::new();
-
-    let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
-
-    // send ClientHello
-    println!("send mk_client_hello");
-    let ret = ctx.mk_client_hello(&mut buf,
-                                  PUBLICKEY, SECRETKEY,
-                                  PUBLICKEY,
-                                  [0; 16], SERVER_EXT);
-    if ret < 0 {
-        println!("mk_client_hello failure!");
-        return;
-    }
-    socket.send_to(&buf[0..(ret as usize)], SERVER_ADDR).expect("err");
-
-    // recv ServerCookie
-    println!("receiving ServerCookie");
-    let (len, src) = socket.recv_from(&mut buf).unwrap();
-    println!("received {} bytes from {}", len, src);
-    // TODO: check ip:port
-    if ctx.parse_server_cookie(&buf, len) < 0 {
-        println!("server cookie parsing failed");
+    let opts = options();
+    let matches = opts.parse(env::args().skip(1)).unwrap_or_else(|e| die(&e.to_string()));
+    if matches.opt_present("help") {
+        print!("{}", opts.usage("Usage: client [options]"));
         return;
     }
-
-    // send ClientInitiate
-    println!("send mk_client_initiate");
-    let ret = ctx.mk_client_initiate(&mut buf,
-                                     SERVER_NAME,
-                                     String::from("TESTTESTTESTTEST").into_bytes().as_slice());
-    if ret < 0 {
-        println!("mk_client_initiate failure!");
-        return;
-    }
-    socket.send_to(&buf[0..(ret as usize)], SERVER_ADDR).expect("err");
-
-    // recv ServerMessage
-    println!("receiving ServerMessage");
-    let (len, src) = socket.recv_from(&mut buf).unwrap();
-    println!("received {} bytes from {}", len, src);
-    // TODO: check ip:port
-    if ctx.parse_server_message(&buf, len) < 0 {
-        println!("server message parsing failed");
-        return;
-    }
-
-    // send ClientMessage
-    println!("send mk_client_message");
-    let ret = ctx.mk_client_message(&mut buf,
-                                    String::from("TESTTESTTESTTESTTESTTESTTESTTESTTESTTESTTESTTEST").into_bytes().as_slice());
-    if ret < 0 {
-        println!("mk_client_message failure!");
-        return;
+    let settings = Settings::from_matches(&matches).unwrap_or_else(|e| die(&e.to_string()));
+    let addr = settings.addr.unwrap_or_else(|| die("addr is required"));
+    let config = settings.client_config().unwrap_or_else(|e| die(&e.to_string()));
+
+    let bind = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind).unwrap_or_else(|e| die(&format!("unable to bind: {}", e)));
+    socket.connect(addr).unwrap_or_else(|e| die(&format!("unable to connect: {}", e)));
+
+    let mut session = ClientSession::new(config);
+    let mut relay = Relay::new(io::stdin(), io::stdout());
+    match run_client(&mut session, &socket, &mut relay) {
+        Ok(true) => relay.finish(),
+        Ok(false) => die(&format!("no answer from {}", addr)),
+        Err(e) => die(&e.to_string())
     }
-    socket.send_to(&buf[0..(ret as usize)], SERVER_ADDR).expect("err");
 }
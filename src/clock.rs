@@ -0,0 +1,75 @@
+/*
+ * Time for the protocol: retransmissions, Hello backoff, idle timeouts
+ * and cookie lifetime all ask a Clock instead of Instant::now().
+ *
+ * SystemClock is the default. ManualClock only moves when advanced, so
+ * timer behaviour can be tested without sleeping.
+ */
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/*
+ * Instant::now()
+ */
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/*
+ * Stands still at its creation time until advance() is called
+ */
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::from_secs(0))
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    /*
+     * Move forward to t, never back
+     */
+    pub fn advance_to(&self, t: Instant) {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        if t > self.start + *elapsed {
+            *elapsed = t - self.start;
+        }
+    }
+
+    /*
+     * Total advanced so far
+     */
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+}
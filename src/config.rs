@@ -0,0 +1,266 @@
+/*
+ * Client and server configuration, built once and handed to the
+ * packet and session code instead of positional key arguments.
+ */
+
+use std::sync::Arc;
+use std::time::Duration;
+use auth::ClientAuthorizer;
+use clock::{Clock, SystemClock};
+use keys::*;
+use message::MessageTracer;
+use rng::{Rng, SodiumRng};
+
+#[derive(Clone, Debug)]
+pub struct RetransmitPolicy {
+    pub initial_rto: Duration,
+    pub min_rto: Duration,
+    pub max_rto: Duration,
+    pub max_inflight: usize,
+    pub hello_tries: u32,
+    pub hello_interval: Duration,
+    // client probes the server after this long without hearing from it,
+    // so a server sending to an old client address learns the new one
+    pub keepalive: Option<Duration>
+}
+
+impl Default for RetransmitPolicy {
+    fn default() -> RetransmitPolicy {
+        RetransmitPolicy {
+            initial_rto: Duration::from_millis(1000),
+            min_rto: Duration::from_millis(200),
+            max_rto: Duration::from_secs(60),
+            max_inflight: 64,
+            hello_tries: 8,
+            hello_interval: Duration::from_millis(1000),
+            keepalive: None
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub max_sessions: usize,
+    pub max_pending: usize,
+    pub max_streams: usize,
+    pub max_send_buffer: usize,
+    pub max_recv_buffer: usize
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_sessions: 4096,
+            max_pending: 1024,
+            max_streams: 256,
+            max_send_buffer: 131072,
+            max_recv_buffer: 131072
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientConfig {
+    pub clientlongtermpk: PublicKey,
+    pub clientlongtermsk: SecretKey,
+    pub serverlongtermpk: PublicKey,
+    pub clientext: [u8; 16],
+    pub serverext: [u8; 16],
+    pub servername: String,
+    pub idle_timeout: Duration,
+    pub retransmit: RetransmitPolicy,
+    pub limits: Limits,
+    pub tracer: Option<Arc<dyn MessageTracer>>,
+    pub rng: Arc<dyn Rng>,
+    pub clock: Arc<dyn Clock>
+}
+
+impl ClientConfig {
+    /*
+     * Config for talking to the server with the given long-term key.
+     * The client gets a fresh long-term key unless client_keypair() is used.
+     */
+    pub fn new(serverlongtermpk: PublicKey) -> ClientConfig {
+        let (pk, sk) = keypair();
+        ClientConfig {
+            clientlongtermpk: pk,
+            clientlongtermsk: sk,
+            serverlongtermpk,
+            clientext: [0; 16],
+            serverext: [0; 16],
+            servername: String::new(),
+            idle_timeout: Duration::from_secs(60),
+            retransmit: RetransmitPolicy::default(),
+            limits: Limits::default(),
+            tracer: None,
+            rng: Arc::new(SodiumRng),
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    pub fn client_keypair(mut self, pk: PublicKey, sk: SecretKey) -> ClientConfig {
+        self.clientlongtermpk = pk;
+        self.clientlongtermsk = sk;
+        self
+    }
+
+    pub fn client_ext(mut self, ext: [u8; 16]) -> ClientConfig {
+        self.clientext = ext;
+        self
+    }
+
+    /*
+     * Random client extension, lets one socket carry many sessions
+     */
+    pub fn random_client_ext(mut self) -> ClientConfig {
+        self.rng.fill(&mut self.clientext);
+        self
+    }
+
+    pub fn server_ext(mut self, ext: [u8; 16]) -> ClientConfig {
+        self.serverext = ext;
+        self
+    }
+
+    pub fn server_name(mut self, name: &str) -> ClientConfig {
+        self.servername = String::from(name);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> ClientConfig {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn retransmit(mut self, policy: RetransmitPolicy) -> ClientConfig {
+        self.retransmit = policy;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> ClientConfig {
+        self.limits = limits;
+        self
+    }
+
+    pub fn tracer(mut self, tracer: Arc<dyn MessageTracer>) -> ClientConfig {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /*
+     * Randomness for short-term keys and nonces, libsodium's by default
+     */
+    pub fn rng(mut self, rng: Arc<dyn Rng>) -> ClientConfig {
+        self.rng = rng;
+        self
+    }
+
+    /*
+     * Where timers read the time, the system clock by default
+     */
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> ClientConfig {
+        self.clock = clock;
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub serverlongtermpk: PublicKey,
+    pub serverlongtermsk: SecretKey,
+    pub serverext: [u8; 16],
+    pub names: Vec<String>,
+    pub authorizer: Option<Arc<dyn ClientAuthorizer>>,
+    pub idle_timeout: Duration,
+    pub cookie_lifetime: Duration,
+    pub retransmit: RetransmitPolicy,
+    pub limits: Limits,
+    pub tracer: Option<Arc<dyn MessageTracer>>,
+    pub rng: Arc<dyn Rng>,
+    pub clock: Arc<dyn Clock>
+}
+
+impl ServerConfig {
+    pub fn new(serverlongtermpk: PublicKey, serverlongtermsk: SecretKey) -> ServerConfig {
+        ServerConfig {
+            serverlongtermpk,
+            serverlongtermsk,
+            serverext: [0; 16],
+            names: vec![],
+            authorizer: None,
+            idle_timeout: Duration::from_secs(60),
+            cookie_lifetime: Duration::from_secs(120),
+            retransmit: RetransmitPolicy::default(),
+            limits: Limits::default(),
+            tracer: None,
+            rng: Arc::new(SodiumRng),
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    pub fn server_ext(mut self, ext: [u8; 16]) -> ServerConfig {
+        self.serverext = ext;
+        self
+    }
+
+    /*
+     * Accept Initiate packets for this name, any name is accepted if none is given
+     */
+    pub fn accept_name(mut self, name: &str) -> ServerConfig {
+        self.names.push(String::from(name));
+        self
+    }
+
+    /*
+     * Ask the authorizer about every new client, all clients are let in without one
+     */
+    pub fn authorizer(mut self, authorizer: Arc<dyn ClientAuthorizer>) -> ServerConfig {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> ServerConfig {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn cookie_lifetime(mut self, lifetime: Duration) -> ServerConfig {
+        self.cookie_lifetime = lifetime;
+        self
+    }
+
+    pub fn retransmit(mut self, policy: RetransmitPolicy) -> ServerConfig {
+        self.retransmit = policy;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> ServerConfig {
+        self.limits = limits;
+        self
+    }
+
+    pub fn tracer(mut self, tracer: Arc<dyn MessageTracer>) -> ServerConfig {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /*
+     * Randomness for short-term keys and nonces, libsodium's by default
+     */
+    pub fn rng(mut self, rng: Arc<dyn Rng>) -> ServerConfig {
+        self.rng = rng;
+        self
+    }
+
+    /*
+     * Where timers read the time, the system clock by default
+     */
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> ServerConfig {
+        self.clock = clock;
+        self
+    }
+
+    pub fn accepts_name(&self, name: &str) -> bool {
+        self.names.is_empty() || self.names.iter().any(|n| n.eq_ignore_ascii_case(name))
+    }
+}
@@ -0,0 +1,178 @@
+/*
+ * curvecpclient [-q] [-Q] [-v] [-c keydir] name key ip port ext prog [arg ...]
+ *
+ * UCSPI client: runs prog with data from the server readable on
+ * descriptor 6 and data for the server written to descriptor 7. prog
+ * sees PROTO=CURVECP, CURVECPSERVERPUBLICKEY, CURVECPSERVEREXTENSION,
+ * CURVECPSERVERNAME, CURVECPREMOTEIP and CURVECPREMOTEPORT. The client
+ * key comes from keydir, or is made up for this connection. Passing
+ * descriptors 6 and 7 needs Unix, elsewhere curvecpclient only exits.
+ */
+
+#![cfg_attr(not(unix), allow(dead_code, unused_imports))]
+
+extern crate curvecp;
+#[cfg(unix)]
+extern crate libc;
+extern crate rustc_serialize;
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::process;
+use std::process::Command;
+use rustc_serialize::hex::{FromHex, ToHex};
+use curvecp::config::ClientConfig;
+use curvecp::keys::{KeyDir, PublicKey};
+use curvecp::relay::{run_client, Relay};
+use curvecp::session::ClientSession;
+
+fn usage() -> ! {
+    eprintln!("curvecpclient: usage: curvecpclient [-q] [-Q] [-v] [-c keydir] name key ip port ext prog [arg ...]");
+    process::exit(111);
+}
+
+fn die(msg: &str) -> ! {
+    eprintln!("curvecpclient: fatal: {}", msg);
+    process::exit(111);
+}
+
+fn fromhex(hex: &str, out: &mut [u8]) -> bool {
+    match hex.from_hex() {
+        Ok(ref bytes) if bytes.len() == out.len() => {
+            out.copy_from_slice(bytes);
+            true
+        },
+        _ => false
+    }
+}
+
+/*
+ * Pipe with both ends closed on exec, prog gets its ends through dup2
+ */
+#[cfg(unix)]
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for &fd in &fds {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])))
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    die("descriptors 6 and 7 can only be passed on Unix");
+}
+
+#[cfg(unix)]
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut verbosity = 1;
+    let mut keydir = None;
+    let mut rest: Vec<String> = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-q" => verbosity = 0,
+            "-Q" => verbosity = 1,
+            "-v" => verbosity = 2,
+            "-c" => keydir = Some(args.next().unwrap_or_else(|| usage())),
+            "--" => break,
+            _ if arg.starts_with('-') => usage(),
+            _ => { rest.push(arg); break; }
+        }
+    }
+    rest.extend(args);
+    if rest.len() < 6 {
+        usage();
+    }
+
+    let mut serverpk = PublicKey::default();
+    if !fromhex(&rest[1], &mut serverpk.0) {
+        die("key must be 64 hex digits");
+    }
+    let ip: IpAddr = rest[2].parse().unwrap_or_else(|_| die("ip is not an address"));
+    let port: u16 = rest[3].parse().unwrap_or_else(|_| die("port is not a number"));
+    let mut ext = [0; 16];
+    if !fromhex(&rest[4], &mut ext) {
+        die("ext must be 32 hex digits");
+    }
+    let prog = &rest[5..];
+    let addr = SocketAddr::new(ip, port);
+
+    let mut config = ClientConfig::new(serverpk)
+        .random_client_ext()
+        .server_ext(ext)
+        .server_name(&rest[0]);
+    if let Some(path) = keydir {
+        let keydir = KeyDir::load(&path).unwrap_or_else(|e| die(&format!("unable to read key directory {}: {}", path, e)));
+        config = config.client_keypair(keydir.publickey, keydir.secretkey.clone());
+    }
+
+    let bind = if ip.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind).unwrap_or_else(|e| die(&format!("unable to bind: {}", e)));
+    socket.connect(addr).unwrap_or_else(|e| die(&format!("unable to connect: {}", e)));
+
+    // prog reads fd 6 from fromserver, writes fd 7 into toserver
+    let (fromserver_r, fromserver_w) = pipe().unwrap_or_else(|e| die(&e.to_string()));
+    let (toserver_r, toserver_w) = pipe().unwrap_or_else(|e| die(&e.to_string()));
+    let (r, w) = (fromserver_r.as_raw_fd(), toserver_w.as_raw_fd());
+    let mut command = Command::new(&prog[0]);
+    command.args(&prog[1..])
+        .env("PROTO", "CURVECP")
+        .env("CURVECPSERVERPUBLICKEY", serverpk.0.to_hex())
+        .env("CURVECPSERVEREXTENSION", ext.to_hex())
+        .env("CURVECPSERVERNAME", &rest[0])
+        .env("CURVECPREMOTEIP", ip.to_string())
+        .env("CURVECPREMOTEPORT", port.to_string());
+    unsafe {
+        command.pre_exec(move || {
+            // move out of the way first in case the pipe got fd 6 or 7
+            let r = libc::fcntl(r, libc::F_DUPFD, 10);
+            let w = libc::fcntl(w, libc::F_DUPFD, 10);
+            if r < 0 || w < 0 || libc::dup2(r, 6) < 0 || libc::dup2(w, 7) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::close(r);
+            libc::close(w);
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap_or_else(|e| die(&format!("unable to run {}: {}", prog[0], e)));
+    drop(fromserver_r);
+    drop(toserver_w);
+
+    let mut session = ClientSession::new(config);
+    let mut relay = Relay::new(toserver_r, fromserver_w);
+    match run_client(&mut session, &socket, &mut relay) {
+        Ok(true) => {
+            if verbosity >= 2 {
+                eprintln!("curvecpclient: session to {} ended", addr);
+            }
+        },
+        Ok(false) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            die(&format!("unable to connect to {}", addr));
+        },
+        Err(e) => {
+            if verbosity >= 1 {
+                eprintln!("curvecpclient: session failed: {}", e);
+            }
+        }
+    }
+    relay.finish();
+    match child.wait() {
+        Ok(status) => process::exit(status.code().unwrap_or(111)),
+        Err(e) => die(&e.to_string())
+    }
+}
@@ -0,0 +1,23 @@
+/*
+ * curvecpmakekey keydir
+ *
+ * Create a key directory with a new long-term key pair.
+ */
+
+extern crate curvecp;
+
+use std::env;
+use std::process;
+use curvecp::keys::KeyDir;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        eprintln!("curvecpmakekey: usage: curvecpmakekey keydir");
+        process::exit(111);
+    }
+    if let Err(e) = KeyDir::create(&args[1]) {
+        eprintln!("curvecpmakekey: fatal: unable to create {}: {}", args[1], e);
+        process::exit(111);
+    }
+}
@@ -0,0 +1,43 @@
+/*
+ * curvecpprintkey [-x|-b|-6] keydir
+ *
+ * Print the public key of a key directory: hex by default like the
+ * reference tool, -b for DNSCurve base32, -6 for base64.
+ */
+
+extern crate curvecp;
+extern crate rustc_serialize;
+
+use std::env;
+use std::process;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use rustc_serialize::hex::ToHex;
+use curvecp::keys::{base32, KeyDir};
+
+fn usage() -> ! {
+    eprintln!("curvecpprintkey: usage: curvecpprintkey [-x|-b|-6] keydir");
+    process::exit(111);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (format, path) = match args.len() {
+        1 => ("-x", &args[0]),
+        2 => (args[0].as_str(), &args[1]),
+        _ => usage()
+    };
+
+    let pk = match KeyDir::load_public(path) {
+        Ok(pk) => pk,
+        Err(e) => {
+            eprintln!("curvecpprintkey: fatal: unable to read {}/publickey: {}", path, e);
+            process::exit(111);
+        }
+    };
+    match format {
+        "-x" => println!("{}", pk.0.to_hex()),
+        "-b" => println!("{}", base32(&pk.0)),
+        "-6" => println!("{}", pk.0.to_base64(STANDARD)),
+        _ => usage()
+    }
+}
@@ -0,0 +1,122 @@
+/*
+ * curvecpserver [-q] [-Q] [-v] [-c n] name keydir ip port ext prog [arg ...]
+ *
+ * UCSPI server: every client session runs prog with the session's
+ * incoming data on its stdin and its stdout going back to the client.
+ * prog sees PROTO=CURVECP, CURVECPCLIENTPUBLICKEY, CURVECPCLIENTEXTENSION,
+ * CURVECPSERVERNAME, CURVECPREMOTEIP and CURVECPREMOTEPORT. Like
+ * curvecpclient it is only built for Unix, elsewhere it only exits.
+ */
+
+#![cfg_attr(not(unix), allow(dead_code, unused_imports))]
+
+extern crate curvecp;
+extern crate rustc_serialize;
+
+use std::env;
+use std::io;
+use std::net::{IpAddr, UdpSocket};
+use std::process;
+use std::process::{Command, Stdio};
+use std::thread;
+use rustc_serialize::hex::{FromHex, ToHex};
+use curvecp::config::ServerConfig;
+use curvecp::keys::KeyDir;
+use curvecp::relay::{serve, Relay};
+use curvecp::session::{Server, ServerSession};
+
+fn usage() -> ! {
+    eprintln!("curvecpserver: usage: curvecpserver [-q] [-Q] [-v] [-c n] name keydir ip port ext prog [arg ...]");
+    process::exit(111);
+}
+
+fn die(msg: &str) -> ! {
+    eprintln!("curvecpserver: fatal: {}", msg);
+    process::exit(111);
+}
+
+fn spawn(prog: &[String], session: &ServerSession) -> io::Result<Relay> {
+    let addr = session.peer_addr();
+    let mut child = Command::new(&prog[0])
+        .args(&prog[1..])
+        .env("PROTO", "CURVECP")
+        .env("CURVECPCLIENTPUBLICKEY", session.clientlongtermpk().0.to_hex())
+        .env("CURVECPCLIENTEXTENSION", session.clientext().to_hex())
+        .env("CURVECPSERVERNAME", session.servername().unwrap_or_default())
+        .env("CURVECPREMOTEIP", addr.ip().to_string())
+        .env("CURVECPREMOTEPORT", addr.port().to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let relay = Relay::new(child.stdout.take().unwrap(), child.stdin.take().unwrap());
+    thread::spawn(move || child.wait());
+    Ok(relay)
+}
+
+#[cfg(not(unix))]
+fn main() {
+    die("only supported on Unix");
+}
+
+#[cfg(unix)]
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut verbosity = 1;
+    let mut maxclients = None;
+    let mut rest: Vec<String> = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-q" => verbosity = 0,
+            "-Q" => verbosity = 1,
+            "-v" => verbosity = 2,
+            "-c" => maxclients = Some(args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage())),
+            "--" => break,
+            _ if arg.starts_with('-') => usage(),
+            _ => { rest.push(arg); break; }
+        }
+    }
+    rest.extend(args);
+    if rest.len() < 6 {
+        usage();
+    }
+
+    let keydir = KeyDir::load(&rest[1]).unwrap_or_else(|e| die(&format!("unable to read key directory {}: {}", rest[1], e)));
+    let ip: IpAddr = rest[2].parse().unwrap_or_else(|_| die("ip is not an address"));
+    let port: u16 = rest[3].parse().unwrap_or_else(|_| die("port is not a number"));
+    let mut ext = [0; 16];
+    match rest[4].from_hex() {
+        Ok(ref hex) if hex.len() == 16 => ext.copy_from_slice(hex),
+        _ => die("ext must be 32 hex digits")
+    }
+    let prog = &rest[5..];
+
+    let mut config = ServerConfig::new(keydir.publickey, keydir.secretkey.clone())
+        .server_ext(ext)
+        .accept_name(&rest[0]);
+    if let Some(n) = maxclients {
+        config.limits.max_sessions = n;
+    }
+
+    let socket = UdpSocket::bind((ip, port)).unwrap_or_else(|e| die(&format!("unable to bind: {}", e)));
+    let mut server = Server::new(config);
+    let ret = serve(&mut server, &socket, None, |id, session| {
+        match spawn(prog, session) {
+            Ok(relay) => {
+                if verbosity >= 2 {
+                    eprintln!("curvecpserver: session {} from {} key {}", id.to_hex(),
+                              session.peer_addr(), session.clientlongtermpk().0.to_hex());
+                }
+                Some(relay)
+            },
+            Err(e) => {
+                if verbosity >= 1 {
+                    eprintln!("curvecpserver: unable to run {}: {}", prog[0], e);
+                }
+                None
+            }
+        }
+    });
+    if let Err(e) = ret {
+        die(&e.to_string());
+    }
+}
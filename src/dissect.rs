@@ -0,0 +1,398 @@
+/*
+ * Taking raw datagrams apart for debugging.
+ *
+ * dissect() classifies a datagram by its signature, lists every header
+ * field with its offset and notes whatever breaks the packet format.
+ * dissect_with() also opens the boxes it has keys for and adds the
+ * fields inside them, for Initiate and Message packets down to the
+ * message header.
+ *
+ * Boxes between the client short-term key and the server long-term key
+ * (Hello, Cookie) open with either secret key of that pair, boxes
+ * between the short-term keys (Initiate, Message) with either short-term
+ * secret key; the public half comes from the packet where it is there,
+ * otherwise from Keys.
+ */
+
+use std::fmt;
+use rust_sodium_sys::*;
+use rustc_serialize::hex::ToHex;
+use keys::*;
+use libcurvecp::*;
+use message::{Eof, Message};
+
+pub const CCP_HELLO_SIZE:usize = 224;
+pub const CCP_COOKIE_SIZE:usize = 200;
+
+#[derive(Clone, Debug, Default)]
+pub struct Keys {
+    pub serverlongtermpk: Option<PublicKey>,
+    pub serverlongtermsk: Option<SecretKey>,
+    pub servershorttermpk: Option<PublicKey>,
+    pub servershorttermsk: Option<SecretKey>,
+    pub clientshorttermpk: Option<PublicKey>,
+    pub clientshorttermsk: Option<SecretKey>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Bytes(Vec<u8>),
+    Number(u64),
+    Text(String)
+}
+
+#[derive(Clone, Debug)]
+pub struct Field {
+    pub name: &'static str,
+    // None for fields found inside a box
+    pub offset: Option<usize>,
+    pub value: Value
+}
+
+#[derive(Clone, Debug)]
+pub struct Dissection {
+    pub kind: Option<PacketKind>,
+    pub size: usize,
+    pub fields: Vec<Field>,
+    pub problems: Vec<String>,
+    // decrypted message of an Initiate or Message packet
+    pub message: Option<Vec<u8>>
+}
+
+impl Dissection {
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        self.fields.iter().find(|f| f.name == name).map(|f| &f.value)
+    }
+
+    pub fn bytes(&self, name: &str) -> Option<&[u8]> {
+        match self.field(name) {
+            Some(Value::Bytes(bytes)) => Some(bytes),
+            _ => None
+        }
+    }
+
+    /*
+     * Opened at least one box
+     */
+    pub fn decrypted(&self) -> bool {
+        self.fields.iter().any(|f| f.offset.is_none())
+    }
+
+    fn header(&mut self, buf: &[u8], name: &'static str, offset: usize, len: usize) -> bool {
+        if buf.len() < offset + len {
+            return false;
+        }
+        self.at(name, offset, Value::Bytes(buf[offset..offset + len].to_vec()));
+        true
+    }
+
+    fn at(&mut self, name: &'static str, offset: usize, value: Value) {
+        self.fields.push(Field { name, offset: Some(offset), value });
+    }
+
+    fn inner(&mut self, name: &'static str, value: Value) {
+        self.fields.push(Field { name, offset: None, value });
+    }
+
+    fn problem(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+}
+
+impl fmt::Display for Dissection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            Some(kind) => writeln!(f, "{:?}, {} bytes", kind, self.size)?,
+            None => writeln!(f, "unknown, {} bytes", self.size)?
+        }
+        for field in &self.fields {
+            match field.offset {
+                Some(offset) => write!(f, "  {:04x} ", offset)?,
+                None => write!(f, "  box  ")?
+            }
+            match field.value {
+                Value::Bytes(ref bytes) => writeln!(f, "{:<18} {}", field.name, bytes.to_hex())?,
+                Value::Number(n) => writeln!(f, "{:<18} {}", field.name, n)?,
+                Value::Text(ref text) => writeln!(f, "{:<18} {}", field.name, text)?
+            }
+        }
+        for problem in &self.problems {
+            writeln!(f, "  problem: {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/*
+ * Kind by signature alone, the size may still be wrong
+ */
+pub fn signature_kind(buf: &[u8]) -> Option<PacketKind> {
+    if buf.len() < 8 {
+        return None;
+    }
+    match &buf[..8] {
+        b"QvnQ5XlH" => Some(PacketKind::ClientHello),
+        b"RL3aNMXK" => Some(PacketKind::ServerCookie),
+        b"QvnQ5XlI" => Some(PacketKind::ClientInitiate),
+        b"QvnQ5XlM" => Some(PacketKind::ClientMessage),
+        b"RL3aNMXM" => Some(PacketKind::ServerMessage),
+        _ => None
+    }
+}
+
+pub fn dissect(buf: &[u8]) -> Dissection {
+    dissect_with(buf, &Keys::default())
+}
+
+pub fn dissect_with(buf: &[u8], keys: &Keys) -> Dissection {
+    let mut d = Dissection {
+        kind: signature_kind(buf),
+        size: buf.len(),
+        fields: vec![],
+        problems: vec![],
+        message: None
+    };
+    if buf.len() > CCP_MAX_PACKET_SIZE {
+        d.problem(format!("longer than {} bytes", CCP_MAX_PACKET_SIZE));
+    }
+    let kind = match d.kind {
+        Some(kind) => kind,
+        None => {
+            if buf.len() < 8 {
+                d.problem(String::from("too short for a signature"));
+            } else {
+                d.header(buf, "signature", 0, 8);
+                d.problem(String::from("unknown signature"));
+            }
+            return d;
+        }
+    };
+    d.at("signature", 0, Value::Text(String::from_utf8_lossy(&buf[..8]).into_owned()));
+
+    match kind {
+        PacketKind::ClientHello => hello(&mut d, buf, keys),
+        PacketKind::ServerCookie => cookie(&mut d, buf, keys),
+        PacketKind::ClientInitiate => initiate(&mut d, buf, keys),
+        PacketKind::ClientMessage => client_message(&mut d, buf, keys),
+        PacketKind::ServerMessage => server_message(&mut d, buf, keys)
+    }
+    d
+}
+
+fn hello(d: &mut Dissection, buf: &[u8], keys: &Keys) {
+    if buf.len() != CCP_HELLO_SIZE {
+        d.problem(format!("Hello must be {} bytes", CCP_HELLO_SIZE));
+    }
+    let complete = d.header(buf, "serverext", 8, 16) &&
+                   d.header(buf, "clientext", 24, 16) &&
+                   d.header(buf, "clientshorttermpk", 40, 32) &&
+                   d.header(buf, "pad", 72, 64) &&
+                   d.header(buf, "nonce", 136, 8) &&
+                   d.header(buf, "box", 144, 80);
+    if !complete {
+        return;
+    }
+    if buf[72..136].iter().any(|&b| b != 0) {
+        d.problem(String::from("padding is not zero"));
+    }
+    d.at("noncecounter", 136, Value::Number(counter(&buf[136..144])));
+
+    let clientshorttermpk = PublicKey(*array_ref![buf, 40, 32]);
+    if let Some(key) = short_long(keys, Some(clientshorttermpk)) {
+        match open(&key, b"CurveCP-client-H", &buf[136..144], &buf[144..224]) {
+            Some(text) => {
+                if text.iter().any(|&b| b != 0) {
+                    d.problem(String::from("box does not hold zeros"));
+                }
+                d.inner("zeros", Value::Bytes(text));
+            },
+            None => d.problem(String::from("box does not open"))
+        }
+    }
+}
+
+fn cookie(d: &mut Dissection, buf: &[u8], keys: &Keys) {
+    if buf.len() != CCP_COOKIE_SIZE {
+        d.problem(format!("Cookie must be {} bytes", CCP_COOKIE_SIZE));
+    }
+    let complete = d.header(buf, "clientext", 8, 16) &&
+                   d.header(buf, "serverext", 24, 16) &&
+                   d.header(buf, "nonce", 40, 16) &&
+                   d.header(buf, "box", 56, 144);
+    if !complete {
+        return;
+    }
+
+    if let Some(key) = short_long(keys, None) {
+        match open(&key, b"CurveCPK", &buf[40..56], &buf[56..200]) {
+            Some(text) => {
+                d.inner("servershorttermpk", Value::Bytes(text[..32].to_vec()));
+                d.inner("cookie", Value::Bytes(text[32..].to_vec()));
+            },
+            None => d.problem(String::from("box does not open"))
+        }
+    }
+}
+
+fn initiate(d: &mut Dissection, buf: &[u8], keys: &Keys) {
+    let min = 544 + 16;
+    let max = 544 + CCP_MAX_CLIENT_INIT_PAYLOAD_SIZE;
+    check_size(d, buf, "Initiate", min, max);
+    let complete = d.header(buf, "serverext", 8, 16) &&
+                   d.header(buf, "clientext", 24, 16) &&
+                   d.header(buf, "clientshorttermpk", 40, 32) &&
+                   d.header(buf, "cookie", 72, 96) &&
+                   d.header(buf, "nonce", 168, 8) &&
+                   buf.len() >= 176 + 16;
+    if !complete {
+        return;
+    }
+    d.header(buf, "box", 176, buf.len() - 176);
+    d.at("noncecounter", 168, Value::Number(counter(&buf[168..176])));
+
+    let clientshorttermpk = PublicKey(*array_ref![buf, 40, 32]);
+    let key = match short_short(keys, Some(clientshorttermpk)) {
+        Some(key) => key,
+        None => return
+    };
+    let text = match open(&key, b"CurveCP-client-I", &buf[168..176], &buf[176..]) {
+        Some(text) => text,
+        None => return d.problem(String::from("box does not open"))
+    };
+    if text.len() < 352 {
+        return d.problem(String::from("box too short for the Initiate contents"));
+    }
+    let clientlongtermpk = PublicKey(*array_ref![text, 0, 32]);
+    d.inner("clientlongtermpk", Value::Bytes(text[..32].to_vec()));
+    d.inner("vouch", Value::Bytes(text[32..96].to_vec()));
+    match nameunparse(&text[96..352]) {
+        Some(name) => d.inner("servername", Value::Text(name)),
+        None => d.problem(String::from("server name is malformed"))
+    }
+    if let Some(ref sk) = keys.serverlongtermsk {
+        let key = SharedKey::precompute(&clientlongtermpk, sk);
+        match open(&key, b"CurveCPV", &text[32..48], &text[48..96]) {
+            Some(ref vouched) if vouched[..] == clientshorttermpk.0[..] => {},
+            Some(_) => d.problem(String::from("vouch is for another short-term key")),
+            None => d.problem(String::from("vouch does not open"))
+        }
+    }
+    message(d, &text[352..]);
+}
+
+fn client_message(d: &mut Dissection, buf: &[u8], keys: &Keys) {
+    check_size(d, buf, "Message", 96 + 16, 96 + CCP_MAX_MESSAGE_SIZE);
+    let complete = d.header(buf, "serverext", 8, 16) &&
+                   d.header(buf, "clientext", 24, 16) &&
+                   d.header(buf, "clientshorttermpk", 40, 32) &&
+                   d.header(buf, "nonce", 72, 8) &&
+                   buf.len() >= 80 + 16;
+    if !complete {
+        return;
+    }
+    d.header(buf, "box", 80, buf.len() - 80);
+    d.at("noncecounter", 72, Value::Number(counter(&buf[72..80])));
+
+    let clientshorttermpk = PublicKey(*array_ref![buf, 40, 32]);
+    if let Some(key) = short_short(keys, Some(clientshorttermpk)) {
+        match open(&key, b"CurveCP-client-M", &buf[72..80], &buf[80..]) {
+            Some(text) => message(d, &text),
+            None => d.problem(String::from("box does not open"))
+        }
+    }
+}
+
+fn server_message(d: &mut Dissection, buf: &[u8], keys: &Keys) {
+    check_size(d, buf, "Message", 64 + 16, 64 + CCP_MAX_MESSAGE_SIZE);
+    let complete = d.header(buf, "clientext", 8, 16) &&
+                   d.header(buf, "serverext", 24, 16) &&
+                   d.header(buf, "nonce", 40, 8) &&
+                   buf.len() >= 48 + 16;
+    if !complete {
+        return;
+    }
+    d.header(buf, "box", 48, buf.len() - 48);
+    d.at("noncecounter", 40, Value::Number(counter(&buf[40..48])));
+
+    if let Some(key) = short_short(keys, None) {
+        match open(&key, b"CurveCP-server-M", &buf[40..48], &buf[48..]) {
+            Some(text) => message(d, &text),
+            None => d.problem(String::from("box does not open"))
+        }
+    }
+}
+
+fn check_size(d: &mut Dissection, buf: &[u8], what: &str, min: usize, max: usize) {
+    if buf.len() < min || buf.len() > max {
+        d.problem(format!("{} must be {} to {} bytes", what, min, max));
+    }
+    if buf.len() & 15 != 0 {
+        d.problem(format!("{} size is not a multiple of 16", what));
+    }
+}
+
+/*
+ * Fields of a decrypted message
+ */
+fn message(d: &mut Dissection, text: &[u8]) {
+    d.message = Some(text.to_vec());
+    let m = match Message::decode(text) {
+        Some(m) => m,
+        None => return d.problem(String::from("message is malformed"))
+    };
+    d.inner("id", Value::Number(m.id as u64));
+    d.inner("ackedid", Value::Number(m.acked_id as u64));
+    let acked: Vec<String> = m.acked().iter().map(|&(a, b)| format!("{}-{}", a, b)).collect();
+    d.inner("acked", Value::Text(acked.join(" ")));
+    d.inner("eof", Value::Text(String::from(match m.eof {
+        Eof::None => "none",
+        Eof::Success => "success",
+        Eof::Failure => "failure"
+    })));
+    d.inner("offset", Value::Number(m.offset));
+    d.inner("data", Value::Bytes(m.data.to_vec()));
+}
+
+fn short_long(keys: &Keys, clientshorttermpk: Option<PublicKey>) -> Option<SharedKey> {
+    let clientshorttermpk = clientshorttermpk.or(keys.clientshorttermpk);
+    match (&keys.serverlongtermsk, clientshorttermpk, &keys.clientshorttermsk, keys.serverlongtermpk) {
+        (Some(sk), Some(pk), _, _) => Some(SharedKey::precompute(&pk, sk)),
+        (_, _, Some(sk), Some(pk)) => Some(SharedKey::precompute(&pk, sk)),
+        _ => None
+    }
+}
+
+fn short_short(keys: &Keys, clientshorttermpk: Option<PublicKey>) -> Option<SharedKey> {
+    let clientshorttermpk = clientshorttermpk.or(keys.clientshorttermpk);
+    match (&keys.servershorttermsk, clientshorttermpk, &keys.clientshorttermsk, keys.servershorttermpk) {
+        (Some(sk), Some(pk), _, _) => Some(SharedKey::precompute(&pk, sk)),
+        (_, _, Some(sk), Some(pk)) => Some(SharedKey::precompute(&pk, sk)),
+        _ => None
+    }
+}
+
+fn counter(nonce: &[u8]) -> u64 {
+    u64::from_le_bytes(*array_ref![nonce, 0, 8])
+}
+
+/*
+ * crypto_box_open_afternm of a packet box, None if it doesn't verify
+ */
+fn open(key: &SharedKey, prefix: &[u8], nonce: &[u8], cbox: &[u8]) -> Option<Vec<u8>> {
+    if cbox.len() < 16 {
+        return None;
+    }
+    let mut n = [0; 24];
+    n[..prefix.len()].copy_from_slice(prefix);
+    n[prefix.len()..].copy_from_slice(nonce);
+    let mut text = vec![0; 16 + cbox.len()];
+    text[16..].copy_from_slice(cbox);
+    let ret = unsafe {
+        crypto_box_open_afternm(text.as_mut_ptr(), text.as_ptr(), text.len() as u64,
+                                &n[0], &key.as_bytes()[0])
+    };
+    if ret != 0 {
+        return None;
+    }
+    Some(text.split_off(32))
+}
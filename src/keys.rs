@@ -0,0 +1,251 @@
+/*
+ * Key types.
+ *
+ * Public, secret and shared (precomputed crypto_box) keys are distinct
+ * types so one can't be passed where another is expected. Secret and
+ * shared keys are wiped when dropped and never printed.
+ */
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use rust_sodium_sys::*;
+use rust_sodium::randombytes::randombytes;
+use rustc_serialize::hex::ToHex;
+
+pub const CCP_KEY_SIZE:usize = 32;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct PublicKey(pub [u8; CCP_KEY_SIZE]);
+
+impl PublicKey {
+    pub fn as_bytes(&self) -> &[u8; CCP_KEY_SIZE] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", self.0.to_hex())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SecretKey([u8; CCP_KEY_SIZE]);
+
+impl SecretKey {
+    pub fn new(bytes: [u8; CCP_KEY_SIZE]) -> SecretKey {
+        SecretKey(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; CCP_KEY_SIZE] {
+        &self.0
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        let mut pk = PublicKey::default();
+        unsafe {
+            crypto_scalarmult_base(&mut pk.0[0], &self.0[0]);
+        }
+        pk
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretKey(<redacted>)")
+    }
+}
+
+/*
+ * crypto_box_beforenm of a public and a secret key
+ */
+#[derive(Clone, Default)]
+pub struct SharedKey([u8; CCP_KEY_SIZE]);
+
+impl SharedKey {
+    pub fn precompute(pk: &PublicKey, sk: &SecretKey) -> SharedKey {
+        let mut k = SharedKey::default();
+        unsafe {
+            crypto_box_beforenm(&mut k.0[0], &pk.0[0], &sk.0[0]);
+        }
+        k
+    }
+
+    pub fn as_bytes(&self) -> &[u8; CCP_KEY_SIZE] {
+        &self.0
+    }
+}
+
+impl Drop for SharedKey {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+impl fmt::Debug for SharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedKey(<redacted>)")
+    }
+}
+
+/*
+ * Fresh random key pair
+ */
+pub fn keypair() -> (PublicKey, SecretKey) {
+    let mut pk = PublicKey::default();
+    let mut sk = SecretKey::default();
+    unsafe {
+        crypto_box_keypair(&mut pk.0[0], &mut sk.0[0]);
+    }
+    (pk, sk)
+}
+
+/*
+ * Key pair whose secret key is whatever fill writes
+ */
+pub fn keypair_from<F: FnOnce(&mut [u8])>(fill: F) -> (PublicKey, SecretKey) {
+    let mut sk = SecretKey::default();
+    fill(&mut sk.0);
+    (sk.public_key(), sk)
+}
+
+/*
+ * DNSCurve base32 (digits and consonants, least significant bits first),
+ * the encoding CurveCP server names carry keys in
+ */
+pub fn base32(data: &[u8]) -> String {
+    let alphabet = b"0123456789bcdfghjklmnpqrstuvwxyz";
+    let mut out = String::new();
+    let mut v: u32 = 0;
+    let mut bits = 0;
+    for &b in data {
+        v |= (b as u32) << bits;
+        bits += 8;
+        while bits >= 5 {
+            out.push(alphabet[(v & 31) as usize] as char);
+            v >>= 5;
+            bits -= 5;
+        }
+    }
+    if bits > 0 {
+        out.push(alphabet[(v & 31) as usize] as char);
+    }
+    out
+}
+
+fn wipe(bytes: &mut [u8; CCP_KEY_SIZE]) {
+    unsafe {
+        sodium_memzero(bytes.as_mut_ptr() as *mut _, bytes.len());
+    }
+}
+
+/*
+ * Key directory in the curvecpmakekey layout:
+ *
+ *   publickey                  32 bytes, 0644
+ *   .expertsonly/              0700
+ *   .expertsonly/secretkey     32 bytes, 0600
+ *   .expertsonly/lock          1 zero byte, 0600
+ *   .expertsonly/noncekey      32 random bytes, 0600
+ *   .expertsonly/noncecounter  8 zero bytes, 0600
+ */
+pub struct KeyDir {
+    pub publickey: PublicKey,
+    pub secretkey: SecretKey
+}
+
+impl KeyDir {
+    /*
+     * Make a new key directory with a fresh key pair, fails if path exists
+     */
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<KeyDir> {
+        let (publickey, secretkey) = keypair();
+        KeyDir::save(path, &publickey, &secretkey)?;
+        Ok(KeyDir { publickey, secretkey })
+    }
+
+    /*
+     * Make a new key directory holding the given key pair, fails if path exists
+     */
+    pub fn save<P: AsRef<Path>>(path: P, pk: &PublicKey, sk: &SecretKey) -> io::Result<()> {
+        let path = path.as_ref();
+        let expertsonly = path.join(".expertsonly");
+        mkdir(path, 0o755)?;
+        mkdir(&expertsonly, 0o700)?;
+        writefile(&expertsonly.join("lock"), &[0], 0o600)?;
+        writefile(&expertsonly.join("noncekey"), &randombytes(32), 0o600)?;
+        writefile(&expertsonly.join("noncecounter"), &[0; 8], 0o600)?;
+        writefile(&expertsonly.join("secretkey"), &sk.0, 0o600)?;
+        writefile(&path.join("publickey"), &pk.0, 0o644)
+    }
+
+    /*
+     * Load both keys, the secret key must belong to the public key
+     */
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<KeyDir> {
+        let path = path.as_ref();
+        let publickey = KeyDir::load_public(path)?;
+        let secretkey = SecretKey(readkey(&path.join(".expertsonly").join("secretkey"))?);
+        if secretkey.public_key() != publickey {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "secretkey does not match publickey"));
+        }
+        Ok(KeyDir { publickey, secretkey })
+    }
+
+    /*
+     * Only the public key, e.g. of a server whose directory we can't read fully
+     */
+    pub fn load_public<P: AsRef<Path>>(path: P) -> io::Result<PublicKey> {
+        readkey(&path.as_ref().join("publickey")).map(PublicKey)
+    }
+}
+
+fn readkey(path: &Path) -> io::Result<[u8; CCP_KEY_SIZE]> {
+    let mut key = [0; CCP_KEY_SIZE];
+    let mut f = fs::File::open(path)?;
+    f.read_exact(&mut key)?;
+    if f.read(&mut [0])? != 0 {
+        wipe(&mut key);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "key file is not 32 bytes"));
+    }
+    Ok(key)
+}
+
+// modes are set again after creation so the umask can't loosen or tighten them
+#[cfg(unix)]
+fn mkdir(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    fs::DirBuilder::new().mode(mode).create(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn mkdir(path: &Path, _mode: u32) -> io::Result<()> {
+    fs::DirBuilder::new().create(path)
+}
+
+#[cfg(unix)]
+fn writefile(path: &Path, data: &[u8], mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let mut f = fs::OpenOptions::new().write(true).create_new(true).mode(mode).open(path)?;
+    f.set_permissions(fs::Permissions::from_mode(mode))?;
+    f.write_all(data)?;
+    f.sync_all()
+}
+
+#[cfg(not(unix))]
+fn writefile(path: &Path, data: &[u8], _mode: u32) -> io::Result<()> {
+    let mut f = fs::OpenOptions::new().write(true).create_new(true).open(path)?;
+    f.write_all(data)?;
+    f.sync_all()
+}
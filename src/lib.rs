@@ -0,0 +1,35 @@
+#[macro_use]
+extern crate arrayref;
+extern crate rustc_serialize;
+extern crate rust_sodium_sys;
+extern crate rust_sodium;
+extern crate getopts;
+extern crate toml;
+#[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+extern crate libc;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
+
+pub mod auth;
+pub mod batch;
+pub mod capture;
+pub mod cli;
+pub mod clock;
+pub mod config;
+pub mod dissect;
+pub mod keys;
+pub mod libcurvecp;
+pub mod message;
+pub mod mux;
+pub mod pcap;
+pub mod relay;
+pub mod rng;
+pub mod router;
+pub mod session;
+pub mod sim;
+pub mod socks;
+pub mod tunnel;
+
+#[cfg(test)]
+mod tests;
@@ -1,16 +1,26 @@
 // TODO: implement safenonce
 
 use std::mem;
+use std::sync::Arc;
 use std::u64;
-use std::str;
-use rust_sodium_sys::*;
-use rust_sodium::randombytes::randombytes;
+use config::{ClientConfig, ServerConfig};
+use keys::*;
+use rng::{Rng, SodiumRng};
 //use rustc_serialize::hex::{ToHex};
 
-pub const CCP_MAX_PACKET_SIZE:usize = 1152;
+pub const CCP_MAX_PACKET_SIZE:usize = 1184;
 pub const CCP_MAX_CLIENT_INIT_PAYLOAD_SIZE:usize = 640;
 pub const CCP_MAX_CLIENT_INIT_CBOX_SIZE:usize = 640 + 368;
-pub const CCP_MAX_MESSAGE_SIZE:usize = 1104;
+pub const CCP_MAX_MESSAGE_SIZE:usize = 1088;
+
+/*
+ * Message packets are the signature, both extensions (the receiver's
+ * first), the client short-term key in client packets only, an 8-byte
+ * nonce, a 16-byte authenticator and the message. seal_*_message()
+ * expects the message at these offsets and open_*_message() leaves it there.
+ */
+pub const CCP_CLIENT_MESSAGE_OFFSET:usize = 96;
+pub const CCP_SERVER_MESSAGE_OFFSET:usize = 64;
 
 #[repr(packed)]
 pub struct ClientHello {
@@ -43,98 +53,164 @@ pub struct ClientInitiate {
     cbox: [u8; CCP_MAX_CLIENT_INIT_CBOX_SIZE]
 }
 
-#[repr(packed)]
-pub struct ServerMessage {
-    signature: [u8; 8],
-    client_ext: [u8; 16],
-    server_ext: [u8; 16],
-    nonce: [u8; 8],
-    cbox: [u8; CCP_MAX_MESSAGE_SIZE + 16]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketKind {
+    ClientHello,
+    ServerCookie,
+    ClientInitiate,
+    ClientMessage,
+    ServerMessage
 }
 
-#[repr(packed)]
-pub struct ClientMessage {
-    signature: [u8; 8],
-    server_ext: [u8; 16],
-    client_ext: [u8; 16],
-    client_sterm_pk: [u8; 32],
-    nonce: [u8; 8],
-    cbox: [u8; CCP_MAX_MESSAGE_SIZE + 16]
+/*
+ * Classify datagram by signature and length, None if it can't be a CurveCP packet
+ */
+pub fn packet_kind(buf: &[u8], size: usize) -> Option<PacketKind> {
+    if size < 8 || size > buf.len() || size > CCP_MAX_PACKET_SIZE {
+        return None;
+    }
+    match &buf[..8] {
+        b"QvnQ5XlH" if size == mem::size_of::<ClientHello>() =>
+            Some(PacketKind::ClientHello),
+        b"RL3aNMXK" if size == mem::size_of::<ServerCookie>() =>
+            Some(PacketKind::ServerCookie),
+        _ if size & 15 != 0 => None,
+        b"QvnQ5XlI" if (544 + 16..=544 + CCP_MAX_CLIENT_INIT_PAYLOAD_SIZE).contains(&size) =>
+            Some(PacketKind::ClientInitiate),
+        b"QvnQ5XlM" if (96 + 16..=96 + CCP_MAX_MESSAGE_SIZE).contains(&size) =>
+            Some(PacketKind::ClientMessage),
+        b"RL3aNMXM" if (64 + 16..=64 + CCP_MAX_MESSAGE_SIZE).contains(&size) =>
+            Some(PacketKind::ServerMessage),
+        _ => None
+    }
 }
 
 pub struct CCPContext {
-    clientlongtermpk: [u8; 32],
-    clientlongtermsk: [u8; 32],
-    clientshorttermpk: [u8; 32],
-    clientshorttermsk: [u8; 32],
-    servershorttermpk: [u8; 32],
-    servershorttermsk: [u8; 32],
-    serverlongtermpk: [u8; 32],
-    serverlongtermsk: [u8; 32],
-    clientshortserverlong: [u8; 32],
-    clientshortservershort: [u8; 32],
-    clientlongserverlong: [u8; 32],
+    clientlongtermpk: PublicKey,
+    clientlongtermsk: SecretKey,
+    clientshorttermpk: PublicKey,
+    clientshorttermsk: SecretKey,
+    servershorttermpk: PublicKey,
+    servershorttermsk: SecretKey,
+    serverlongtermpk: PublicKey,
+    serverlongtermsk: SecretKey,
+    clientshortserverlong: SharedKey,
+    clientshortservershort: SharedKey,
+    clientlongserverlong: SharedKey,
     clientshorttermnonce: u64,
     clientext: [u8; 16],
     serverext: [u8; 16],
-    servercookie: [u8; 96]
+    servercookie: [u8; 96],
+    servername: [u8; 256],
+    vouch: [u8; 64],
+    message: [u8; CCP_MAX_MESSAGE_SIZE],
+    messagelen: usize,
+    rng: Arc<dyn Rng>
 }
 
 impl CCPContext {
     pub fn new() -> CCPContext {
+        CCPContext::with_rng(Arc::new(SodiumRng))
+    }
+
+    /*
+     * Context whose short-term keys and random nonces come from rng
+     */
+    pub fn with_rng(rng: Arc<dyn Rng>) -> CCPContext {
         CCPContext {
-            clientlongtermpk: [0; 32],
-            clientlongtermsk: [0; 32],
-            clientshorttermpk: [0; 32],
-            clientshorttermsk: [0; 32],
-            servershorttermpk: [0; 32],
-            servershorttermsk: [0; 32],
-            serverlongtermpk: [0; 32],
-            serverlongtermsk: [0; 32],
-            clientshortserverlong: [0; 32],
-            clientshortservershort: [0; 32],
-            clientlongserverlong: [0; 32],
+            clientlongtermpk: PublicKey::default(),
+            clientlongtermsk: SecretKey::default(),
+            clientshorttermpk: PublicKey::default(),
+            clientshorttermsk: SecretKey::default(),
+            servershorttermpk: PublicKey::default(),
+            servershorttermsk: SecretKey::default(),
+            serverlongtermpk: PublicKey::default(),
+            serverlongtermsk: SecretKey::default(),
+            clientshortserverlong: SharedKey::default(),
+            clientshortservershort: SharedKey::default(),
+            clientlongserverlong: SharedKey::default(),
             clientshorttermnonce: 0,
             clientext: [0; 16],
             serverext: [0; 16],
-            servercookie: [0; 96]
+            servercookie: [0; 96],
+            servername: [0; 256],
+            vouch: [0; 64],
+            message: [0; CCP_MAX_MESSAGE_SIZE],
+            messagelen: 0,
+            rng
         }
     }
 
+    /*
+     * Payload of the last successfully parsed Initiate or Message packet
+     */
+    pub fn message(&self) -> &[u8] {
+        &self.message[..self.messagelen]
+    }
+
+    /*
+     * Client short-term public key, identifies the session on the server side
+     */
+    pub fn clientshorttermpk(&self) -> PublicKey {
+        self.clientshorttermpk
+    }
+
+    /*
+     * Client long-term public key, known to the server after Initiate
+     */
+    pub fn clientlongtermpk(&self) -> PublicKey {
+        self.clientlongtermpk
+    }
+
+    /*
+     * Server name requested by the client in Initiate, None if it is malformed
+     */
+    pub fn servername(&self) -> Option<String> {
+        nameunparse(&self.servername)
+    }
+
+    /*
+     * Short-term secret keys, only for key logs that let captures be decrypted
+     */
+    pub fn clientshorttermsk(&self) -> &SecretKey {
+        &self.clientshorttermsk
+    }
+
+    pub fn servershorttermsk(&self) -> &SecretKey {
+        &self.servershorttermsk
+    }
+
+    pub fn clientext(&self) -> [u8; 16] {
+        self.clientext
+    }
+
+    pub fn serverext(&self) -> [u8; 16] {
+        self.serverext
+    }
+
     /*
      * Make client hello packet
      */
     pub fn mk_client_hello(&mut self,
                        buf: &mut [u8; CCP_MAX_PACKET_SIZE],
-                       clientlongtermpk: [u8; 32],
-                       clientlongtermsk: [u8; 32],
-                       serverlongtermpk: [u8; 32],
-                       clientext: [u8; 16],
-                       serverext: [u8; 16]) -> isize {
+                       config: &ClientConfig) -> isize {
         // init
-        self.clientext = clientext;
-        self.serverext = serverext;
-        self.clientlongtermpk = clientlongtermpk;
-        self.serverlongtermpk = serverlongtermpk;
-        self.clientlongtermsk = clientlongtermsk;
-        self.clientshorttermpk = [0; 32];
-        self.clientshorttermsk = [0; 32];
-        self.clientshorttermnonce = randommod(281474976710656);
-        self.clientshortserverlong = [0; 32];
-        self.clientlongserverlong = [0; 32];
+        self.clientext = config.clientext;
+        self.serverext = config.serverext;
+        self.clientlongtermpk = config.clientlongtermpk;
+        self.serverlongtermpk = config.serverlongtermpk;
+        self.clientlongtermsk = config.clientlongtermsk.clone();
+        self.clientshorttermnonce = self.rng.randommod(281474976710656);
         self.clientshorttermnonce += 1;
 
         // keys
-        unsafe {
-            crypto_box_keypair(&mut self.clientshorttermpk[0],
-                               &mut self.clientshorttermsk[0]);
-            crypto_box_beforenm(&mut self.clientshortserverlong[0],
-                                &mut self.serverlongtermpk[0],
-                                &mut self.clientshorttermsk[0]);
-            crypto_box_beforenm(&mut self.clientlongserverlong[0],
-                                &mut self.serverlongtermpk[0],
-                                &mut self.clientlongtermsk[0]);
-        }
+        let (pk, sk) = self.rng.keypair();
+        self.clientshorttermpk = pk;
+        self.clientshorttermsk = sk;
+        self.clientshortserverlong = SharedKey::precompute(&self.serverlongtermpk,
+                                                           &self.clientshorttermsk);
+        self.clientlongserverlong = SharedKey::precompute(&self.serverlongtermpk,
+                                                          &self.clientlongtermsk);
 
         // signature
         let signature = String::from("QvnQ5XlH").into_bytes();
@@ -146,22 +222,19 @@ impl CCPContext {
             nonce[16+i] = ((self.clientshorttermnonce >> i*8) & 0xFF) as u8;
         }
 
-        // cbox
-        let mut ctext: [u8; 96] = [0; 96];
-        unsafe {
-            let zeros = [0; 96];
-            crypto_box_afternm(&mut ctext[0], &zeros[0], 96, &nonce[0], &self.clientshortserverlong[0]);
-        }
-
         // complete ClientHello packet
         let packet: &mut ClientHello = unsafe { mem::transmute(buf) };
         packet.signature = *array_ref![signature.as_slice(), 0, 8];
         packet.server_ext = self.serverext;
         packet.client_ext = self.clientext;
-        packet.client_sterm_pk = self.clientshorttermpk;
+        packet.client_sterm_pk = self.clientshorttermpk.0;
         packet.pad = [0; 64];
         packet.nonce = *array_ref![nonce[16..], 0, 8];
-        packet.cbox = *array_ref![ctext[16..], 0, 80];
+
+        // cbox, 64 zero bytes
+        packet.cbox = [0; 80];
+        let (mac, text) = packet.cbox.split_at_mut(16);
+        seal(text, mac, &nonce, &self.clientshortserverlong);
 
         return mem::size_of::<ClientHello>() as isize;
     }
@@ -170,8 +243,11 @@ impl CCPContext {
      * Parse server cookie packet
      */
     pub fn parse_server_cookie(&mut self, buf: &[u8; CCP_MAX_PACKET_SIZE], size: usize) -> isize {
+        if size != mem::size_of::<ServerCookie>() {
+            return -1;
+        }
         let packet: &ServerCookie = unsafe { mem::transmute(buf) };
-        if str::from_utf8(&packet.signature).unwrap() != "RL3aNMXK" {
+        if &packet.signature != b"RL3aNMXK" {
             return -1;
         }
         if (packet.client_ext != self.clientext) ||
@@ -185,20 +261,12 @@ impl CCPContext {
             nonce[8+i] = packet.nonce[i];
         }
 
-        let mut text: [u8; 160] = [0; 160];
-        for i in 0..144 {
-            text[16+i] = packet.cbox[i];
-        }
-        unsafe {
-            if crypto_box_open_afternm(&mut text[0],
-                                       &text[0], 160,
-                                       &nonce[0],
-                                       &self.clientshortserverlong[0]) != 0 {
-                return -3;
-            }
+        let mut text: [u8; 128] = [0; 128];
+        if !open_into(&mut text, &packet.cbox[16..], &packet.cbox[..16], &nonce, &self.clientshortserverlong) {
+            return -3;
         }
-        self.servershorttermpk = *array_ref![text[32..], 0, 32];
-        self.servercookie = *array_ref![text[64..], 0, 96];
+        self.servershorttermpk = PublicKey(*array_ref![text, 0, 32]);
+        self.servercookie = *array_ref![text, 32, 96];
 
         return size as isize;
     }
@@ -214,7 +282,8 @@ impl CCPContext {
         if msg.len() < 16 || msg.len() > CCP_MAX_CLIENT_INIT_PAYLOAD_SIZE {
             return -1;
         }
-        if servername.len() > 256 {
+        let name = nameparse(servername);
+        if name.len() > 256 {
             return -2;
         }
 
@@ -225,32 +294,19 @@ impl CCPContext {
         packet.signature = *array_ref![signature.as_slice(), 0, 8];
         packet.server_ext = self.serverext;
         packet.client_ext = self.clientext;
-        packet.client_sterm_pk = self.clientshorttermpk;
+        packet.client_sterm_pk = self.clientshorttermpk.0;
         packet.servercookie = self.servercookie;
 
         // vouch
         let x = String::from("CurveCPV________________").into_bytes();
         let mut nonce: [u8; 24]  = *array_ref![x.as_slice(), 0, 24];
-        let r = randombytes(16);
-        for i in 0..16 {
-            nonce[8+i] = r[i];
-        }
-        let mut text: [u8; 64] = [0; 64];
-        for i in 0..32 {
-            text[32+i] = self.clientshorttermpk[i];
-        }
-        unsafe {
-            crypto_box_afternm(&mut text[0],
-                               &text[0], 64,
-                               &nonce[0],
-                               &self.clientlongserverlong[0]);
-        }
+        self.rng.fill(&mut nonce[8..]);
         let mut vouch: [u8; 64] = [0; 64];
-        for i in 0..16 {
-            vouch[i] = nonce[8+i];
-        }
-        for i in 0..48 {
-            vouch[16+i] = text[16+i];
+        vouch[..16].copy_from_slice(&nonce[8..]);
+        vouch[32..].copy_from_slice(&self.clientshorttermpk.0);
+        {
+            let (mac, text) = vouch[16..].split_at_mut(16);
+            seal(text, mac, &nonce, &self.clientlongserverlong);
         }
 
         // nonce
@@ -262,70 +318,60 @@ impl CCPContext {
         }
         packet.nonce = *array_ref![nonce[16..], 0, 8];
 
-        // cbox
-        let mut text: [u8; 16 + CCP_MAX_CLIENT_INIT_CBOX_SIZE] = [0; 16 + CCP_MAX_CLIENT_INIT_CBOX_SIZE];
-        for i in 0..32 {
-            text[32+i] = self.clientlongtermpk[i];
-        }
-        for i in 0..64 {
-            text[64+i] = vouch[i];
-        }
-        let x = nameparse(servername);
-        for i in 0..x.len() {
-            text[128+i] = x[i];
-        }
-        for i in 0..msg.len() {
-            text[384+i] = msg[i];
+        // cbox: long-term key, vouch, name padded to 256 bytes and message
+        let (mac, text) = packet.cbox[..368 + msg.len()].split_at_mut(16);
+        text[..32].copy_from_slice(&self.clientlongtermpk.0);
+        text[32..96].copy_from_slice(&vouch);
+        text[96..96 + name.len()].copy_from_slice(&name);
+        for b in text[96 + name.len()..352].iter_mut() {
+            *b = 0;
         }
-        unsafe {
-            crypto_box_beforenm(&mut self.clientshortservershort[0],
-                                &self.servershorttermpk[0],
-                                &self.clientshorttermsk[0]);
-            crypto_box_afternm(&mut text[0],
-                               &text[0], (msg.len() + 384) as u64,
-                               &nonce[0],
-                               &self.clientshortservershort[0]);
-        }
-        packet.cbox = *array_ref![text[16..], 0, CCP_MAX_CLIENT_INIT_CBOX_SIZE];
+        text[352..].copy_from_slice(msg);
+        self.clientshortservershort = SharedKey::precompute(&self.servershorttermpk,
+                                                            &self.clientshorttermsk);
+        seal(text, mac, &nonce, &self.clientshortservershort);
 
         return 544 + msg.len() as isize;
     }
 
     /*
-     * Parse server message
+     * Parse server message, its message is then in message()
      */
     pub fn parse_server_message(&mut self, buf: &[u8; CCP_MAX_PACKET_SIZE], size: usize) -> isize {
-        let packet: &ServerMessage = unsafe { mem::transmute(buf) };
-        if str::from_utf8(&packet.signature).unwrap() != "RL3aNMXM" {
-            return -1;
-        }
-        if (packet.client_ext != self.clientext) ||
-           (packet.server_ext != self.serverext) {
-            return -2;
-        }
-
-        let x = String::from("CurveCP-server-M________").into_bytes();
-        let mut nonce: [u8; 24]  = *array_ref![x.as_slice(), 0, 24];
-        for i in 0..8 {
-            nonce[16+i] = packet.nonce[i];
-        }
+        let packet = match buf.get(..size) {
+            Some(packet) => packet,
+            None => return -1
+        };
+        let nonce = match self.message_nonce(packet, false) {
+            Ok(nonce) => nonce,
+            Err(err) => return err
+        };
+        let len = size - CCP_SERVER_MESSAGE_OFFSET;
+        if !open_into(&mut self.message[..len], &packet[CCP_SERVER_MESSAGE_OFFSET..],
+                      &packet[CCP_SERVER_MESSAGE_OFFSET - 16..CCP_SERVER_MESSAGE_OFFSET],
+                      &nonce, &self.clientshortservershort) {
+            return -3;
+        }
+        self.messagelen = len;
+
+        size as isize
+    }
 
-        let mut text: [u8; CCP_MAX_MESSAGE_SIZE + 16] = [0; CCP_MAX_MESSAGE_SIZE + 16];
-        for i in 0..size-48 {
-            text[16+i] = packet.cbox[i];
-        }
-        unsafe {
-            if crypto_box_open_afternm(&mut text[0],
-                                       &text[0], (size-48+16) as u64,
-                                       &nonce[0],
-                                       &self.clientshortservershort[0]) != 0 {
-                return -3;
-            }
+    /*
+     * Open server message packet in place, its message is then in
+     * packet[CCP_SERVER_MESSAGE_OFFSET..]; message() is left alone
+     */
+    pub fn open_server_message(&mut self, packet: &mut [u8]) -> isize {
+        let nonce = match self.message_nonce(packet, false) {
+            Ok(nonce) => nonce,
+            Err(err) => return err
+        };
+        let (mac, text) = packet[CCP_SERVER_MESSAGE_OFFSET - 16..].split_at_mut(16);
+        if !open(text, mac, &nonce, &self.clientshortservershort) {
+            return -3;
         }
 
-        println!("ServerMessage: {}", str::from_utf8(&text).unwrap());
-
-        return size as isize;
+        packet.len() as isize
     }
 
     /*
@@ -337,39 +383,34 @@ impl CCPContext {
         if msg.len() < 16 || msg.len() > CCP_MAX_MESSAGE_SIZE {
             return -1;
         }
+        buf[CCP_CLIENT_MESSAGE_OFFSET..CCP_CLIENT_MESSAGE_OFFSET + msg.len()].copy_from_slice(msg);
+        self.seal_client_message(buf, msg.len())
+    }
 
-        // signature
-        let signature = String::from("QvnQ5XlM").into_bytes();
+    /*
+     * Make client message packet around the len bytes of message the caller
+     * has put at packet[CCP_CLIENT_MESSAGE_OFFSET..], encrypting them in place
+     */
+    pub fn seal_client_message(&mut self, packet: &mut [u8], len: usize) -> isize {
+        if !(16..=CCP_MAX_MESSAGE_SIZE).contains(&len) || packet.len() < CCP_CLIENT_MESSAGE_OFFSET + len {
+            return -1;
+        }
 
-        let packet: &mut ClientMessage = unsafe { mem::transmute(buf) };
-        packet.signature = *array_ref![signature.as_slice(), 0, 8];
-        packet.server_ext = self.serverext;
-        packet.client_ext = self.clientext;
-        packet.client_sterm_pk = self.clientshorttermpk;
+        // header
+        packet[..8].copy_from_slice(b"QvnQ5XlM");
+        packet[8..24].copy_from_slice(&self.serverext);
+        packet[24..40].copy_from_slice(&self.clientext);
+        packet[40..72].copy_from_slice(&self.clientshorttermpk.0);
 
         // nonce
-        self.clientshorttermnonce += 1;
-        let x = String::from("CurveCP-client-M________").into_bytes();
-        let mut nonce: [u8; 24]  = *array_ref![x.as_slice(), 0, 24];
-        for i in 0..8 {
-            nonce[16+i] = ((self.clientshorttermnonce >> i*8) & 0xFF) as u8;
-        }
-        packet.nonce = *array_ref![nonce[16..], 0, 8];
+        let nonce = self.next_message_nonce(b"CurveCP-client-M");
+        packet[72..80].copy_from_slice(&nonce[16..]);
 
         // cbox
-        let mut text: [u8; 32 + CCP_MAX_MESSAGE_SIZE] = [0; 32 + CCP_MAX_MESSAGE_SIZE];
-        for i in 0..msg.len() {
-            text[32+i] = msg[i];
-        }
-        unsafe {
-            crypto_box_afternm(&mut text[0],
-                               &text[0], (msg.len() + 32) as u64,
-                               &nonce[0],
-                               &self.clientshortservershort[0]);
-        }
-        packet.cbox = *array_ref![text[16..], 0, CCP_MAX_MESSAGE_SIZE + 16];
+        let (mac, text) = packet[80..CCP_CLIENT_MESSAGE_OFFSET + len].split_at_mut(16);
+        seal(text, mac, &nonce, &self.clientshortservershort);
 
-        return 96 + msg.len() as isize;
+        (CCP_CLIENT_MESSAGE_OFFSET + len) as isize
     }
 
 
@@ -378,17 +419,18 @@ impl CCPContext {
      */
     pub fn parse_client_hello(&mut self,
                               buf: &[u8; CCP_MAX_PACKET_SIZE], size: usize,
-                              serverlongtermpk: [u8; 32],
-                              serverlongtermsk: [u8; 32],
-                              serverext: [u8; 16]) -> isize {
+                              config: &ServerConfig) -> isize {
         // init
-        self.serverext = serverext;
-        self.serverlongtermpk = serverlongtermpk;
-        self.serverlongtermsk = serverlongtermsk;
+        self.serverext = config.serverext;
+        self.serverlongtermpk = config.serverlongtermpk;
+        self.serverlongtermsk = config.serverlongtermsk.clone();
 
         // parse
+        if size != mem::size_of::<ClientHello>() {
+            return -1;
+        }
         let packet: &ClientHello = unsafe { mem::transmute(buf) };
-        if str::from_utf8(&packet.signature).unwrap() != "QvnQ5XlH" {
+        if &packet.signature != b"QvnQ5XlH" {
             return -1;
         }
         if packet.server_ext != self.serverext {
@@ -403,31 +445,18 @@ impl CCPContext {
         }
 
         // client_sterm_pk
-        self.clientshorttermpk = packet.client_sterm_pk;
-        unsafe {
-            crypto_box_beforenm(&mut self.clientshortserverlong[0],
-                                &self.clientshorttermpk[0],
-                                &serverlongtermsk[0]);
-        }
+        self.clientshorttermpk = PublicKey(packet.client_sterm_pk);
+        self.clientshortserverlong = SharedKey::precompute(&self.clientshorttermpk,
+                                                           &self.serverlongtermsk);
 
         self.clientext = packet.client_ext;
 
-        // cbox
-        let mut text: [u8; 96] = [0; 96];
-        for i in 0..80 {
-            text[16+i] = packet.cbox[i];
-        }
-        unsafe {
-            if crypto_box_open_afternm(&mut text[0],
-                                       &text[0], 96,
-                                       &nonce[0],
-                                       &self.clientshortserverlong[0]) != 0 {
-                return -3;
-            }
+        // cbox, its content is ignored
+        let mut text: [u8; 64] = [0; 64];
+        if !open_into(&mut text, &packet.cbox[16..], &packet.cbox[..16], &nonce, &self.clientshortserverlong) {
+            return -3;
         }
 
-        // ignore content, it is client_sterm_pk + [0; 64]
-
         return size as isize;
     }
 
@@ -449,32 +478,19 @@ impl CCPContext {
         self.clientshorttermnonce += 1;
         let x = String::from("CurveCPKminute-k________").into_bytes();
         let mut nonce: [u8; 24]  = *array_ref![x.as_slice(), 0, 24];
-        let r = randombytes(8); // FIXME: ???
-        for i in 0..8 {
-            nonce[16+i] = r[i];
-        }
+        self.rng.fill(&mut nonce[16..]); // FIXME: ???
         packet.nonce = *array_ref![nonce[8..], 0, 16];
 
-        unsafe {
-            crypto_box_keypair(&mut self.servershorttermpk[0],
-                               &mut self.servershorttermsk[0]);
-        }
+        let (pk, sk) = self.rng.keypair();
+        self.servershorttermpk = pk;
+        self.servershorttermsk = sk;
 
-        // cbox
-        let mut text: [u8; 160] = [0; 160];
-        for i in 0..32 {
-            text[32+i] = self.servershorttermpk[i];
-        }
-        for i in 0..16 {
-            text[64+i] = nonce[8+i];
-        }
-        unsafe {
-            crypto_box_afternm(&mut text[0],
-                               &text[0], 160,
-                               &nonce[0],
-                               &self.clientshortserverlong[0]);
-        }
-        packet.cbox = *array_ref![text[16..], 0, 144];
+        // cbox: short-term key and cookie
+        packet.cbox = [0; 144];
+        let (mac, text) = packet.cbox.split_at_mut(16);
+        text[..32].copy_from_slice(&self.servershorttermpk.0);
+        text[32..48].copy_from_slice(&nonce[8..]);
+        seal(text, mac, &nonce, &self.clientshortserverlong);
         return mem::size_of::<ServerCookie>() as isize;
     }
 
@@ -483,92 +499,138 @@ impl CCPContext {
      * Parse client initiate
      */
     pub fn parse_client_initiate(&mut self, buf: &[u8; CCP_MAX_PACKET_SIZE], size: usize) -> isize {
-        let packet: &ClientInitiate = unsafe { mem::transmute(buf) };
-        if str::from_utf8(&packet.signature).unwrap() != "QvnQ5XlI" {
-            return -1;
-        }
-        if packet.server_ext != self.serverext {
-            return -2;
+        let mut text: [u8; CCP_MAX_CLIENT_INIT_CBOX_SIZE - 16] = [0; CCP_MAX_CLIENT_INIT_CBOX_SIZE - 16];
+        let ret = self.open_client_initiate(buf, size, &mut text);
+        if ret < 0 {
+            return ret;
         }
+        self.clientlongtermpk = PublicKey(*array_ref![text, 0, 32]);
+        self.messagelen = size - 544;
+        self.message[..self.messagelen].copy_from_slice(&text[352..352 + self.messagelen]);
 
-        // nonce
-        let x = String::from("CurveCP-client-I________").into_bytes();
-        let mut nonce: [u8; 24]  = *array_ref![x.as_slice(), 0, 24];
-        for i in 0..8 {
-            nonce[16+i] = packet.nonce[i];
-        }
+        self.vouch = *array_ref![text, 32, 64];
+        self.servername = *array_ref![text, 96, 256];
 
-        unsafe {
-            crypto_box_beforenm(&mut self.clientshortservershort[0],
-                                &self.clientshorttermpk[0],
-                                &self.servershorttermsk[0]);
-        }
+        self.clientlongserverlong = SharedKey::precompute(&self.clientlongtermpk,
+                                                          &self.serverlongtermsk);
 
-        // cbox
-        let mut text: [u8; 16 + CCP_MAX_CLIENT_INIT_CBOX_SIZE] = [0; 16 + CCP_MAX_CLIENT_INIT_CBOX_SIZE];
-        for i in 0..(size - 160) {
-            text[16+i] = packet.cbox[i];
-        }
-        unsafe {
-            if crypto_box_open_afternm(&mut text[0],
-                                       &text[0], (size - 160) as u64,
-                                       &nonce[0],
-                                       &self.clientshortservershort[0]) != 0 {
-                return -3;
-            }
+        ret
+    }
+
+    /*
+     * Parse another client initiate after the first one was accepted: only
+     * its message is taken, and only if it comes from the same client
+     * long-term key with a valid vouch
+     */
+    pub fn parse_client_reinitiate(&mut self, buf: &[u8; CCP_MAX_PACKET_SIZE], size: usize) -> isize {
+        let mut text: [u8; CCP_MAX_CLIENT_INIT_CBOX_SIZE - 16] = [0; CCP_MAX_CLIENT_INIT_CBOX_SIZE - 16];
+        let ret = self.open_client_initiate(buf, size, &mut text);
+        if ret < 0 {
+            return ret;
         }
-        for i in 0..32 {
-            self.clientlongtermpk[i] = text[32+i];
+        if text[..32] != self.clientlongtermpk.0 || !self.vouches(array_ref![text, 32, 64]) {
+            return -3;
         }
+        self.messagelen = size - 544;
+        self.message[..self.messagelen].copy_from_slice(&text[352..352 + self.messagelen]);
 
-        // TODO: check server name
-
-        unsafe {
-            crypto_box_beforenm(&mut self.clientlongserverlong[0],
-                                &self.clientlongtermpk[0],
-                                &self.serverlongtermsk[0]);
-        }
+        ret
+    }
 
-        return size as isize;
+    /*
+     * Check the vouch from the last parsed Initiate: the client long-term key
+     * must have boxed the client short-term key for us
+     */
+    pub fn verify_client_vouch(&self) -> bool {
+        self.vouches(&self.vouch)
     }
 
+    fn vouches(&self, vouch: &[u8; 64]) -> bool {
+        let x = String::from("CurveCPV________________").into_bytes();
+        let mut nonce: [u8; 24]  = *array_ref![x.as_slice(), 0, 24];
+        nonce[8..].copy_from_slice(&vouch[..16]);
+
+        let mut text: [u8; 32] = [0; 32];
+        if !open_into(&mut text, &vouch[32..], &vouch[16..32], &nonce, &self.clientlongserverlong) {
+            return false;
+        }
+        text == self.clientshorttermpk.0
+    }
 
     /*
-     * Parse client message
+     * Check client initiate and open its box into text
      */
-    pub fn parse_client_message(&mut self, buf: &[u8; CCP_MAX_PACKET_SIZE], size: usize) -> isize {
-        let packet: &ClientMessage = unsafe { mem::transmute(buf) };
-        if str::from_utf8(&packet.signature).unwrap() != "QvnQ5XlM" {
+    fn open_client_initiate(&mut self, buf: &[u8; CCP_MAX_PACKET_SIZE], size: usize,
+                            text: &mut [u8; CCP_MAX_CLIENT_INIT_CBOX_SIZE - 16]) -> isize {
+        if !(544 + 16..=544 + CCP_MAX_CLIENT_INIT_PAYLOAD_SIZE).contains(&size) {
             return -1;
         }
-        if packet.server_ext != self.serverext {
+        let packet: &ClientInitiate = unsafe { mem::transmute(buf) };
+        if &packet.signature != b"QvnQ5XlI" {
+            return -1;
+        }
+        if (packet.client_ext != self.clientext) ||
+           (packet.server_ext != self.serverext) {
             return -2;
         }
 
         // nonce
-        let x = String::from("CurveCP-client-M________").into_bytes();
+        let x = String::from("CurveCP-client-I________").into_bytes();
         let mut nonce: [u8; 24]  = *array_ref![x.as_slice(), 0, 24];
-        for i in 0..8 {
-            nonce[16+i] = packet.nonce[i];
-        }
+        nonce[16..].copy_from_slice(&packet.nonce);
+
+        self.clientshortservershort = SharedKey::precompute(&self.clientshorttermpk,
+                                                            &self.servershorttermsk);
 
         // cbox
-        let mut text: [u8; CCP_MAX_MESSAGE_SIZE + 16] = [0; CCP_MAX_MESSAGE_SIZE + 16];
-        for i in 0..size-80 {
-            text[16+i] = packet.cbox[i];
-        }
-        unsafe {
-            if crypto_box_open_afternm(&mut text[0],
-                                       &text[0], (size-80+16) as u64,
-                                       &nonce[0],
-                                       &self.clientshortservershort[0]) != 0 {
-                return -3;
-            }
+        let len = size - 192;
+        if !open_into(&mut text[..len], &packet.cbox[16..16 + len], &packet.cbox[..16],
+                      &nonce, &self.clientshortservershort) {
+            return -3;
         }
 
-        println!("ClientMessage: {}", str::from_utf8(&text).unwrap());
+        size as isize
+    }
 
-        return size as isize;
+
+    /*
+     * Parse client message, its message is then in message()
+     */
+    pub fn parse_client_message(&mut self, buf: &[u8; CCP_MAX_PACKET_SIZE], size: usize) -> isize {
+        let packet = match buf.get(..size) {
+            Some(packet) => packet,
+            None => return -1
+        };
+        let nonce = match self.message_nonce(packet, true) {
+            Ok(nonce) => nonce,
+            Err(err) => return err
+        };
+        let len = size - CCP_CLIENT_MESSAGE_OFFSET;
+        if !open_into(&mut self.message[..len], &packet[CCP_CLIENT_MESSAGE_OFFSET..],
+                      &packet[CCP_CLIENT_MESSAGE_OFFSET - 16..CCP_CLIENT_MESSAGE_OFFSET],
+                      &nonce, &self.clientshortservershort) {
+            return -3;
+        }
+        self.messagelen = len;
+
+        size as isize
+    }
+
+    /*
+     * Open client message packet in place, its message is then in
+     * packet[CCP_CLIENT_MESSAGE_OFFSET..]; message() is left alone
+     */
+    pub fn open_client_message(&mut self, packet: &mut [u8]) -> isize {
+        let nonce = match self.message_nonce(packet, true) {
+            Ok(nonce) => nonce,
+            Err(err) => return err
+        };
+        let (mac, text) = packet[CCP_CLIENT_MESSAGE_OFFSET - 16..].split_at_mut(16);
+        if !open(text, mac, &nonce, &self.clientshortservershort) {
+            return -3;
+        }
+
+        packet.len() as isize
     }
 
 
@@ -581,53 +643,118 @@ impl CCPContext {
         if msg.len() < 16 || msg.len() > CCP_MAX_MESSAGE_SIZE {
             return -1;
         }
+        buf[CCP_SERVER_MESSAGE_OFFSET..CCP_SERVER_MESSAGE_OFFSET + msg.len()].copy_from_slice(msg);
+        self.seal_server_message(buf, msg.len())
+    }
 
-        // signature
-        let signature = String::from("RL3aNMXM").into_bytes();
+    /*
+     * Make server message packet around the len bytes of message the caller
+     * has put at packet[CCP_SERVER_MESSAGE_OFFSET..], encrypting them in place
+     */
+    pub fn seal_server_message(&mut self, packet: &mut [u8], len: usize) -> isize {
+        if !(16..=CCP_MAX_MESSAGE_SIZE).contains(&len) || packet.len() < CCP_SERVER_MESSAGE_OFFSET + len {
+            return -1;
+        }
 
-        let packet: &mut ServerMessage = unsafe { mem::transmute(buf) };
-        packet.signature = *array_ref![signature.as_slice(), 0, 8];
-        packet.server_ext = self.serverext;
-        packet.client_ext = self.clientext;
+        // header
+        packet[..8].copy_from_slice(b"RL3aNMXM");
+        packet[8..24].copy_from_slice(&self.clientext);
+        packet[24..40].copy_from_slice(&self.serverext);
 
         // nonce
-        self.clientshorttermnonce += 1;
-        let x = String::from("CurveCP-server-M________").into_bytes();
-        let mut nonce: [u8; 24]  = *array_ref![x.as_slice(), 0, 24];
-        for i in 0..8 {
-            nonce[16+i] = ((self.clientshorttermnonce >> i*8) & 0xFF) as u8;
-        }
-        packet.nonce = *array_ref![nonce[16..], 0, 8];
+        let nonce = self.next_message_nonce(b"CurveCP-server-M");
+        packet[40..48].copy_from_slice(&nonce[16..]);
 
         // cbox
-        let mut text: [u8; 32 + CCP_MAX_MESSAGE_SIZE] = [0; 32 + CCP_MAX_MESSAGE_SIZE];
-        for i in 0..msg.len() {
-            text[32+i] = msg[i];
-        }
-        unsafe {
-            crypto_box_afternm(&mut text[0],
-                               &text[0], (msg.len() + 32) as u64,
-                               &nonce[0],
-                               &self.clientshortservershort[0]);
-        }
-        packet.cbox = *array_ref![text[16..], 0, CCP_MAX_MESSAGE_SIZE + 16];
+        let (mac, text) = packet[48..CCP_SERVER_MESSAGE_OFFSET + len].split_at_mut(16);
+        seal(text, mac, &nonce, &self.clientshortservershort);
 
-        return 64 + msg.len() as isize;
+        (CCP_SERVER_MESSAGE_OFFSET + len) as isize
+    }
+
+    fn next_message_nonce(&mut self, prefix: &[u8; 16]) -> [u8; 24] {
+        self.clientshorttermnonce += 1;
+        let mut nonce: [u8; 24] = [0; 24];
+        nonce[..16].copy_from_slice(prefix);
+        nonce[16..].copy_from_slice(&self.clientshorttermnonce.to_le_bytes());
+        nonce
+    }
+
+    /*
+     * Check size, signature and extensions of a client or server Message
+     * packet; its nonce, or what to return if it is not one of ours
+     */
+    fn message_nonce(&self, packet: &[u8], client: bool) -> Result<[u8; 24], isize> {
+        let (offset, signature, prefix, exts) = if client {
+            (CCP_CLIENT_MESSAGE_OFFSET, b"QvnQ5XlM", b"CurveCP-client-M", (&self.serverext, &self.clientext))
+        } else {
+            (CCP_SERVER_MESSAGE_OFFSET, b"RL3aNMXM", b"CurveCP-server-M", (&self.clientext, &self.serverext))
+        };
+        if !(offset + 16..=offset + CCP_MAX_MESSAGE_SIZE).contains(&packet.len()) || &packet[..8] != signature {
+            return Err(-1);
+        }
+        if packet[8..24] != exts.0[..] || packet[24..40] != exts.1[..] {
+            return Err(-2);
+        }
+        let mut nonce: [u8; 24] = [0; 24];
+        nonce[..16].copy_from_slice(prefix);
+        nonce[16..].copy_from_slice(&packet[offset - 24..offset - 16]);
+        Ok(nonce)
     }
 }
 
 
+/*
+ * The detached crypto_box calls are in libsodium but not exported by
+ * rust_sodium-sys 0.1
+ */
+extern "C" {
+    fn crypto_box_detached_afternm(c: *mut u8, mac: *mut u8, m: *const u8, mlen: libc::c_ulonglong,
+                                   n: *const u8, k: *const u8) -> libc::c_int;
+    fn crypto_box_open_detached_afternm(m: *mut u8, c: *const u8, mac: *const u8, clen: libc::c_ulonglong,
+                                        n: *const u8, k: *const u8) -> libc::c_int;
+}
 
+/*
+ * crypto_box_detached_afternm in place: text is encrypted where it lies
+ * and its 16-byte authenticator written to mac
+ */
+fn seal(text: &mut [u8], mac: &mut [u8], nonce: &[u8; 24], key: &SharedKey) {
+    assert_eq!(mac.len(), 16);
+    unsafe {
+        let p = text.as_mut_ptr();
+        crypto_box_detached_afternm(p, mac.as_mut_ptr(), p, text.len() as u64,
+                                    &nonce[0], &key.as_bytes()[0]);
+    }
+}
 
-pub fn randommod(n: u64) -> u64 {
-    let mut result:u64 = 0;
-    if n > 1 {
-        let r = randombytes(32);
-        for j in 0..32 {
-            result = (result * 256 + (r[j] as u64)) % n;
-        }
+/*
+ * crypto_box_open_detached_afternm in place, false if text or mac is not
+ * authentic; text is only touched if it is
+ */
+fn open(text: &mut [u8], mac: &[u8], nonce: &[u8; 24], key: &SharedKey) -> bool {
+    assert_eq!(mac.len(), 16);
+    unsafe {
+        let p = text.as_mut_ptr();
+        crypto_box_open_detached_afternm(p, p, mac.as_ptr(), text.len() as u64,
+                                         &nonce[0], &key.as_bytes()[0]) == 0
+    }
+}
+
+/*
+ * Same, decrypting into out, which is as long as text
+ */
+fn open_into(out: &mut [u8], text: &[u8], mac: &[u8], nonce: &[u8; 24], key: &SharedKey) -> bool {
+    assert!(mac.len() == 16 && out.len() == text.len());
+    unsafe {
+        crypto_box_open_detached_afternm(out.as_mut_ptr(), text.as_ptr(), mac.as_ptr(), text.len() as u64,
+                                         &nonce[0], &key.as_bytes()[0]) == 0
     }
-    result
+}
+
+
+pub fn randommod(n: u64) -> u64 {
+    SodiumRng.randommod(n)
 }
 
 pub fn nameparse(source: &str) -> Vec<u8> {
@@ -650,3 +777,23 @@ pub fn nameparse(source: &str) -> Vec<u8> {
     }
     return dst;
 }
+
+/*
+ * Inverse of nameparse, None if labels run past the end
+ */
+pub fn nameunparse(source: &[u8]) -> Option<String> {
+    let mut dst: Vec<u8> = vec![];
+    let mut s = 0;
+    while s < source.len() && source[s] != 0 {
+        let len = source[s] as usize;
+        if s + 1 + len > source.len() {
+            return None;
+        }
+        if !dst.is_empty() {
+            dst.push(b'.');
+        }
+        dst.extend_from_slice(&source[s+1..s+1+len]);
+        s += 1 + len;
+    }
+    String::from_utf8(dst).ok()
+}
@@ -0,0 +1,516 @@
+/*
+ * CurveCP message layer, see http://curvecp.org/messages.html
+ *
+ * Every Initiate and Message packet carries one message: acknowledgements
+ * for the peer's stream plus at most one block of our own stream.
+ * MessageStream turns that into a reliable byte stream in each direction.
+ */
+
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use clock::Clock;
+use config::{Limits, RetransmitPolicy};
+use keys::SecretKey;
+
+pub const MSG_HEADER_SIZE:usize = 56;
+pub const MSG_MAX_BLOCK_SIZE:usize = 1024;
+
+const MSG_FLAG_SUCCESS:u16 = 2048;
+const MSG_FLAG_FAILURE:u16 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eof {
+    None,
+    Success,
+    Failure
+}
+
+/*
+ * Sees the plaintext of every message a session sends or receives,
+ * for debugging tools
+ */
+pub trait MessageTracer: Send + Sync {
+    fn trace(&self, sent: bool, message: &[u8]);
+
+    /*
+     * Every short-term secret key the session makes, so captured
+     * traffic can be decrypted later; ignored unless overridden
+     */
+    fn shortterm_key(&self, _sk: &SecretKey) {}
+}
+
+impl<F> MessageTracer for F
+    where F: Fn(bool, &[u8]) + Send + Sync {
+    fn trace(&self, sent: bool, message: &[u8]) {
+        self(sent, message)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Message<'a> {
+    pub id: u32,
+    pub acked_id: u32,
+    pub acked_first: u64,
+    pub acked_ranges: [(u32, u16); 5],
+    pub eof: Eof,
+    pub offset: u64,
+    pub data: &'a [u8]
+}
+
+impl<'a> Message<'a> {
+    /*
+     * Encoded size: header and data, padded to a multiple of 16
+     */
+    pub fn size(&self) -> usize {
+        (MSG_HEADER_SIZE + self.data.len() + 15) & !15
+    }
+
+    /*
+     * Encode message, data goes to the end of the padded message
+     */
+    pub fn encode(&self, buf: &mut [u8]) -> isize {
+        if self.data.len() > MSG_MAX_BLOCK_SIZE {
+            return -1;
+        }
+        let size = self.size();
+        if buf.len() < size {
+            return -2;
+        }
+
+        for b in buf[..size].iter_mut() {
+            *b = 0;
+        }
+        buf[0..4].copy_from_slice(&self.id.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.acked_id.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.acked_first.to_le_bytes());
+        for i in 0..5 {
+            let (gap, range) = self.acked_ranges[i];
+            buf[16+i*6..20+i*6].copy_from_slice(&gap.to_le_bytes());
+            buf[20+i*6..22+i*6].copy_from_slice(&range.to_le_bytes());
+        }
+        let mut flags = self.data.len() as u16;
+        match self.eof {
+            Eof::None => {},
+            Eof::Success => flags += MSG_FLAG_SUCCESS,
+            Eof::Failure => flags += MSG_FLAG_FAILURE
+        }
+        buf[46..48].copy_from_slice(&flags.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.offset.to_le_bytes());
+        buf[size - self.data.len()..size].copy_from_slice(self.data);
+
+        size as isize
+    }
+
+    /*
+     * Decode message, None if it is malformed
+     */
+    pub fn decode(buf: &'a [u8]) -> Option<Message<'a>> {
+        if buf.len() < MSG_HEADER_SIZE || !buf.len().is_multiple_of(16) {
+            return None;
+        }
+
+        let flags = u16::from_le_bytes(*array_ref![buf, 46, 2]);
+        let len = (flags & 2047) as usize;
+        let eof = match flags & !2047 {
+            0 => Eof::None,
+            MSG_FLAG_SUCCESS => Eof::Success,
+            MSG_FLAG_FAILURE => Eof::Failure,
+            _ => return None
+        };
+        if len > MSG_MAX_BLOCK_SIZE || MSG_HEADER_SIZE + len > buf.len() {
+            return None;
+        }
+        let offset = u64::from_le_bytes(*array_ref![buf, 48, 8]);
+        offset.checked_add(len as u64)?;
+
+        let mut acked_ranges = [(0, 0); 5];
+        for (i, range) in acked_ranges.iter_mut().enumerate() {
+            *range = (u32::from_le_bytes(*array_ref![buf, 16+i*6, 4]),
+                      u16::from_le_bytes(*array_ref![buf, 20+i*6, 2]));
+        }
+
+        Some(Message {
+            id: u32::from_le_bytes(*array_ref![buf, 0, 4]),
+            acked_id: u32::from_le_bytes(*array_ref![buf, 4, 4]),
+            acked_first: u64::from_le_bytes(*array_ref![buf, 8, 8]),
+            acked_ranges,
+            eof,
+            offset,
+            data: &buf[buf.len() - len..]
+        })
+    }
+
+    /*
+     * Acknowledged stream ranges as [start, end) pairs, clamped at the
+     * end of the stream space
+     */
+    pub fn acked(&self) -> Vec<(u64, u64)> {
+        let mut ret = vec![(0, self.acked_first)];
+        let mut pos = self.acked_first;
+        for &(gap, range) in self.acked_ranges.iter() {
+            if range == 0 {
+                break;
+            }
+            pos = pos.saturating_add(gap as u64);
+            let end = pos.saturating_add(range as u64);
+            ret.push((pos, end));
+            pos = end;
+        }
+        ret
+    }
+}
+
+struct Block {
+    pos: u64,
+    len: usize,
+    eof: Eof,
+    id: u32,
+    sent: Option<Instant>,
+    tries: u32
+}
+
+pub struct MessageStream {
+    // sending side
+    sendbuf: VecDeque<u8>,
+    sendbase: u64,
+    sendnext: u64,
+    sendeof: Eof,
+    sendeofqueued: bool,
+    blocks: Vec<Block>,
+    nextid: u32,
+    rto: Duration,
+    srtt: Option<Duration>,
+    policy: RetransmitPolicy,
+    maxsendbuf: usize,
+    maxrecvbuf: usize,
+    clock: Arc<dyn Clock>,
+
+    // receiving side
+    recvbuf: VecDeque<u8>,
+    recvpos: u64,
+    recvooo: BTreeMap<u64, Vec<u8>>,
+    recveof: Eof,
+    recveofpos: u64,
+    ackpending: Option<u32>
+}
+
+impl MessageStream {
+    pub fn new(policy: &RetransmitPolicy, limits: &Limits, clock: Arc<dyn Clock>) -> MessageStream {
+        MessageStream {
+            sendbuf: VecDeque::new(),
+            sendbase: 0,
+            sendnext: 0,
+            sendeof: Eof::None,
+            sendeofqueued: false,
+            blocks: vec![],
+            nextid: 1,
+            rto: policy.initial_rto,
+            srtt: None,
+            policy: policy.clone(),
+            maxsendbuf: limits.max_send_buffer,
+            maxrecvbuf: limits.max_recv_buffer,
+            clock,
+            recvbuf: VecDeque::new(),
+            recvpos: 0,
+            recvooo: BTreeMap::new(),
+            recveof: Eof::None,
+            recveofpos: 0,
+            ackpending: None
+        }
+    }
+
+    /*
+     * Queue bytes for sending, WouldBlock when the send buffer is full
+     */
+    pub fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.sendeof != Eof::None {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream closed"));
+        }
+        let n = cmp::min(data.len(), self.maxsendbuf.saturating_sub(self.sendbuf.len()));
+        if n == 0 && !data.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "send buffer full"));
+        }
+        self.sendbuf.extend(&data[..n]);
+        Ok(n)
+    }
+
+    /*
+     * Read received bytes: Ok(0) on success EOF, WouldBlock if nothing yet
+     */
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.recvbuf.is_empty() {
+            return match self.recveof {
+                Eof::Success if self.recvpos == self.recveofpos => Ok(0),
+                Eof::Failure if self.recvpos == self.recveofpos =>
+                    Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream failed")),
+                _ => Err(io::Error::new(io::ErrorKind::WouldBlock, "no data"))
+            };
+        }
+        let n = cmp::min(buf.len(), self.recvbuf.len());
+        for (i, b) in self.recvbuf.drain(..n).enumerate() {
+            buf[i] = b;
+        }
+        Ok(n)
+    }
+
+    /*
+     * Send EOF after all queued bytes
+     */
+    pub fn close(&mut self, eof: Eof) {
+        if self.sendeof == Eof::None {
+            self.sendeof = eof;
+        }
+    }
+
+    /*
+     * Both directions reached EOF, everything was acknowledged and read
+     */
+    pub fn is_finished(&self) -> bool {
+        self.sendeofqueued && self.blocks.is_empty() &&
+            self.recveof != Eof::None && self.recvpos == self.recveofpos &&
+            self.recvbuf.is_empty()
+    }
+
+    pub fn is_acked(&self) -> bool {
+        self.blocks.is_empty() && self.sendbuf.is_empty()
+    }
+
+    /*
+     * Something is waiting for the peer, either data, EOF or an ack
+     */
+    pub fn wants_transmit(&self) -> bool {
+        self.ackpending.is_some() ||
+            (self.blocks.len() < self.policy.max_inflight &&
+             (self.sendnext < self.sendbase + self.sendbuf.len() as u64 ||
+              (self.sendeof != Eof::None && !self.sendeofqueued)))
+    }
+
+    /*
+     * Earliest retransmission deadline
+     */
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.blocks.iter()
+            .filter_map(|b| b.sent.map(|t| t + self.backoff(b.tries)))
+            .min()
+    }
+
+    /*
+     * Next message to send, at most maxsize bytes.
+     * Retransmissions go first, then new data, then a bare acknowledgement.
+     */
+    pub fn poll_message(&mut self, out: &mut [u8], maxsize: usize) -> Option<usize> {
+        let now = self.clock.now();
+        let maxblock = cmp::min(MSG_MAX_BLOCK_SIZE, (cmp::min(maxsize, out.len()) & !15) - MSG_HEADER_SIZE);
+
+        // retransmit
+        let mut due: Option<usize> = None;
+        for (i, b) in self.blocks.iter().enumerate() {
+            if b.len > maxblock {
+                continue;
+            }
+            let deadline = match b.sent {
+                Some(t) => t + self.backoff(b.tries),
+                None => now
+            };
+            if deadline <= now {
+                due = match due {
+                    Some(j) if self.blocks[j].sent <= b.sent => Some(j),
+                    _ => Some(i)
+                };
+            }
+        }
+        if let Some(i) = due {
+            return Some(self.send_block(i, now, out));
+        }
+
+        // new block
+        if self.blocks.len() < self.policy.max_inflight {
+            let end = self.sendbase + self.sendbuf.len() as u64;
+            let len = cmp::min((end - self.sendnext) as usize, maxblock);
+            if len > 0 || (self.sendeof != Eof::None && !self.sendeofqueued) {
+                let eof = if self.sendnext + len as u64 == end { self.sendeof } else { Eof::None };
+                if eof != Eof::None {
+                    self.sendeofqueued = true;
+                }
+                self.blocks.push(Block {
+                    pos: self.sendnext,
+                    len,
+                    eof,
+                    id: 0,
+                    sent: None,
+                    tries: 0
+                });
+                self.sendnext += len as u64;
+                let i = self.blocks.len() - 1;
+                return Some(self.send_block(i, now, out));
+            }
+        }
+
+        // bare acknowledgement
+        if self.ackpending.is_some() {
+            let msg = self.mk_message(0, Eof::None, 0, &[]);
+            return Some(msg.encode(out) as usize);
+        }
+
+        None
+    }
+
+    /*
+     * Message carrying no data but asking the peer for an acknowledgement
+     */
+    pub fn poll_probe(&mut self, out: &mut [u8]) -> usize {
+        let id = self.take_id();
+        let msg = self.mk_message(id, Eof::None, self.sendnext, &[]);
+        msg.encode(out) as usize
+    }
+
+    /*
+     * Process message received from the peer
+     */
+    pub fn handle_message(&mut self, buf: &[u8]) -> io::Result<()> {
+        let msg = match Message::decode(buf) {
+            Some(msg) => msg,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed message"))
+        };
+        let now = self.clock.now();
+
+        // acknowledgements
+        let acked = msg.acked();
+        let mut rtt = None;
+        let mut i = 0;
+        while i < self.blocks.len() {
+            let done = {
+                let b = &self.blocks[i];
+                let end = b.pos + b.len as u64;
+                (msg.acked_id != 0 && msg.acked_id == b.id) ||
+                    (b.len > 0 && acked.iter().any(|&(s, e)| s <= b.pos && end <= e))
+            };
+            if done {
+                let b = self.blocks.remove(i);
+                if b.tries == 1 && b.id == msg.acked_id {
+                    rtt = b.sent.map(|t| now.duration_since(t));
+                }
+            } else {
+                i += 1;
+            }
+        }
+        if let Some(rtt) = rtt {
+            self.update_rtt(rtt);
+        }
+        let base = self.blocks.iter().map(|b| b.pos).min().unwrap_or(self.sendnext);
+        if base > self.sendbase {
+            self.sendbuf.drain(..(base - self.sendbase) as usize);
+            self.sendbase = base;
+        }
+
+        // data
+        if msg.eof != Eof::None && self.recveof == Eof::None {
+            self.recveof = msg.eof;
+            self.recveofpos = msg.offset + msg.data.len() as u64;
+        }
+        let end = msg.offset + msg.data.len() as u64;
+        if !msg.data.is_empty() && end > self.recvpos {
+            if self.recvbuf.len() >= self.maxrecvbuf {
+                // application is not reading, let the peer retransmit later
+            } else if msg.offset <= self.recvpos {
+                let skip = (self.recvpos - msg.offset) as usize;
+                self.recvbuf.extend(&msg.data[skip..]);
+                self.recvpos = end;
+            } else if end <= self.recvpos + self.maxrecvbuf as u64 {
+                self.recvooo.insert(msg.offset, msg.data.to_vec());
+            }
+            self.merge_received();
+        }
+
+        if msg.id != 0 {
+            self.ackpending = Some(msg.id);
+        }
+
+        Ok(())
+    }
+
+    fn merge_received(&mut self) {
+        loop {
+            let first = match self.recvooo.keys().next() {
+                Some(&pos) if pos <= self.recvpos => pos,
+                _ => return
+            };
+            let data = self.recvooo.remove(&first).unwrap();
+            let end = first + data.len() as u64;
+            if end > self.recvpos {
+                let skip = (self.recvpos - first) as usize;
+                self.recvbuf.extend(&data[skip..]);
+                self.recvpos = end;
+            }
+        }
+    }
+
+    fn send_block(&mut self, i: usize, now: Instant, out: &mut [u8]) -> usize {
+        let id = self.take_id();
+        let (pos, len, eof) = {
+            let b = &mut self.blocks[i];
+            b.id = id;
+            b.sent = Some(now);
+            b.tries += 1;
+            (b.pos, b.len, b.eof)
+        };
+        let start = (pos - self.sendbase) as usize;
+        let data: Vec<u8> = self.sendbuf.range(start..start + len).cloned().collect();
+        let msg = self.mk_message(id, eof, pos, &data);
+        msg.encode(out) as usize
+    }
+
+    fn mk_message<'a>(&mut self, id: u32, eof: Eof, offset: u64, data: &'a [u8]) -> Message<'a> {
+        let mut acked_ranges = [(0, 0); 5];
+        let mut pos = self.recvpos;
+        let mut n = 0;
+        for (&start, block) in self.recvooo.iter() {
+            if start < pos {
+                continue;
+            }
+            if n == 5 || start - pos > u32::MAX as u64 || block.len() > u16::MAX as usize {
+                break;
+            }
+            acked_ranges[n] = ((start - pos) as u32, block.len() as u16);
+            pos = start + block.len() as u64;
+            n += 1;
+        }
+        let acked_id = self.ackpending.take().unwrap_or(0);
+
+        Message {
+            id,
+            acked_id,
+            acked_first: self.recvpos,
+            acked_ranges,
+            eof,
+            offset,
+            data
+        }
+    }
+
+    fn take_id(&mut self) -> u32 {
+        let id = self.nextid;
+        self.nextid = self.nextid.wrapping_add(1);
+        if self.nextid == 0 {
+            self.nextid = 1;
+        }
+        id
+    }
+
+    fn backoff(&self, tries: u32) -> Duration {
+        let rto = self.rto * (1 << cmp::min(tries.saturating_sub(1), 6));
+        cmp::min(rto, self.policy.max_rto)
+    }
+
+    fn update_rtt(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt
+        };
+        self.srtt = Some(srtt);
+        self.rto = cmp::min(cmp::max(srtt * 2, self.policy.min_rto), self.policy.max_rto);
+    }
+}
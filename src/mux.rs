@@ -0,0 +1,365 @@
+/*
+ * Optional stream multiplexing on top of one session's byte stream.
+ *
+ * Frame: 1 byte type, 4 bytes stream id, 2 bytes length, payload.
+ * Streams opened by the client have odd ids, by the server even ids.
+ * Each stream has its own send window, replenished by WINDOW frames
+ * as the receiving application reads.
+ */
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::{Read, Write};
+use config::Limits;
+
+pub type StreamId = u32;
+
+pub const MUX_HEADER_SIZE:usize = 7;
+pub const MUX_MAX_FRAME_DATA:usize = 4096;
+pub const MUX_INITIAL_WINDOW:u32 = 65536;
+
+const FRAME_DATA:u8 = 0;
+const FRAME_WINDOW:u8 = 1;
+const FRAME_FIN:u8 = 2;
+const FRAME_RST:u8 = 3;
+
+struct MuxStream {
+    sendbuf: VecDeque<u8>,
+    sendwindow: u32,
+    sendfin: bool,
+    finsent: bool,
+    recvbuf: VecDeque<u8>,
+    recvfin: bool,
+    recvconsumed: u32,
+    eofread: bool,
+    reset: bool
+}
+
+impl MuxStream {
+    fn new() -> MuxStream {
+        MuxStream {
+            sendbuf: VecDeque::new(),
+            sendwindow: MUX_INITIAL_WINDOW,
+            sendfin: false,
+            finsent: false,
+            recvbuf: VecDeque::new(),
+            recvfin: false,
+            recvconsumed: 0,
+            eofread: false,
+            reset: false
+        }
+    }
+}
+
+pub struct Mux {
+    initiator: bool,
+    nextid: StreamId,
+    maxpeerid: StreamId,
+    maxstreams: usize,
+    streams: HashMap<StreamId, MuxStream>,
+    order: VecDeque<StreamId>,
+    incoming: VecDeque<StreamId>,
+    control: VecDeque<(u8, StreamId, u32)>,
+    inbuf: Vec<u8>,
+    outbuf: Vec<u8>,
+    peerclosed: bool
+}
+
+impl Mux {
+    /*
+     * initiator is true on the client side of the session
+     */
+    pub fn new(initiator: bool, limits: &Limits) -> Mux {
+        Mux {
+            initiator,
+            nextid: if initiator { 1 } else { 2 },
+            maxpeerid: 0,
+            maxstreams: limits.max_streams,
+            streams: HashMap::new(),
+            order: VecDeque::new(),
+            incoming: VecDeque::new(),
+            control: VecDeque::new(),
+            inbuf: vec![],
+            outbuf: vec![],
+            peerclosed: false
+        }
+    }
+
+    /*
+     * Open a new outgoing stream, the peer learns about it with the first frame
+     */
+    pub fn open_stream(&mut self) -> io::Result<StreamId> {
+        if self.peerclosed {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "session closed"));
+        }
+        if self.streams.len() >= self.maxstreams {
+            return Err(io::Error::other("too many streams"));
+        }
+        let id = self.nextid;
+        self.nextid += 2;
+        self.streams.insert(id, MuxStream::new());
+        self.order.push_back(id);
+        Ok(id)
+    }
+
+    /*
+     * Next stream opened by the peer
+     */
+    pub fn accept_stream(&mut self) -> Option<StreamId> {
+        self.incoming.pop_front()
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub fn write(&mut self, id: StreamId, data: &[u8]) -> io::Result<usize> {
+        let stream = self.stream(id)?;
+        if stream.sendfin {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream closed"));
+        }
+        let room = (MUX_INITIAL_WINDOW as usize).saturating_sub(stream.sendbuf.len());
+        let n = cmp::min(room, data.len());
+        if n == 0 && !data.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "stream send buffer full"));
+        }
+        stream.sendbuf.extend(&data[..n]);
+        Ok(n)
+    }
+
+    /*
+     * Ok(0) once the peer closed the stream and everything was read
+     */
+    pub fn read(&mut self, id: StreamId, buf: &mut [u8]) -> io::Result<usize> {
+        let peerclosed = self.peerclosed;
+        let (n, credit) = {
+            let stream = self.stream(id)?;
+            if stream.recvbuf.is_empty() {
+                if stream.recvfin {
+                    stream.eofread = true;
+                    (0, 0)
+                } else if peerclosed {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "session closed"));
+                } else {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "no data"));
+                }
+            } else {
+                let n = cmp::min(buf.len(), stream.recvbuf.len());
+                for (i, b) in stream.recvbuf.drain(..n).enumerate() {
+                    buf[i] = b;
+                }
+                stream.recvconsumed += n as u32;
+                let credit = if stream.recvconsumed >= MUX_INITIAL_WINDOW / 2 && !stream.recvfin {
+                    let credit = stream.recvconsumed;
+                    stream.recvconsumed = 0;
+                    credit
+                } else {
+                    0
+                };
+                (n, credit)
+            }
+        };
+        if credit > 0 {
+            self.control.push_back((FRAME_WINDOW, id, credit));
+        }
+        self.gc(id);
+        Ok(n)
+    }
+
+    /*
+     * Half-close: FIN goes out after the buffered data
+     */
+    pub fn close(&mut self, id: StreamId) -> io::Result<()> {
+        let stream = self.stream(id)?;
+        stream.sendfin = true;
+        Ok(())
+    }
+
+    /*
+     * Abort the stream in both directions
+     */
+    pub fn reset(&mut self, id: StreamId) -> io::Result<()> {
+        self.stream(id)?;
+        self.streams.remove(&id);
+        self.control.push_back((FRAME_RST, id, 0));
+        Ok(())
+    }
+
+    /*
+     * Feed bytes read from the session
+     */
+    pub fn handle_input(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inbuf.extend_from_slice(data);
+        let mut pos = 0;
+        while self.inbuf.len() - pos >= MUX_HEADER_SIZE {
+            let kind = self.inbuf[pos];
+            let id = u32::from_le_bytes(*array_ref![self.inbuf, pos + 1, 4]);
+            let len = u16::from_le_bytes(*array_ref![self.inbuf, pos + 5, 2]) as usize;
+            if self.inbuf.len() - pos < MUX_HEADER_SIZE + len {
+                break;
+            }
+            let payload = self.inbuf[pos + MUX_HEADER_SIZE..pos + MUX_HEADER_SIZE + len].to_vec();
+            pos += MUX_HEADER_SIZE + len;
+            self.handle_frame(kind, id, &payload)?;
+        }
+        self.inbuf.drain(..pos);
+        Ok(())
+    }
+
+    /*
+     * Next chunk of encoded frames for the session, 0 if there is nothing to send
+     */
+    pub fn poll_output(&mut self, buf: &mut [u8]) -> usize {
+        if self.outbuf.is_empty() {
+            self.fill_output();
+        }
+        let n = cmp::min(buf.len(), self.outbuf.len());
+        buf[..n].copy_from_slice(&self.outbuf[..n]);
+        self.outbuf.drain(..n);
+        n
+    }
+
+    /*
+     * The session's byte stream ended, no more frames will arrive
+     */
+    pub fn handle_eof(&mut self) {
+        self.peerclosed = true;
+    }
+
+    /*
+     * Move bytes between the mux and a non-blocking session stream
+     */
+    pub fn drive<T: Read + Write>(&mut self, io: &mut T) -> io::Result<()> {
+        let mut buf = [0; MUX_MAX_FRAME_DATA];
+        while !self.peerclosed {
+            match io.read(&mut buf) {
+                Ok(0) => self.handle_eof(),
+                Ok(n) => self.handle_input(&buf[..n])?,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e)
+            }
+        }
+        loop {
+            if self.outbuf.is_empty() && !self.fill_output() {
+                break;
+            }
+            match io.write(&self.outbuf) {
+                Ok(n) => { self.outbuf.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(())
+    }
+
+    fn stream(&mut self, id: StreamId) -> io::Result<&mut MuxStream> {
+        let reset = match self.streams.get(&id) {
+            Some(stream) => stream.reset,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "no such stream"))
+        };
+        if reset {
+            self.streams.remove(&id);
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream reset by peer"));
+        }
+        Ok(self.streams.get_mut(&id).unwrap())
+    }
+
+    fn handle_frame(&mut self, kind: u8, id: StreamId, payload: &[u8]) -> io::Result<()> {
+        if !self.streams.contains_key(&id) {
+            // a stream we don't know: new one from the peer, or one already gone
+            let peer = (id % 2 == 1) != self.initiator;
+            if !peer || id <= self.maxpeerid || kind == FRAME_RST {
+                return Ok(());
+            }
+            self.maxpeerid = id;
+            if self.streams.len() >= self.maxstreams {
+                self.control.push_back((FRAME_RST, id, 0));
+                return Ok(());
+            }
+            self.streams.insert(id, MuxStream::new());
+            self.order.push_back(id);
+            self.incoming.push_back(id);
+        }
+
+        let stream = self.streams.get_mut(&id).unwrap();
+        match kind {
+            FRAME_DATA => {
+                if stream.recvfin || stream.recvbuf.len() + payload.len() > MUX_INITIAL_WINDOW as usize {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "stream window exceeded"));
+                }
+                stream.recvbuf.extend(payload);
+            },
+            FRAME_WINDOW => {
+                if payload.len() != 4 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed window frame"));
+                }
+                let credit = u32::from_le_bytes(*array_ref![payload, 0, 4]);
+                stream.sendwindow = stream.sendwindow.saturating_add(credit);
+            },
+            FRAME_FIN => stream.recvfin = true,
+            FRAME_RST => stream.reset = true,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown frame type"))
+        }
+        Ok(())
+    }
+
+    /*
+     * Encode control frames and one round of data frames into outbuf
+     */
+    fn fill_output(&mut self) -> bool {
+        while let Some((kind, id, credit)) = self.control.pop_front() {
+            if kind == FRAME_WINDOW {
+                let payload = credit.to_le_bytes();
+                push_frame(&mut self.outbuf, kind, id, &payload);
+            } else {
+                push_frame(&mut self.outbuf, kind, id, &[]);
+            }
+        }
+
+        for _ in 0..self.order.len() {
+            let id = self.order.pop_front().unwrap();
+            let done = match self.streams.get_mut(&id) {
+                None => continue,
+                Some(stream) => {
+                    let n = cmp::min(cmp::min(stream.sendbuf.len(), stream.sendwindow as usize),
+                                     MUX_MAX_FRAME_DATA);
+                    if n > 0 {
+                        let data: Vec<u8> = stream.sendbuf.drain(..n).collect();
+                        stream.sendwindow -= n as u32;
+                        push_frame(&mut self.outbuf, FRAME_DATA, id, &data);
+                    }
+                    if stream.sendfin && !stream.finsent && stream.sendbuf.is_empty() {
+                        stream.finsent = true;
+                        push_frame(&mut self.outbuf, FRAME_FIN, id, &[]);
+                    }
+                    stream.finsent && stream.eofread
+                }
+            };
+            if done {
+                self.streams.remove(&id);
+            } else {
+                self.order.push_back(id);
+            }
+        }
+
+        !self.outbuf.is_empty()
+    }
+
+    fn gc(&mut self, id: StreamId) {
+        let done = match self.streams.get(&id) {
+            Some(stream) => stream.finsent && stream.eofread,
+            None => false
+        };
+        if done {
+            self.streams.remove(&id);
+        }
+    }
+}
+
+fn push_frame(out: &mut Vec<u8>, kind: u8, id: StreamId, payload: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&id.to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    out.extend_from_slice(payload);
+}
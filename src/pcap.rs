@@ -0,0 +1,338 @@
+/*
+ * UDP datagrams from and to packet capture files.
+ *
+ * read_capture() takes a whole .pcap or .pcapng file and returns the UDP
+ * datagrams in it, over Ethernet (with VLAN tags), raw IP, BSD loopback
+ * or Linux cooked captures. Anything else, including IP fragments, is
+ * skipped. PcapWriter writes classic pcap with raw IP link type, making
+ * up the IP and UDP headers.
+ */
+
+use std::io;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+pub const PCAP_LINKTYPE_NULL:u32 = 0;
+pub const PCAP_LINKTYPE_ETHERNET:u32 = 1;
+pub const PCAP_LINKTYPE_RAW:u32 = 101;
+pub const PCAP_LINKTYPE_LOOP:u32 = 108;
+pub const PCAP_LINKTYPE_LINUX_SLL:u32 = 113;
+pub const PCAP_LINKTYPE_IPV4:u32 = 228;
+pub const PCAP_LINKTYPE_IPV6:u32 = 229;
+pub const PCAP_LINKTYPE_LINUX_SLL2:u32 = 276;
+
+const PCAP_MAGIC:u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NSEC:u32 = 0xa1b23c4d;
+const PCAPNG_SHB:u32 = 0x0a0d0d0a;
+const PCAPNG_IDB:u32 = 1;
+const PCAPNG_PB:u32 = 2;
+const PCAPNG_SPB:u32 = 3;
+const PCAPNG_EPB:u32 = 6;
+const PCAPNG_BYTE_ORDER:u32 = 0x1a2b3c4d;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Datagram {
+    // since the epoch
+    pub time: Duration,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub payload: Vec<u8>
+}
+
+/*
+ * Bounds-checked reads in the file's byte order
+ */
+struct Reader<'a> {
+    data: &'a [u8],
+    big: bool
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, pos: usize, len: usize) -> io::Result<&'a [u8]> {
+        match pos.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(&self.data[pos..end]),
+            _ => Err(invalid("capture is truncated"))
+        }
+    }
+
+    fn u16(&self, pos: usize) -> io::Result<u16> {
+        let b = self.bytes(pos, 2)?;
+        let b = [b[0], b[1]];
+        Ok(if self.big { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+    }
+
+    fn u32(&self, pos: usize) -> io::Result<u32> {
+        let b = self.bytes(pos, 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Ok(if self.big { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+}
+
+/*
+ * UDP datagrams of a pcap or pcapng file, in file order
+ */
+pub fn read_capture(data: &[u8]) -> io::Result<Vec<Datagram>> {
+    if data.len() < 4 {
+        return Err(invalid("not a capture file"));
+    }
+    let magic = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    if magic == PCAPNG_SHB {
+        return read_pcapng(data);
+    }
+    for &big in &[false, true] {
+        let r = Reader { data, big };
+        match r.u32(0)? {
+            PCAP_MAGIC => return read_pcap(&r, 1000),
+            PCAP_MAGIC_NSEC => return read_pcap(&r, 1),
+            _ => {}
+        }
+    }
+    Err(invalid("not a capture file"))
+}
+
+// nanos per unit of the subsecond timestamp
+fn read_pcap(r: &Reader, unit: u32) -> io::Result<Vec<Datagram>> {
+    let linktype = r.u32(20)? & 0xfff;
+    let mut out = vec![];
+    let mut pos = 24;
+    while pos < r.data.len() {
+        let secs = r.u32(pos)?;
+        let frac = r.u32(pos + 4)?;
+        let caplen = r.u32(pos + 8)? as usize;
+        let frame = r.bytes(pos + 16, caplen)?;
+        let time = Duration::new(secs as u64, frac.saturating_mul(unit).min(999_999_999));
+        if let Some(d) = link(linktype, frame, time) {
+            out.push(d);
+        }
+        pos += 16 + caplen;
+    }
+    Ok(out)
+}
+
+fn read_pcapng(data: &[u8]) -> io::Result<Vec<Datagram>> {
+    let mut out = vec![];
+    // link type and timestamp units per second of every interface in the section
+    let mut interfaces: Vec<(u32, u64)> = vec![];
+    let mut r = Reader { data, big: false };
+    let mut pos = 0;
+    while pos < data.len() {
+        // the SHB type reads the same in both byte orders
+        let kind = r.u32(pos)?;
+        if kind == PCAPNG_SHB {
+            r.big = false;
+            if r.u32(pos + 8)? != PCAPNG_BYTE_ORDER {
+                r.big = true;
+                if r.u32(pos + 8)? != PCAPNG_BYTE_ORDER {
+                    return Err(invalid("bad pcapng byte-order magic"));
+                }
+            }
+            interfaces.clear();
+        }
+        let len = r.u32(pos + 4)? as usize;
+        if len < 12 || len & 3 != 0 {
+            return Err(invalid("bad pcapng block length"));
+        }
+        let body = r.bytes(pos + 8, len - 12)?;
+        let b = Reader { data: body, big: r.big };
+        match kind {
+            PCAPNG_IDB => interfaces.push((b.u16(0)? as u32, tsresol(&b)?)),
+            PCAPNG_EPB | PCAPNG_PB => {
+                // the obsolete Packet Block has a 16-bit interface id and a drop count
+                let id = if kind == PCAPNG_EPB { b.u32(0)? } else { b.u16(0)? as u32 };
+                let ts = ((b.u32(4)? as u64) << 32) | b.u32(8)? as u64;
+                let caplen = b.u32(12)? as usize;
+                let frame = b.bytes(20, caplen)?;
+                let &(linktype, unit) = interfaces.get(id as usize)
+                    .ok_or_else(|| invalid("packet for an unknown interface"))?;
+                if let Some(d) = link(linktype, frame, timestamp(ts, unit)) {
+                    out.push(d);
+                }
+            },
+            PCAPNG_SPB => {
+                let &(linktype, _) = interfaces.first()
+                    .ok_or_else(|| invalid("packet for an unknown interface"))?;
+                let caplen = (b.u32(0)? as usize).min(body.len() - 4);
+                if let Some(d) = link(linktype, &body[4..4 + caplen], Duration::from_secs(0)) {
+                    out.push(d);
+                }
+            },
+            _ => {}
+        }
+        pos += len;
+    }
+    Ok(out)
+}
+
+/*
+ * Timestamp units per second from an IDB's if_tsresol option
+ */
+fn tsresol(b: &Reader) -> io::Result<u64> {
+    let mut pos = 8;
+    while pos + 4 <= b.data.len() {
+        let code = b.u16(pos)?;
+        let len = b.u16(pos + 2)? as usize;
+        if code == 0 {
+            break;
+        }
+        if code == 9 && len == 1 {
+            let v = b.bytes(pos + 4, 1)?[0];
+            let exp = (v & 0x7f) as u32;
+            let base: u64 = if v & 0x80 != 0 { 2 } else { 10 };
+            return base.checked_pow(exp).ok_or_else(|| invalid("if_tsresol out of range"));
+        }
+        pos += 4 + ((len + 3) & !3);
+    }
+    Ok(1000000)
+}
+
+fn timestamp(ts: u64, units: u64) -> Duration {
+    let frac = (ts % units) as u128 * 1000000000 / units as u128;
+    Duration::new(ts / units, frac as u32)
+}
+
+fn link(linktype: u32, frame: &[u8], time: Duration) -> Option<Datagram> {
+    let ip = match linktype {
+        PCAP_LINKTYPE_RAW | PCAP_LINKTYPE_IPV4 | PCAP_LINKTYPE_IPV6 => frame,
+        PCAP_LINKTYPE_NULL | PCAP_LINKTYPE_LOOP => frame.get(4..)?,
+        PCAP_LINKTYPE_LINUX_SLL => frame.get(16..)?,
+        PCAP_LINKTYPE_LINUX_SLL2 => frame.get(20..)?,
+        PCAP_LINKTYPE_ETHERNET => {
+            let mut pos = 12;
+            // 802.1Q and 802.1ad tags
+            while frame.len() >= pos + 2 && (frame[pos..pos + 2] == [0x81, 0x00] || frame[pos..pos + 2] == [0x88, 0xa8]) {
+                pos += 4;
+            }
+            frame.get(pos + 2..)?
+        },
+        _ => return None
+    };
+    ip_udp(ip, time)
+}
+
+fn ip_udp(ip: &[u8], time: Duration) -> Option<Datagram> {
+    let (src, dst, udp) = match ip.first()? >> 4 {
+        4 => {
+            if ip.len() < 20 {
+                return None;
+            }
+            let ihl = ((ip[0] & 15) as usize) * 4;
+            let total = be16(ip.get(2..4)?) as usize;
+            let fragment = be16(ip.get(6..8)?);
+            if ip.get(9) != Some(&17) || fragment & 0x3fff != 0 || ihl < 20 || total < ihl {
+                return None;
+            }
+            let src = IpAddr::V4(Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]));
+            let dst = IpAddr::V4(Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]));
+            (src, dst, ip.get(ihl..total.min(ip.len()))?)
+        },
+        6 => {
+            let mut next = *ip.get(6)?;
+            let mut pos = 40;
+            let end = (40 + be16(ip.get(4..6)?) as usize).min(ip.len());
+            // hop-by-hop, routing and destination options headers
+            while next == 0 || next == 43 || next == 60 {
+                next = *ip.get(pos)?;
+                pos += (*ip.get(pos + 1)? as usize + 1) * 8;
+            }
+            if next != 17 {
+                return None;
+            }
+            let src = IpAddr::V6(Ipv6Addr::from(*array_ref![ip.get(8..24)?, 0, 16]));
+            let dst = IpAddr::V6(Ipv6Addr::from(*array_ref![ip.get(24..40)?, 0, 16]));
+            (src, dst, ip.get(pos..end)?)
+        },
+        _ => return None
+    };
+    let len = (be16(udp.get(4..6)?) as usize).min(udp.len());
+    Some(Datagram {
+        time,
+        src: SocketAddr::new(src, be16(&udp[0..2])),
+        dst: SocketAddr::new(dst, be16(&udp[2..4])),
+        payload: udp.get(8..len)?.to_vec()
+    })
+}
+
+fn be16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+/*
+ * Classic pcap writer, microsecond timestamps, raw IP link type
+ */
+pub struct PcapWriter<W: Write> {
+    w: W
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut w: W) -> io::Result<PcapWriter<W>> {
+        let mut header = vec![];
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&65535u32.to_le_bytes());
+        header.extend_from_slice(&PCAP_LINKTYPE_RAW.to_le_bytes());
+        w.write_all(&header)?;
+        Ok(PcapWriter { w })
+    }
+
+    pub fn write(&mut self, d: &Datagram) -> io::Result<()> {
+        let udplen = 8 + d.payload.len();
+        if udplen > 65535 - 40 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "datagram too long"));
+        }
+        let mut frame = match (d.src.ip(), d.dst.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 17, 0, 0];
+                ip[2..4].copy_from_slice(&((20 + udplen) as u16).to_be_bytes());
+                ip.extend_from_slice(&src.octets());
+                ip.extend_from_slice(&dst.octets());
+                let sum = checksum(&ip);
+                ip[10..12].copy_from_slice(&sum.to_be_bytes());
+                ip
+            },
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                let mut ip = vec![0x60, 0, 0, 0, 0, 0, 17, 64];
+                ip[4..6].copy_from_slice(&(udplen as u16).to_be_bytes());
+                ip.extend_from_slice(&src.octets());
+                ip.extend_from_slice(&dst.octets());
+                ip
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "addresses of different families"))
+        };
+        frame.extend_from_slice(&d.src.port().to_be_bytes());
+        frame.extend_from_slice(&d.dst.port().to_be_bytes());
+        frame.extend_from_slice(&(udplen as u16).to_be_bytes());
+        // no UDP checksum, allowed over IPv4 and ignored by readers
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(&d.payload);
+
+        let mut record = vec![];
+        record.extend_from_slice(&(d.time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&d.time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        self.w.write_all(&record)?;
+        self.w.write_all(&frame)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for pair in header.chunks(2) {
+        sum += be16(pair) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,320 @@
+/*
+ * Connecting a session to blocking readers and writers (pipes, stdio,
+ * sockets).
+ *
+ * A thread per direction does the blocking I/O and talks to the packet
+ * loop through bounded channels, so pump() never blocks and a slow
+ * writer pushes back on the session instead of buffering without limit.
+ */
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+use batch::Batch;
+use libcurvecp::CCP_MAX_PACKET_SIZE;
+use session::*;
+
+const RELAY_CHUNK:usize = 4096;
+const RELAY_QUEUE:usize = 16;
+
+// how often relays are polled while the socket is quiet
+const RELAY_TICK:u64 = 10;
+
+/*
+ * Sockets whose sending side can be shut down while the receiving side
+ * stays open
+ */
+pub trait HalfClose: Read + Write + Send + 'static {
+    fn close_write(&self) -> io::Result<()>;
+}
+
+impl HalfClose for TcpStream {
+    fn close_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+impl HalfClose for UnixStream {
+    fn close_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+// sends EOF to the socket's peer once the writer thread lets go of it
+struct SocketWriter<T: HalfClose>(T);
+
+impl<T: HalfClose> Write for SocketWriter<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<T: HalfClose> Drop for SocketWriter<T> {
+    fn drop(&mut self) {
+        let _ = self.0.close_write();
+    }
+}
+
+pub struct Relay {
+    input: Receiver<Vec<u8>>,
+    output: Option<SyncSender<Vec<u8>>>,
+    inbuf: Vec<u8>,
+    outbuf: Vec<u8>,
+    inputeof: bool,
+    outputeof: bool
+}
+
+impl Relay {
+    /*
+     * Bytes read from reader go to the session, bytes from the session go to writer
+     */
+    pub fn new<R, W>(mut reader: R, mut writer: W) -> Relay
+        where R: Read + Send + 'static, W: Write + Send + 'static {
+        let (intx, input) = sync_channel(RELAY_QUEUE);
+        let (output, outrx) = sync_channel::<Vec<u8>>(RELAY_QUEUE);
+
+        thread::spawn(move || {
+            let mut buf = [0; RELAY_CHUNK];
+            loop {
+                // an empty chunk tells the loop about EOF
+                let n = match reader.read(&mut buf) {
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => 0
+                };
+                if intx.send(buf[..n].to_vec()).is_err() || n == 0 {
+                    break;
+                }
+            }
+        });
+        thread::spawn(move || {
+            for data in outrx {
+                if writer.write_all(&data).and_then(|_| writer.flush()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Relay {
+            input,
+            output: Some(output),
+            inbuf: vec![],
+            outbuf: vec![],
+            inputeof: false,
+            outputeof: false
+        }
+    }
+
+    /*
+     * Relay over a socket given as two handles to it (see try_clone). The
+     * session's EOF shuts down the socket's sending side, so its peer sees
+     * EOF even though the receiving side is still open.
+     */
+    pub fn socket<T: HalfClose>(reader: T, writer: T) -> Relay {
+        Relay::new(reader, SocketWriter(writer))
+    }
+
+    /*
+     * Move whatever is ready in both directions without blocking
+     */
+    pub fn pump<S: Read + Write>(&mut self, session: &mut S) -> io::Result<()> {
+        while !self.input_done() {
+            if self.inbuf.is_empty() {
+                match self.input.try_recv() {
+                    Ok(ref data) if data.is_empty() => self.inputeof = true,
+                    Ok(data) => self.inbuf = data,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => self.inputeof = true
+                }
+                continue;
+            }
+            match session.write(&self.inbuf) {
+                Ok(n) => { self.inbuf.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e)
+            }
+        }
+
+        let mut buf = [0; RELAY_CHUNK];
+        loop {
+            if !self.outbuf.is_empty() && !self.flush_output() {
+                break;
+            }
+            if self.outputeof {
+                break;
+            }
+            match session.read(&mut buf) {
+                Ok(0) => self.outputeof = true,
+                Ok(n) => self.outbuf.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    self.outputeof = true;
+                    self.outbuf.clear();
+                    self.output = None;
+                    return Err(e);
+                }
+            }
+        }
+        // closing the channel ends the writer thread, which drops the writer
+        if self.outputeof && self.outbuf.is_empty() {
+            self.output = None;
+        }
+        Ok(())
+    }
+
+    /*
+     * The reader hit EOF and everything it produced went to the session,
+     * time to close the session's sending side
+     */
+    pub fn input_done(&self) -> bool {
+        self.inputeof && self.inbuf.is_empty()
+    }
+
+    /*
+     * The session's data ended and the writer was closed
+     */
+    pub fn output_done(&self) -> bool {
+        self.outputeof && self.output.is_none()
+    }
+
+    /*
+     * Hand the writer whatever is still buffered, waiting for room if needed
+     */
+    pub fn finish(mut self) {
+        if let Some(output) = self.output.take() {
+            if !self.outbuf.is_empty() {
+                let _ = output.send(self.outbuf.split_off(0));
+            }
+        }
+    }
+
+    fn flush_output(&mut self) -> bool {
+        let data = self.outbuf.split_off(0);
+        let ret = match self.output {
+            Some(ref output) => output.try_send(data),
+            None => return true
+        };
+        match ret {
+            Ok(()) => true,
+            Err(TrySendError::Full(data)) => {
+                self.outbuf = data;
+                false
+            },
+            // writer is gone, drop what it would have received
+            Err(TrySendError::Disconnected(_)) => {
+                self.output = None;
+                true
+            }
+        }
+    }
+}
+
+/*
+ * Run a client session over a connected socket until it closes, relaying
+ * its data. Ok(false) if the server never answered.
+ */
+pub fn run_client(session: &mut ClientSession, socket: &UdpSocket, relay: &mut Relay) -> io::Result<bool> {
+    let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+    let mut established = false;
+    while !session.is_closed() {
+        relay.pump(session)?;
+        if relay.input_done() {
+            session.close();
+        }
+
+        while let Some(n) = session.poll_transmit(&mut buf) {
+            socket.send(&buf[..n])?;
+        }
+
+        socket.set_read_timeout(Some(timeout(session.now(), session.next_timeout(), true).unwrap()))?;
+        match socket.recv(&mut buf) {
+            Ok(n) => { session.handle_datagram(&buf, n); },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                          e.kind() == io::ErrorKind::TimedOut ||
+                          e.kind() == io::ErrorKind::ConnectionRefused => {},
+            Err(e) => return Err(e)
+        }
+        established = established || session.is_established();
+    }
+    Ok(established)
+}
+
+/*
+ * Serve sessions on the socket, accept() gives each new one its relay
+ * or None to turn it away. With a limit, returns once that many sessions
+ * were served and finished; otherwise runs until an I/O error.
+ */
+pub fn serve<F>(server: &mut Server, socket: &UdpSocket, limit: Option<usize>, mut accept: F) -> io::Result<()>
+    where F: FnMut(&SessionId, &mut ServerSession) -> Option<Relay> {
+    // received datagrams must never end up in what is sent
+    let mut incoming = Batch::new();
+    let mut outgoing = Batch::new();
+    let mut relays: HashMap<SessionId, Relay> = HashMap::new();
+    let mut served = 0;
+    loop {
+        relays.retain(|id, relay| {
+            let session = match server.session(id) {
+                Some(session) => session,
+                None => return false
+            };
+            let ok = relay.pump(session).is_ok();
+            if !ok || relay.input_done() {
+                session.close();
+            }
+            ok
+        });
+        if limit.is_some_and(|n| served >= n) && relays.is_empty() {
+            return Ok(());
+        }
+
+        while server.poll_transmit_batch(&mut outgoing) > 0 {
+            outgoing.send(socket)?;
+        }
+
+        socket.set_read_timeout(timeout(server.now(), server.next_timeout(), !relays.is_empty()))?;
+        match incoming.recv(socket) {
+            Ok(_) => server.handle_batch(&incoming),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                          e.kind() == io::ErrorKind::TimedOut => {},
+            Err(e) => return Err(e)
+        }
+
+        while let Some(id) = server.accept() {
+            let session = server.session(&id).unwrap();
+            if limit.is_some_and(|n| served >= n) {
+                session.close();
+                continue;
+            }
+            match accept(&id, session) {
+                Some(relay) => {
+                    served += 1;
+                    relays.insert(id, relay);
+                },
+                None => session.close()
+            }
+        }
+    }
+}
+
+/*
+ * Socket read timeout from now until the deadline, at most a tick while relays may have data
+ */
+fn timeout(now: Instant, deadline: Option<Instant>, tick: bool) -> Option<Duration> {
+    let timeout = deadline.map(|t| if t > now { t - now } else { Duration::from_millis(1) });
+    if !tick {
+        return timeout;
+    }
+    let tick = Duration::from_millis(RELAY_TICK);
+    Some(timeout.map_or(tick, |t| t.min(tick)))
+}
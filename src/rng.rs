@@ -0,0 +1,85 @@
+/*
+ * Randomness for the packet layer: short-term key pairs, the first
+ * client nonce, vouch and cookie nonces.
+ *
+ * Contexts draw everything through an Rng, libsodium's unless another
+ * one is set in the config, so tests, fuzzers and known-answer vectors
+ * can replay a handshake byte for byte with SeededRng.
+ */
+
+use std::sync::Mutex;
+use rust_sodium_sys::*;
+use rust_sodium::randombytes::randombytes_into;
+use keys::*;
+
+pub trait Rng: Send + Sync {
+    fn fill(&self, buf: &mut [u8]);
+
+    /*
+     * Uniform in 0..n, 0 if n < 2
+     */
+    fn randommod(&self, n: u64) -> u64 {
+        let mut result:u64 = 0;
+        if n > 1 {
+            let mut r = [0; 32];
+            self.fill(&mut r);
+            for &b in &r {
+                result = (result * 256 + (b as u64)) % n;
+            }
+        }
+        result
+    }
+
+    /*
+     * Key pair with the secret key from fill
+     */
+    fn keypair(&self) -> (PublicKey, SecretKey) {
+        keypair_from(|sk| self.fill(sk))
+    }
+}
+
+/*
+ * randombytes_buf
+ */
+pub struct SodiumRng;
+
+impl Rng for SodiumRng {
+    fn fill(&self, buf: &mut [u8]) {
+        randombytes_into(buf)
+    }
+}
+
+/*
+ * ChaCha20 keystream under a fixed key, each fill takes the next nonce.
+ * Same seed, same bytes; never for real sessions.
+ */
+pub struct SeededRng {
+    key: [u8; 32],
+    counter: Mutex<u64>
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        let mut key = [0; 32];
+        key[..8].copy_from_slice(&seed.to_le_bytes());
+        SeededRng::from_key(key)
+    }
+
+    pub fn from_key(key: [u8; 32]) -> SeededRng {
+        SeededRng { key, counter: Mutex::new(0) }
+    }
+}
+
+impl Rng for SeededRng {
+    fn fill(&self, buf: &mut [u8]) {
+        if buf.is_empty() {
+            return;
+        }
+        let mut counter = self.counter.lock().unwrap();
+        let nonce = counter.to_le_bytes();
+        *counter += 1;
+        unsafe {
+            crypto_stream_chacha20(&mut buf[0], buf.len() as u64, &nonce[0], &self.key[0]);
+        }
+    }
+}
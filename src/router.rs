@@ -0,0 +1,158 @@
+/*
+ * Several virtual servers on one socket.
+ *
+ * Client packets carry the server extension right after the signature,
+ * Router uses it to pick the Server (and so the long-term key, authorizer
+ * and application handler) that gets the packet.
+ */
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use batch::Batch;
+use libcurvecp::*;
+use config::ServerConfig;
+use session::*;
+
+/*
+ * Application side of a virtual server
+ */
+pub trait Handler {
+    /*
+     * New session passed the handshake and authorization
+     */
+    fn accept(&mut self, _id: &SessionId, _session: &mut ServerSession) {}
+
+    /*
+     * Packet for the session arrived, there may be data to read
+     */
+    fn ready(&mut self, id: &SessionId, session: &mut ServerSession);
+}
+
+struct VirtualServer {
+    server: Server,
+    handler: Box<dyn Handler>
+}
+
+pub struct Router {
+    servers: HashMap<[u8; 16], VirtualServer>
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            servers: HashMap::new()
+        }
+    }
+
+    /*
+     * Add virtual server for config.serverext, false if the extension is taken
+     */
+    pub fn add(&mut self, config: ServerConfig, handler: Box<dyn Handler>) -> bool {
+        if self.servers.contains_key(&config.serverext) {
+            return false;
+        }
+        self.servers.insert(config.serverext, VirtualServer {
+            server: Server::new(config),
+            handler
+        });
+        true
+    }
+
+    pub fn remove(&mut self, serverext: &[u8; 16]) -> bool {
+        self.servers.remove(serverext).is_some()
+    }
+
+    pub fn server(&mut self, serverext: &[u8; 16]) -> Option<&mut Server> {
+        self.servers.get_mut(serverext).map(|v| &mut v.server)
+    }
+
+    /*
+     * Route datagram to its virtual server, -2 if no server has its extension
+     */
+    pub fn handle_datagram(&mut self,
+                           buf: &[u8; CCP_MAX_PACKET_SIZE], size: usize,
+                           from: SocketAddr) -> isize {
+        let kind = match packet_kind(buf, size) {
+            Some(kind) => kind,
+            None => return -1
+        };
+        let v = match self.servers.get_mut(array_ref![buf, 8, 16]) {
+            Some(v) => v,
+            None => return -2
+        };
+        let ret = v.server.handle_datagram(buf, size, from);
+        if ret < 0 {
+            return ret;
+        }
+
+        while let Some(id) = v.server.accept() {
+            if let Some(session) = v.server.session(&id) {
+                v.handler.accept(&id, session);
+            }
+        }
+        if kind == PacketKind::ClientInitiate || kind == PacketKind::ClientMessage {
+            let id: SessionId = *array_ref![buf, 40, 32];
+            if let Some(session) = v.server.session(&id) {
+                v.handler.ready(&id, session);
+            }
+        }
+        ret
+    }
+
+    pub fn poll_transmit(&mut self, buf: &mut [u8; CCP_MAX_PACKET_SIZE]) -> Option<(usize, SocketAddr)> {
+        for v in self.servers.values_mut() {
+            if let Some(ret) = v.server.poll_transmit(buf) {
+                return Some(ret);
+            }
+        }
+        None
+    }
+
+    pub fn poll_transmit_batch(&mut self, batch: &mut Batch) -> usize {
+        self.servers.values_mut().map(|v| v.server.poll_transmit_batch(batch)).sum()
+    }
+
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.servers.values().filter_map(|v| v.server.next_timeout()).min()
+    }
+
+    /*
+     * Serve all virtual servers on the socket until an I/O error
+     */
+    pub fn serve(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        // received datagrams must never end up in what is sent
+        let mut incoming = Batch::new();
+        let mut outgoing = Batch::new();
+        loop {
+            while self.poll_transmit_batch(&mut outgoing) > 0 {
+                outgoing.send(socket)?;
+            }
+
+            let timeout = self.servers.values()
+                .filter_map(|v| v.server.next_timeout().map(|t| t.saturating_duration_since(v.server.now())))
+                .min()
+                .map(|t| cmp::max(t, Duration::from_millis(1)));
+            socket.set_read_timeout(timeout)?;
+            match incoming.recv(socket) {
+                Ok(n) => {
+                    for i in 0..n {
+                        let (buf, size, from) = incoming.get(i);
+                        self.handle_datagram(buf, size, from);
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => {},
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
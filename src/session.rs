@@ -0,0 +1,762 @@
+/*
+ * Client and server sessions: the handshake state machine on top of
+ * CCPContext with a MessageStream for the data.
+ *
+ * Nothing here touches the network, the caller moves datagrams between
+ * poll_transmit()/handle_datagram() and its socket and wakes up at
+ * next_timeout().
+ */
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use batch::Batch;
+use clock::Clock;
+use libcurvecp::*;
+use message::*;
+use config::{ClientConfig, ServerConfig};
+use keys::PublicKey;
+
+/*
+ * Sessions are identified by the client short-term public key
+ */
+pub type SessionId = [u8; 32];
+
+/*
+ * Counter in the 8-byte nonce at offset of an Initiate or Message packet
+ */
+fn packet_nonce(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(*array_ref![buf, offset, 8])
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState {
+    Hello,
+    Initiate,
+    Established,
+    Closed
+}
+
+pub struct ClientSession {
+    ctx: CCPContext,
+    state: SessionState,
+    stream: MessageStream,
+    config: ClientConfig,
+    tries: u32,
+    lastsend: Option<Instant>,
+    lastrecv: Instant,
+    lastnonce: u64
+}
+
+impl ClientSession {
+    pub fn new(config: ClientConfig) -> ClientSession {
+        ClientSession {
+            ctx: CCPContext::with_rng(config.rng.clone()),
+            state: SessionState::Hello,
+            stream: MessageStream::new(&config.retransmit, &config.limits, config.clock.clone()),
+            lastrecv: config.clock.now(),
+            config,
+            tries: 0,
+            lastsend: None,
+            lastnonce: 0
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    pub fn clientext(&self) -> [u8; 16] {
+        self.config.clientext
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.state == SessionState::Established
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state == SessionState::Closed
+    }
+
+    /*
+     * Send success EOF once everything written so far is delivered
+     */
+    pub fn close(&mut self) {
+        self.stream.close(Eof::Success);
+    }
+
+    /*
+     * Next datagram to send to the server, None when there is nothing to do
+     */
+    pub fn poll_transmit(&mut self, buf: &mut [u8; CCP_MAX_PACKET_SIZE]) -> Option<usize> {
+        let now = self.config.clock.now();
+        self.check_timeouts(now);
+
+        let ret = match self.state {
+            SessionState::Hello => {
+                if !self.resend_due(now) {
+                    return None;
+                }
+                if self.tries >= self.config.retransmit.hello_tries {
+                    self.state = SessionState::Closed;
+                    return None;
+                }
+                self.tries += 1;
+                self.lastsend = Some(now);
+                let ret = self.ctx.mk_client_hello(buf, &self.config);
+                if let Some(ref tracer) = self.config.tracer {
+                    tracer.shortterm_key(self.ctx.clientshorttermsk());
+                }
+                ret
+            },
+            SessionState::Initiate => {
+                // keep sending Initiate packets until the server answers
+                let mut msg = [0; CCP_MAX_CLIENT_INIT_PAYLOAD_SIZE];
+                let n = match self.stream.poll_message(&mut msg, CCP_MAX_CLIENT_INIT_PAYLOAD_SIZE) {
+                    Some(n) => n,
+                    None => {
+                        if !self.resend_due(now) {
+                            return None;
+                        }
+                        self.stream.poll_probe(&mut msg)
+                    }
+                };
+                self.lastsend = Some(now);
+                self.trace(true, &msg[..n]);
+                self.ctx.mk_client_initiate(buf, &self.config.servername, &msg[..n])
+            },
+            SessionState::Established => {
+                // the message goes straight into the packet, sealed in place
+                let msg = &mut buf[CCP_CLIENT_MESSAGE_OFFSET..];
+                let n = match self.stream.poll_message(msg, CCP_MAX_MESSAGE_SIZE) {
+                    Some(n) => n,
+                    None => {
+                        if self.keepalive().is_none_or(|t| t > now) {
+                            return None;
+                        }
+                        self.stream.poll_probe(msg)
+                    }
+                };
+                self.lastsend = Some(now);
+                self.trace(true, &msg[..n]);
+                self.ctx.seal_client_message(buf, n)
+            },
+            SessionState::Closed => return None
+        };
+
+        if ret < 0 {
+            return None;
+        }
+        Some(ret as usize)
+    }
+
+    /*
+     * Process datagram received from the server
+     */
+    pub fn handle_datagram(&mut self, buf: &[u8; CCP_MAX_PACKET_SIZE], size: usize) -> isize {
+        let ret = match (self.state, packet_kind(buf, size)) {
+            (SessionState::Hello, Some(PacketKind::ServerCookie)) => {
+                let ret = self.ctx.parse_server_cookie(buf, size);
+                if ret < 0 {
+                    return ret;
+                }
+                self.state = SessionState::Initiate;
+                self.lastsend = None;
+                ret
+            },
+            (SessionState::Initiate, Some(PacketKind::ServerMessage)) |
+            (SessionState::Established, Some(PacketKind::ServerMessage)) => {
+                let ret = self.ctx.parse_server_message(buf, size);
+                if ret < 0 {
+                    return ret;
+                }
+                // replayed or reordered, the message layer asks again if needed
+                let nonce = packet_nonce(buf, 40);
+                if nonce <= self.lastnonce {
+                    return -6;
+                }
+                self.lastnonce = nonce;
+                self.trace(false, self.ctx.message());
+                if self.stream.handle_message(self.ctx.message()).is_err() {
+                    return -4;
+                }
+                self.state = SessionState::Established;
+                ret
+            },
+            _ => return -1
+        };
+        self.lastrecv = self.config.clock.now();
+        ret
+    }
+
+    /*
+     * Current time on the config's clock, next_timeout() is measured against it
+     */
+    pub fn now(&self) -> Instant {
+        self.config.clock.now()
+    }
+
+    /*
+     * When poll_transmit() should be called again even without incoming packets
+     */
+    pub fn next_timeout(&self) -> Option<Instant> {
+        let idle = self.lastrecv + self.config.idle_timeout;
+        let timeout = match self.state {
+            SessionState::Closed => return None,
+            SessionState::Hello | SessionState::Initiate => self.lastsend.map(|t| t + self.resend_interval()),
+            SessionState::Established => match (self.stream.next_timeout(), self.keepalive()) {
+                (Some(a), Some(b)) => Some(cmp::min(a, b)),
+                (a, b) => a.or(b)
+            }
+        };
+        Some(timeout.map_or(idle, |t| cmp::min(t, idle)))
+    }
+
+    fn trace(&self, sent: bool, message: &[u8]) {
+        if let Some(ref tracer) = self.config.tracer {
+            tracer.trace(sent, message);
+        }
+    }
+
+    fn resend_interval(&self) -> Duration {
+        self.config.retransmit.hello_interval * (1 << cmp::min(self.tries.saturating_sub(1), 4))
+    }
+
+    /*
+     * When a silent established session probes the server, if it does
+     */
+    fn keepalive(&self) -> Option<Instant> {
+        let interval = self.config.retransmit.keepalive?;
+        if self.stream.is_finished() {
+            return None;
+        }
+        let last = self.lastsend.map_or(self.lastrecv, |t| cmp::max(t, self.lastrecv));
+        Some(last + interval)
+    }
+
+    fn resend_due(&self, now: Instant) -> bool {
+        match self.lastsend {
+            Some(t) => t + self.resend_interval() <= now,
+            None => true
+        }
+    }
+
+    fn check_timeouts(&mut self, now: Instant) {
+        if self.state == SessionState::Hello {
+            return;
+        }
+        if now >= self.lastrecv + self.config.idle_timeout ||
+           (self.stream.is_finished() && !self.stream.wants_transmit()) {
+            self.state = SessionState::Closed;
+        }
+    }
+}
+
+impl io::Read for ClientSession {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl io::Write for ClientSession {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.state == SessionState::Closed {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "session closed"));
+        }
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct ServerSession {
+    ctx: CCPContext,
+    stream: MessageStream,
+    addr: SocketAddr,
+    lastrecv: Instant,
+    lastnonce: u64,
+    idle_timeout: Duration,
+    tracer: Option<Arc<dyn MessageTracer>>,
+    clock: Arc<dyn Clock>,
+    closed: bool
+}
+
+impl ServerSession {
+    pub fn clientlongtermpk(&self) -> PublicKey {
+        self.ctx.clientlongtermpk()
+    }
+
+    /*
+     * Client extension from Hello, used for every packet to this client
+     */
+    pub fn clientext(&self) -> [u8; 16] {
+        self.ctx.clientext()
+    }
+
+    /*
+     * Server name the client asked for in Initiate
+     */
+    pub fn servername(&self) -> Option<String> {
+        self.ctx.servername()
+    }
+
+    /*
+     * Last address the client sent an authenticated packet from
+     */
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn close(&mut self) {
+        self.stream.close(Eof::Success);
+    }
+
+    /*
+     * Deliver the message of an authentic packet with the given nonce;
+     * only a nonce above every earlier one may move the session to from
+     */
+    fn handle_message(&mut self, nonce: u64, from: SocketAddr) -> isize {
+        if nonce <= self.lastnonce {
+            return -6;
+        }
+        self.lastnonce = nonce;
+        if let Some(ref tracer) = self.tracer {
+            tracer.trace(false, self.ctx.message());
+        }
+        if self.stream.handle_message(self.ctx.message()).is_err() {
+            return -4;
+        }
+        self.addr = from;
+        self.lastrecv = self.clock.now();
+        0
+    }
+
+    fn poll_transmit(&mut self, buf: &mut [u8; CCP_MAX_PACKET_SIZE]) -> Option<usize> {
+        if self.closed {
+            return None;
+        }
+        if self.clock.now() >= self.lastrecv + self.idle_timeout ||
+           (self.stream.is_finished() && !self.stream.wants_transmit()) {
+            self.closed = true;
+            return None;
+        }
+
+        let n = self.stream.poll_message(&mut buf[CCP_SERVER_MESSAGE_OFFSET..], CCP_MAX_MESSAGE_SIZE)?;
+        if let Some(ref tracer) = self.tracer {
+            tracer.trace(true, &buf[CCP_SERVER_MESSAGE_OFFSET..CCP_SERVER_MESSAGE_OFFSET + n]);
+        }
+        let ret = self.ctx.seal_server_message(buf, n);
+        if ret < 0 {
+            return None;
+        }
+        Some(ret as usize)
+    }
+
+    fn next_timeout(&self) -> Option<Instant> {
+        if self.closed {
+            return None;
+        }
+        let idle = self.lastrecv + self.idle_timeout;
+        Some(self.stream.next_timeout().map_or(idle, |t| cmp::min(t, idle)))
+    }
+}
+
+impl io::Read for ServerSession {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl io::Write for ServerSession {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "session closed"));
+        }
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/*
+ * Handshake state between Hello and the first valid Initiate
+ */
+struct Pending {
+    ctx: CCPContext,
+    cookie: Vec<u8>,
+    created: Instant
+}
+
+/*
+ * Session table of one server extension and long-term key
+ */
+pub struct Server {
+    config: ServerConfig,
+    pending: HashMap<SessionId, Pending>,
+    sessions: HashMap<SessionId, ServerSession>,
+    order: VecDeque<SessionId>,
+    accepted: VecDeque<SessionId>,
+    outgoing: VecDeque<(Vec<u8>, SocketAddr)>
+}
+
+impl Server {
+    pub fn new(config: ServerConfig) -> Server {
+        Server {
+            config,
+            pending: HashMap::new(),
+            sessions: HashMap::new(),
+            order: VecDeque::new(),
+            accepted: VecDeque::new(),
+            outgoing: VecDeque::new()
+        }
+    }
+
+    /*
+     * Process datagram received from a client
+     */
+    pub fn handle_datagram(&mut self,
+                           buf: &[u8; CCP_MAX_PACKET_SIZE], size: usize,
+                           from: SocketAddr) -> isize {
+        let kind = match packet_kind(buf, size) {
+            Some(kind) => kind,
+            None => return -1
+        };
+        let id: SessionId = *array_ref![buf, 40, 32];
+
+        match kind {
+            PacketKind::ClientHello => {
+                // duplicate Hello, answer with the same cookie
+                if let Some(p) = self.pending.get(&id) {
+                    self.outgoing.push_back((p.cookie.clone(), from));
+                    return size as isize;
+                }
+                if self.sessions.contains_key(&id) {
+                    return -4;
+                }
+                if self.pending.len() >= self.config.limits.max_pending {
+                    self.expire_pending();
+                    if self.pending.len() >= self.config.limits.max_pending {
+                        return -4;
+                    }
+                }
+
+                let mut ctx = CCPContext::with_rng(self.config.rng.clone());
+                let ret = ctx.parse_client_hello(buf, size, &self.config);
+                if ret < 0 {
+                    return ret;
+                }
+                let mut out = [0; CCP_MAX_PACKET_SIZE];
+                let n = ctx.mk_server_cookie(&mut out);
+                if n < 0 {
+                    return n;
+                }
+                let cookie = out[..n as usize].to_vec();
+                self.outgoing.push_back((cookie.clone(), from));
+                self.pending.insert(id, Pending {
+                    ctx,
+                    cookie,
+                    created: self.config.clock.now()
+                });
+                ret
+            },
+            PacketKind::ClientInitiate => {
+                // the client resends Initiate until it hears from us,
+                // nothing but its message may change the session
+                if let Some(session) = self.sessions.get_mut(&id) {
+                    let ret = session.ctx.parse_client_reinitiate(buf, size);
+                    if ret < 0 {
+                        return ret;
+                    }
+                    let err = session.handle_message(packet_nonce(buf, 168), from);
+                    return if err < 0 { err } else { ret };
+                }
+
+                let mut p = match self.pending.remove(&id) {
+                    Some(p) => p,
+                    None => return -4
+                };
+                let ret = p.ctx.parse_client_initiate(buf, size);
+                if ret < 0 {
+                    self.pending.insert(id, p);
+                    return ret;
+                }
+                if !p.ctx.verify_client_vouch() {
+                    return -3;
+                }
+                let name = match p.ctx.servername() {
+                    Some(name) => name,
+                    None => return -5
+                };
+                if !self.config.accepts_name(&name) {
+                    return -5;
+                }
+                if let Some(ref authorizer) = self.config.authorizer {
+                    if !authorizer.authorize(&p.ctx.clientlongtermpk(), &name, &from) {
+                        return -5;
+                    }
+                }
+                if self.sessions.len() >= self.config.limits.max_sessions {
+                    return -4;
+                }
+                let mut session = ServerSession {
+                    ctx: p.ctx,
+                    stream: MessageStream::new(&self.config.retransmit, &self.config.limits, self.config.clock.clone()),
+                    addr: from,
+                    lastrecv: self.config.clock.now(),
+                    lastnonce: 0,
+                    idle_timeout: self.config.idle_timeout,
+                    tracer: self.config.tracer.clone(),
+                    clock: self.config.clock.clone(),
+                    closed: false
+                };
+                if let Some(ref tracer) = session.tracer {
+                    tracer.shortterm_key(session.ctx.servershorttermsk());
+                }
+                let err = session.handle_message(packet_nonce(buf, 168), from);
+                if err < 0 {
+                    return err;
+                }
+                self.sessions.insert(id, session);
+                self.order.push_back(id);
+                self.accepted.push_back(id);
+                ret
+            },
+            PacketKind::ClientMessage => {
+                let session = match self.sessions.get_mut(&id) {
+                    Some(session) => session,
+                    None => return -4
+                };
+                let ret = session.ctx.parse_client_message(buf, size);
+                if ret < 0 {
+                    return ret;
+                }
+                let err = session.handle_message(packet_nonce(buf, 72), from);
+                if err < 0 { err } else { ret }
+            },
+            _ => -1
+        }
+    }
+
+    /*
+     * Process every datagram of a received batch
+     */
+    pub fn handle_batch(&mut self, batch: &Batch) {
+        for i in 0..batch.len() {
+            let (buf, size, from) = batch.get(i);
+            self.handle_datagram(buf, size, from);
+        }
+    }
+
+    /*
+     * Fill batch with datagrams to send, one per session per turn in the
+     * same order as poll_transmit(); how many were added
+     */
+    pub fn poll_transmit_batch(&mut self, batch: &mut Batch) -> usize {
+        let start = batch.len();
+        while !batch.is_full() {
+            match self.outgoing.pop_front() {
+                Some((packet, addr)) => batch.push(&packet, addr),
+                None => break
+            };
+        }
+        // stop once every session in a row had nothing to send
+        let mut idle = 0;
+        while !batch.is_full() && idle < self.order.len() {
+            let id = self.order.pop_front().unwrap();
+            let session = match self.sessions.get_mut(&id) {
+                Some(session) => session,
+                None => continue
+            };
+            let addr = session.addr;
+            let sent = batch.push_with(|buf| session.poll_transmit(buf).map(|n| (n, addr)));
+            if session.is_closed() {
+                self.sessions.remove(&id);
+                continue;
+            }
+            self.order.push_back(id);
+            idle = if sent { 0 } else { idle + 1 };
+        }
+        batch.len() - start
+    }
+
+    /*
+     * Next datagram to send and its destination
+     */
+    pub fn poll_transmit(&mut self, buf: &mut [u8; CCP_MAX_PACKET_SIZE]) -> Option<(usize, SocketAddr)> {
+        if let Some((packet, addr)) = self.outgoing.pop_front() {
+            buf[..packet.len()].copy_from_slice(&packet);
+            return Some((packet.len(), addr));
+        }
+
+        // sessions take turns, a busy one must not starve the rest
+        for _ in 0..self.order.len() {
+            let id = self.order.pop_front().unwrap();
+            let session = match self.sessions.get_mut(&id) {
+                Some(session) => session,
+                None => continue
+            };
+            let sent = session.poll_transmit(buf);
+            if session.is_closed() {
+                self.sessions.remove(&id);
+                continue;
+            }
+            let addr = session.addr;
+            self.order.push_back(id);
+            if let Some(n) = sent {
+                return Some((n, addr));
+            }
+        }
+        None
+    }
+
+    /*
+     * Next session established by a client
+     */
+    pub fn accept(&mut self) -> Option<SessionId> {
+        while let Some(id) = self.accepted.pop_front() {
+            if self.sessions.contains_key(&id) {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    pub fn session(&mut self, id: &SessionId) -> Option<&mut ServerSession> {
+        self.sessions.get_mut(id)
+    }
+
+    pub fn session_ids(&self) -> Vec<SessionId> {
+        self.sessions.keys().cloned().collect()
+    }
+
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.sessions.values().filter_map(|s| s.next_timeout()).min()
+    }
+
+    /*
+     * Current time on the config's clock, next_timeout() is measured against it
+     */
+    pub fn now(&self) -> Instant {
+        self.config.clock.now()
+    }
+
+    /*
+     * Drop expired handshakes, and the oldest ones while the table is
+     * still full: a flood of Hellos must not lock out new clients
+     */
+    fn expire_pending(&mut self) {
+        let now = self.config.clock.now();
+        let lifetime = self.config.cookie_lifetime;
+        self.pending.retain(|_, p| now < p.created + lifetime);
+        while self.pending.len() >= self.config.limits.max_pending {
+            let oldest = match self.pending.iter().min_by_key(|&(_, p)| p.created) {
+                Some((id, _)) => *id,
+                None => break
+            };
+            self.pending.remove(&oldest);
+        }
+    }
+}
+
+/*
+ * Client sessions sharing one socket, told apart by client extension
+ */
+pub struct Client {
+    sessions: HashMap<[u8; 16], (ClientSession, SocketAddr)>,
+    order: VecDeque<[u8; 16]>
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client {
+            sessions: HashMap::new(),
+            order: VecDeque::new()
+        }
+    }
+
+    /*
+     * Start a session to the server at addr. A zero or already used client
+     * extension is replaced with a random one; the extension in use is
+     * returned and identifies the session from now on.
+     */
+    pub fn connect(&mut self, mut config: ClientConfig, addr: SocketAddr) -> [u8; 16] {
+        while config.clientext == [0; 16] || self.sessions.contains_key(&config.clientext) {
+            config = config.random_client_ext();
+        }
+        let clientext = config.clientext;
+        self.sessions.insert(clientext, (ClientSession::new(config), addr));
+        self.order.push_back(clientext);
+        clientext
+    }
+
+    pub fn session(&mut self, clientext: &[u8; 16]) -> Option<&mut ClientSession> {
+        self.sessions.get_mut(clientext).map(|s| &mut s.0)
+    }
+
+    pub fn remove(&mut self, clientext: &[u8; 16]) -> Option<ClientSession> {
+        self.order.retain(|ext| ext != clientext);
+        self.sessions.remove(clientext).map(|s| s.0)
+    }
+
+    pub fn clientexts(&self) -> Vec<[u8; 16]> {
+        self.sessions.keys().cloned().collect()
+    }
+
+    /*
+     * Hand server packet to the session owning its client and server
+     * extensions, -2 if there is none. Where it came from doesn't matter:
+     * the session only takes authentic packets it hasn't seen, so a server
+     * that shows up from another address (NAT rebinding, multihoming)
+     * keeps working, and a forged source gains nothing.
+     */
+    pub fn handle_datagram(&mut self, buf: &[u8; CCP_MAX_PACKET_SIZE], size: usize) -> isize {
+        match packet_kind(buf, size) {
+            Some(PacketKind::ServerCookie) | Some(PacketKind::ServerMessage) => {},
+            _ => return -1
+        }
+        match self.sessions.get_mut(array_ref![buf, 8, 16]) {
+            Some(&mut (ref mut session, _)) if buf[24..40] == session.config.serverext =>
+                session.handle_datagram(buf, size),
+            _ => -2
+        }
+    }
+
+    /*
+     * Next datagram to send and its destination, sessions take turns
+     */
+    pub fn poll_transmit(&mut self, buf: &mut [u8; CCP_MAX_PACKET_SIZE]) -> Option<(usize, SocketAddr)> {
+        for _ in 0..self.order.len() {
+            let clientext = self.order.pop_front().unwrap();
+            let &mut (ref mut session, addr) = match self.sessions.get_mut(&clientext) {
+                Some(s) => s,
+                None => continue
+            };
+            self.order.push_back(clientext);
+            if let Some(n) = session.poll_transmit(buf) {
+                return Some((n, addr));
+            }
+        }
+        None
+    }
+
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.sessions.values().filter_map(|s| s.0.next_timeout()).min()
+    }
+}
+
+impl Default for Client {
+    fn default() -> Client {
+        Client::new()
+    }
+}
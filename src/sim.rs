@@ -0,0 +1,237 @@
+/*
+ * Deterministic network simulator.
+ *
+ * One ClientSession and one Server exchange datagrams in memory on a
+ * ManualClock. Every datagram may be lost, duplicated, held back past
+ * later ones, delayed with jitter or have a bit flipped, and the client
+ * may move to a new port, all decided by a SeededRng. The sessions get
+ * seeded generators too, so one seed always replays the same run, and
+ * the clock only jumps to the next delivery or timer: minutes of
+ * retransmissions simulate in milliseconds.
+ */
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use clock::{Clock, ManualClock};
+use config::{ClientConfig, ServerConfig};
+use libcurvecp::CCP_MAX_PACKET_SIZE;
+use pcap::Datagram;
+use rng::{Rng, SeededRng};
+use session::{ClientSession, Server};
+
+/*
+ * What the network does to each datagram, probabilities from 0 to 1
+ */
+#[derive(Clone, Debug)]
+pub struct Conditions {
+    pub loss: f64,
+    pub duplicate: f64,
+    // held back by twice the delay and jitter, so later datagrams overtake it
+    pub reorder: f64,
+    pub corrupt: f64,
+    // client moves to a new port before sending
+    pub rebind: f64,
+    pub delay: Duration,
+    // uniform extra delay up to this much
+    pub jitter: Duration
+}
+
+impl Default for Conditions {
+    fn default() -> Conditions {
+        Conditions {
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            corrupt: 0.0,
+            rebind: 0.0,
+            delay: Duration::from_millis(10),
+            jitter: Duration::from_secs(0)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub sent: usize,
+    pub delivered: usize,
+    pub lost: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+    pub corrupted: usize,
+    pub rebinds: usize,
+    // sent to an address the client has moved away from
+    pub misrouted: usize
+}
+
+struct Flight {
+    to_server: bool,
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: Vec<u8>
+}
+
+pub struct Simulation {
+    pub client: ClientSession,
+    pub server: Server,
+    pub stats: Stats,
+    // datagrams as they arrived, for pcap::PcapWriter or capture::analyze
+    pub log: Vec<Datagram>,
+    conditions: Conditions,
+    clock: Arc<ManualClock>,
+    rng: SeededRng,
+    client_addr: SocketAddr,
+    server_addr: SocketAddr,
+    // by arrival time, then order of sending
+    inflight: BTreeMap<(Instant, u64), Flight>,
+    seq: u64
+}
+
+impl Simulation {
+    /*
+     * The clock and random generators of both configs are replaced
+     */
+    pub fn new(client: ClientConfig, server: ServerConfig, conditions: Conditions, seed: u64) -> Simulation {
+        let clock = Arc::new(ManualClock::new());
+        let client = client.clock(clock.clone()).rng(Arc::new(SeededRng::new(seed.wrapping_add(1))));
+        let server = server.clock(clock.clone()).rng(Arc::new(SeededRng::new(seed.wrapping_add(2))));
+        Simulation {
+            client: ClientSession::new(client),
+            server: Server::new(server),
+            stats: Stats::default(),
+            log: vec![],
+            conditions,
+            clock,
+            rng: SeededRng::new(seed),
+            client_addr: "10.0.0.2:40000".parse().unwrap(),
+            server_addr: "10.0.0.1:443".parse().unwrap(),
+            inflight: BTreeMap::new(),
+            seq: 0
+        }
+    }
+
+    /*
+     * Simulated time since the start
+     */
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    pub fn client_addr(&self) -> SocketAddr {
+        self.client_addr
+    }
+
+    /*
+     * Call app after every step until it returns true or limit of
+     * simulated time has passed; false if the limit was hit or nothing
+     * was left to happen
+     */
+    pub fn run<F>(&mut self, limit: Duration, mut app: F) -> bool
+        where F: FnMut(&mut ClientSession, &mut Server) -> bool {
+        loop {
+            if app(&mut self.client, &mut self.server) {
+                return true;
+            }
+            if self.elapsed() > limit || !self.step() {
+                return app(&mut self.client, &mut self.server);
+            }
+        }
+    }
+
+    /*
+     * Send whatever both sides have, then deliver what is due or move
+     * the clock to the next event; false once nothing is pending
+     */
+    pub fn step(&mut self) -> bool {
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        while let Some(n) = self.client.poll_transmit(&mut buf) {
+            if self.chance(self.conditions.rebind) {
+                self.client_addr.set_port(self.client_addr.port() + 1);
+                self.stats.rebinds += 1;
+            }
+            let (src, dst) = (self.client_addr, self.server_addr);
+            self.send(true, src, dst, &buf[..n]);
+        }
+        while let Some((n, to)) = self.server.poll_transmit(&mut buf) {
+            let src = self.server_addr;
+            self.send(false, src, to, &buf[..n]);
+        }
+
+        let now = self.clock.now();
+        let due = match self.inflight.keys().next() {
+            Some(&key) if key.0 <= now => key,
+            _ => {
+                let next = [self.inflight.keys().next().map(|k| k.0),
+                            self.client.next_timeout(),
+                            self.server.next_timeout()];
+                return match next.iter().filter_map(|&t| t).min() {
+                    Some(t) => {
+                        self.clock.advance_to(cmp::max(t, now + Duration::from_millis(1)));
+                        true
+                    },
+                    None => false
+                };
+            }
+        };
+        let flight = self.inflight.remove(&due).unwrap();
+        if !flight.to_server && flight.dst != self.client_addr {
+            self.stats.misrouted += 1;
+            return true;
+        }
+        self.stats.delivered += 1;
+        self.log.push(Datagram {
+            time: self.clock.elapsed(),
+            src: flight.src,
+            dst: flight.dst,
+            payload: flight.payload.clone()
+        });
+        let n = flight.payload.len();
+        buf[..n].copy_from_slice(&flight.payload);
+        if flight.to_server {
+            self.server.handle_datagram(&buf, n, flight.src);
+        } else {
+            self.client.handle_datagram(&buf, n);
+        }
+        true
+    }
+
+    fn send(&mut self, to_server: bool, src: SocketAddr, dst: SocketAddr, payload: &[u8]) {
+        self.stats.sent += 1;
+        if self.chance(self.conditions.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+        let copies = if self.chance(self.conditions.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut payload = payload.to_vec();
+            if self.chance(self.conditions.corrupt) && !payload.is_empty() {
+                let bit = self.rng.randommod(payload.len() as u64 * 8) as usize;
+                payload[bit / 8] ^= 1 << (bit & 7);
+                self.stats.corrupted += 1;
+            }
+            let mut delay = self.conditions.delay + self.jitter();
+            if self.chance(self.conditions.reorder) {
+                delay += (self.conditions.delay + self.conditions.jitter) * 2 + Duration::from_millis(1);
+                self.stats.reordered += 1;
+            }
+            self.seq += 1;
+            self.inflight.insert((self.clock.now() + delay, self.seq), Flight { to_server, src, dst, payload });
+        }
+    }
+
+    fn jitter(&self) -> Duration {
+        let micros = self.conditions.jitter.as_micros() as u64;
+        Duration::from_micros(self.rng.randommod(micros + 1))
+    }
+
+    fn chance(&self, p: f64) -> bool {
+        p > 0.0 && (self.rng.randommod(1 << 32) as f64) < p * 4294967296.0
+    }
+}
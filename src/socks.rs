@@ -0,0 +1,183 @@
+/*
+ * SOCKS5 proxying over CurveCP (RFC 1928, CONNECT only).
+ *
+ * The local end is a plain tunnel local end (see tunnel.rs): browsers
+ * talk SOCKS5 to it on localhost and every connection becomes a session.
+ * The remote end answers the SOCKS5 negotiation arriving through each
+ * session and connects the session to the requested address. Clients are
+ * already authenticated by their long-term key, so the only method
+ * offered is "no authentication".
+ */
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::net::{Shutdown, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::thread;
+use std::time::Duration;
+#[cfg(unix)]
+use relay::{serve, Relay};
+#[cfg(unix)]
+use session::Server;
+
+pub const SOCKS_VERSION:u8 = 5;
+pub const SOCKS_NO_AUTH:u8 = 0;
+pub const SOCKS_NO_METHOD:u8 = 0xff;
+pub const SOCKS_CONNECT:u8 = 1;
+pub const SOCKS_ATYP_IPV4:u8 = 1;
+pub const SOCKS_ATYP_DOMAIN:u8 = 3;
+pub const SOCKS_ATYP_IPV6:u8 = 4;
+
+pub const SOCKS_SUCCEEDED:u8 = 0;
+pub const SOCKS_FAILURE:u8 = 1;
+pub const SOCKS_HOST_UNREACHABLE:u8 = 4;
+pub const SOCKS_REFUSED:u8 = 5;
+pub const SOCKS_COMMAND_NOT_SUPPORTED:u8 = 7;
+pub const SOCKS_ATYP_NOT_SUPPORTED:u8 = 8;
+
+const SOCKS_CONNECT_TIMEOUT:u64 = 10;
+
+/*
+ * Server side of the negotiation: read the greeting and the CONNECT
+ * request from stream, connect, and tell the client how it went. The
+ * connected target is returned, the stream then carries its data.
+ */
+pub fn negotiate<S: Read + Write>(stream: &mut S) -> io::Result<TcpStream> {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head)?;
+    if head[0] != SOCKS_VERSION {
+        return Err(invalid("not SOCKS5"));
+    }
+    let mut methods = vec![0; head[1] as usize];
+    stream.read_exact(&mut methods)?;
+    if !methods.contains(&SOCKS_NO_AUTH) {
+        stream.write_all(&[SOCKS_VERSION, SOCKS_NO_METHOD])?;
+        return Err(invalid("client wants authentication"));
+    }
+    stream.write_all(&[SOCKS_VERSION, SOCKS_NO_AUTH])?;
+
+    let mut request = [0; 4];
+    stream.read_exact(&mut request)?;
+    if request[0] != SOCKS_VERSION {
+        return Err(invalid("not SOCKS5"));
+    }
+    let host = match request[3] {
+        SOCKS_ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip)?;
+            Ipv4Addr::from(ip).to_string()
+        },
+        SOCKS_ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip)?;
+            Ipv6Addr::from(ip).to_string()
+        },
+        SOCKS_ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            let mut name = vec![0; len[0] as usize];
+            stream.read_exact(&mut name)?;
+            String::from_utf8(name).map_err(|_| invalid("domain name is not UTF-8"))?
+        },
+        _ => {
+            reply(stream, SOCKS_ATYP_NOT_SUPPORTED, None)?;
+            return Err(invalid("unknown address type"));
+        }
+    };
+    let mut port = [0; 2];
+    stream.read_exact(&mut port)?;
+    if request[1] != SOCKS_CONNECT {
+        reply(stream, SOCKS_COMMAND_NOT_SUPPORTED, None)?;
+        return Err(invalid("only CONNECT is supported"));
+    }
+
+    match connect(&host, u16::from_be_bytes(port)) {
+        Ok(target) => {
+            reply(stream, SOCKS_SUCCEEDED, target.local_addr().ok())?;
+            Ok(target)
+        },
+        Err(e) => {
+            let code = match e.kind() {
+                io::ErrorKind::ConnectionRefused => SOCKS_REFUSED,
+                io::ErrorKind::TimedOut | io::ErrorKind::NotFound => SOCKS_HOST_UNREACHABLE,
+                _ => SOCKS_FAILURE
+            };
+            reply(stream, code, None)?;
+            Err(e)
+        }
+    }
+}
+
+/*
+ * Remote end: every session the server lets in is a SOCKS5 client.
+ * Negotiation and the data after it run on a thread per session, tied
+ * to the session's relay through a socket pair.
+ */
+#[cfg(unix)]
+pub fn forward_socks(server: &mut Server, socket: &UdpSocket) -> io::Result<()> {
+    serve(server, socket, None, |_, _| {
+        let (ours, theirs) = UnixStream::pair().ok()?;
+        let relay = Relay::socket(ours.try_clone().ok()?, ours);
+        thread::spawn(move || proxy(theirs));
+        Some(relay)
+    })
+}
+
+#[cfg(unix)]
+fn proxy(mut stream: UnixStream) -> io::Result<()> {
+    let target = match negotiate(&mut stream) {
+        Ok(target) => target,
+        Err(e) => {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(e);
+        }
+    };
+    let mut upstream = stream.try_clone()?;
+    let mut downstream = target.try_clone()?;
+    let copier = thread::spawn(move || {
+        let _ = io::copy(&mut upstream, &mut downstream);
+        let _ = downstream.shutdown(Shutdown::Write);
+    });
+    let mut target = target;
+    let _ = io::copy(&mut target, &mut stream);
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = copier.join();
+    Ok(())
+}
+
+fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let timeout = Duration::from_secs(SOCKS_CONNECT_TIMEOUT);
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "host resolves to nothing");
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e
+        }
+    }
+    Err(last)
+}
+
+fn reply<S: Write>(stream: &mut S, code: u8, bound: Option<SocketAddr>) -> io::Result<()> {
+    let bound = bound.unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0));
+    let mut out = vec![SOCKS_VERSION, code, 0];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            out.push(SOCKS_ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            out.push(SOCKS_ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&bound.port().to_be_bytes());
+    stream.write_all(&out)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
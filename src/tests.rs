@@ -2,8 +2,42 @@
 
 pub mod tests {
 
+    use std::cmp;
     use std::mem;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::io::Cursor;
+    use std::io::{Read, Write};
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::net::{SocketAddr, TcpListener, UdpSocket};
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use rust_sodium_sys::*;
+    use rustc_serialize::hex::FromHex;
     use libcurvecp::*;
+    use auth::*;
+    use batch::*;
+    use capture::*;
+    use cli::*;
+    use clock::*;
+    use config::*;
+    use dissect::*;
+    use keys::*;
+    use message::{Eof, Message, MessageTracer, MSG_HEADER_SIZE, MSG_MAX_BLOCK_SIZE};
+    use pcap::*;
+    use proptest::prelude::{any, prop, Just, Strategy};
+    use mux::*;
+    use relay::*;
+    use rng::*;
+    use router::*;
+    use session::*;
+    use sim::*;
+    use socks::*;
+    use tunnel::*;
 
     const SECRETKEY:[u8; 32] = [
         0x70, 0x2d, 0x76, 0x4d, 0xe0, 0x54, 0x7c, 0x94,
@@ -11,12 +45,12 @@ pub mod tests {
         0x80, 0x08, 0x08, 0xd9, 0x1f, 0xdf, 0x70, 0xf6,
         0xe4, 0x37, 0x7b, 0x13, 0x7d, 0x0c, 0x13, 0x8d
     ];
-    const PUBLICKEY:[u8; 32] = [
+    const PUBLICKEY:PublicKey = PublicKey([
         0x0a, 0x02, 0x94, 0xb7, 0x69, 0x86, 0x30, 0x42,
         0x28, 0xa3, 0x34, 0x11, 0x23, 0x92, 0x70, 0x95,
         0x88, 0xf2, 0xe0, 0x04, 0xf3, 0xd8, 0xe0, 0xdd,
         0x13, 0x9b, 0x90, 0x95, 0x96, 0xe4, 0xf9, 0x48
-    ];
+    ]);
     const SERVER_EXT:[u8; 16] = [
         0x31, 0x41, 0x59, 0x26, 0x53, 0x58, 0x97, 0x93,
         0x23, 0x84, 0x62, 0x64, 0x33, 0x83, 0x27, 0x95
@@ -27,12 +61,14 @@ pub mod tests {
     fn test_client_hello() {
         let mut ctx: CCPContext = CCPContext::new();
         let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
-        let mut ret = ctx.mk_client_hello(&mut buf,
-                                          PUBLICKEY, SECRETKEY,
-                                          PUBLICKEY,
-                                          [0; 16], SERVER_EXT);
+        let client = ClientConfig::new(PUBLICKEY)
+            .client_keypair(PUBLICKEY, SecretKey::new(SECRETKEY))
+            .server_ext(SERVER_EXT);
+        let server = ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY))
+            .server_ext(SERVER_EXT);
+        let mut ret = ctx.mk_client_hello(&mut buf, &client);
         if ret > 0 {
-            ret = ctx.parse_client_hello(&buf, ret as usize, PUBLICKEY, SECRETKEY, SERVER_EXT);
+            ret = ctx.parse_client_hello(&buf, ret as usize, &server);
         }
         assert!(ret == 224)
     }
@@ -52,6 +88,17 @@ pub mod tests {
         assert!(ret == 544 + msg.len() as isize);
     }
 
+    #[test]
+    fn test_max_client_initiate() {
+        let mut ctx: CCPContext = CCPContext::new();
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        let msg = [7; CCP_MAX_CLIENT_INIT_PAYLOAD_SIZE];
+        let ret = ctx.mk_client_initiate(&mut buf, SERVER_NAME, &msg);
+        assert_eq!(ret, CCP_MAX_PACKET_SIZE as isize);
+        assert_eq!(ctx.parse_client_initiate(&buf, ret as usize), ret);
+        assert_eq!(ctx.message(), &msg[..]);
+    }
+
     #[test]
     fn test_client_message() {
         let mut ctx: CCPContext = CCPContext::new();
@@ -90,4 +137,1819 @@ pub mod tests {
         }
         assert!(ret == 64 + msg.len() as isize);
     }
+
+    struct Pipe<'a> {
+        rx: &'a mut VecDeque<u8>,
+        tx: &'a mut VecDeque<u8>
+    }
+
+    impl<'a> Read for Pipe<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.rx.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "empty"));
+            }
+            let n = ::std::cmp::min(buf.len(), self.rx.len());
+            for (i, b) in self.rx.drain(..n).enumerate() {
+                buf[i] = b;
+            }
+            Ok(n)
+        }
+    }
+
+    impl<'a> Write for Pipe<'a> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.tx.extend(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn read_all(mux: &mut Mux, id: StreamId, out: &mut Vec<u8>) -> bool {
+        let mut buf = [0; 1000];
+        loop {
+            match mux.read(id, &mut buf) {
+                Ok(0) => return true,
+                Ok(n) => out.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+                Err(e) => panic!("{}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn test_mux_streams() {
+        let mut client = Mux::new(true, &Limits::default());
+        let mut server = Mux::new(false, &Limits::default());
+        let mut c2s = VecDeque::new();
+        let mut s2c = VecDeque::new();
+
+        let data: Vec<u8> = (0..200000).map(|i| (i % 251) as u8).collect();
+        let a = client.open_stream().unwrap();
+        let b = client.open_stream().unwrap();
+        assert!(a != b);
+
+        let mut written = 0;
+        let mut received_a = vec![];
+        let mut received_b = vec![];
+        let mut accepted = vec![];
+        let mut done = false;
+        for _ in 0..1000 {
+            if written < data.len() {
+                written += client.write(a, &data[written..]).unwrap_or(0);
+                if written == data.len() {
+                    client.write(b, b"short").unwrap();
+                    client.close(a).unwrap();
+                    client.close(b).unwrap();
+                }
+            }
+            client.drive(&mut Pipe { rx: &mut s2c, tx: &mut c2s }).unwrap();
+            server.drive(&mut Pipe { rx: &mut c2s, tx: &mut s2c }).unwrap();
+            while let Some(id) = server.accept_stream() {
+                accepted.push(id);
+            }
+            // stream b is never read until a is done, a must not be blocked by it
+            let eof_a = !accepted.is_empty() && read_all(&mut server, accepted[0], &mut received_a);
+            if eof_a {
+                done = read_all(&mut server, accepted[1], &mut received_b);
+                if done {
+                    break;
+                }
+            }
+        }
+        assert!(done);
+        assert_eq!(accepted, vec![a, b]);
+        assert!(received_a == data);
+        assert_eq!(received_b, b"short".to_vec());
+    }
+
+    #[test]
+    fn test_session_mux() {
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let clientconfig = ClientConfig::new(PUBLICKEY)
+            .server_ext(SERVER_EXT)
+            .server_name(SERVER_NAME);
+        let clientlongtermpk = clientconfig.clientlongtermpk;
+        let mut client = ClientSession::new(clientconfig);
+        let mut server = Server::new(ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY))
+                                     .server_ext(SERVER_EXT)
+                                     .accept_name(SERVER_NAME));
+        let mut cmux = Mux::new(true, &Limits::default());
+        let mut smux = Mux::new(false, &Limits::default());
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+
+        let id = cmux.open_stream().unwrap();
+        cmux.write(id, b"hello over a stream").unwrap();
+        cmux.close(id).unwrap();
+
+        let mut session = None;
+        let mut stream = None;
+        let mut received = vec![];
+        let mut done = false;
+        for _ in 0..100 {
+            cmux.drive(&mut client).unwrap();
+            while let Some(n) = client.poll_transmit(&mut buf) {
+                assert!(server.handle_datagram(&buf, n, addr) > 0);
+            }
+            if session.is_none() {
+                session = server.accept();
+            }
+            if let Some(sid) = session {
+                smux.drive(server.session(&sid).unwrap()).unwrap();
+                if stream.is_none() {
+                    stream = smux.accept_stream();
+                }
+                if let Some(st) = stream {
+                    done = read_all(&mut smux, st, &mut received);
+                }
+            }
+            while let Some((n, to)) = server.poll_transmit(&mut buf) {
+                assert_eq!(to, addr);
+                assert!(client.handle_datagram(&buf, n) > 0);
+            }
+            if done {
+                break;
+            }
+        }
+        assert!(done);
+        assert!(client.is_established());
+        assert_eq!(received, b"hello over a stream".to_vec());
+        assert!(session.is_some());
+        assert!(server.session(&session.unwrap()).unwrap().clientlongtermpk() == clientlongtermpk);
+    }
+
+    #[test]
+    fn test_server_name() {
+        let mut ctx: CCPContext = CCPContext::new();
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        let msg = String::from("TESTTESTTESTTEST").into_bytes();
+        let ret = ctx.mk_client_initiate(&mut buf, SERVER_NAME, &msg);
+        assert!(ctx.parse_client_initiate(&buf, ret as usize) > 0);
+        assert_eq!(ctx.servername(), Some(String::from(SERVER_NAME)));
+
+        let config = ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).accept_name("Machine.Example.com");
+        assert!(config.accepts_name(SERVER_NAME));
+        assert!(!config.accepts_name("other.example.com"));
+        assert!(ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).accepts_name("other.example.com"));
+    }
+
+    fn connect(server: &mut Server, addr: SocketAddr) -> (ClientSession, SessionId) {
+        let mut client = ClientSession::new(ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT));
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        client.write_all(b"ping").unwrap();
+        for _ in 0..2 {
+            let n = client.poll_transmit(&mut buf).unwrap();
+            assert!(server.handle_datagram(&buf, n, addr) > 0);
+            if let Some((n, _)) = server.poll_transmit(&mut buf) {
+                assert!(client.handle_datagram(&buf, n) > 0);
+            }
+        }
+        (client, server.accept().unwrap())
+    }
+
+    #[test]
+    fn test_replay() {
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let elsewhere: SocketAddr = "10.0.0.1:4444".parse().unwrap();
+        let mut server = Server::new(ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT));
+        let mut client = ClientSession::new(ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT));
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        client.write_all(b"ping").unwrap();
+        let n = client.poll_transmit(&mut buf).unwrap();
+        assert!(server.handle_datagram(&buf, n, addr) > 0);
+        let (n, _) = server.poll_transmit(&mut buf).unwrap();
+        assert!(client.handle_datagram(&buf, n) > 0);
+        let n = client.poll_transmit(&mut buf).unwrap();
+        let initiate = buf;
+        assert!(server.handle_datagram(&initiate, n, addr) > 0);
+        let id = server.accept().unwrap();
+
+        // a captured Initiate or Message sent again from elsewhere is dropped
+        assert_eq!(server.handle_datagram(&initiate, n, elsewhere), -6);
+        client.write_all(b"more").unwrap();
+        server.session(&id).unwrap().write_all(b"pong").unwrap();
+        let (n, _) = server.poll_transmit(&mut buf).unwrap();
+        let reply = buf;
+        assert!(client.handle_datagram(&reply, n) > 0);
+        assert_eq!(client.handle_datagram(&reply, n), -6);
+        let m = client.poll_transmit(&mut buf).unwrap();
+        assert!(server.handle_datagram(&buf, m, addr) > 0);
+        assert_eq!(server.handle_datagram(&buf, m, elsewhere), -6);
+        assert_eq!(server.session(&id).unwrap().peer_addr(), addr);
+    }
+
+    #[test]
+    fn test_pending_flood() {
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let limits = Limits { max_pending: 4, ..Limits::default() };
+        let mut server = Server::new(ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY))
+                                     .server_ext(SERVER_EXT)
+                                     .limits(limits));
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        let mut first = ClientSession::new(ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT));
+        let n = first.poll_transmit(&mut buf).unwrap();
+        assert!(server.handle_datagram(&buf, n, addr) > 0);
+        let (n, _) = server.poll_transmit(&mut buf).unwrap();
+        assert!(first.handle_datagram(&buf, n) > 0);
+
+        // a table full of Hellos makes room by dropping the oldest handshake
+        for _ in 1..4 {
+            let mut client = ClientSession::new(ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT));
+            let n = client.poll_transmit(&mut buf).unwrap();
+            assert!(server.handle_datagram(&buf, n, addr) > 0);
+        }
+        while server.poll_transmit(&mut buf).is_some() {}
+        connect(&mut server, addr);
+        let n = first.poll_transmit(&mut buf).unwrap();
+        assert_eq!(server.handle_datagram(&buf, n, addr), -4);
+    }
+
+    #[test]
+    fn test_server_turns() {
+        let addrs: [SocketAddr; 2] = ["127.0.0.1:1111".parse().unwrap(), "127.0.0.1:2222".parse().unwrap()];
+        let mut server = Server::new(ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT));
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        let (_a, a) = connect(&mut server, addrs[0]);
+        let (_b, b) = connect(&mut server, addrs[1]);
+
+        // both have plenty to send, the packets alternate between them
+        for id in &[a, b] {
+            server.session(id).unwrap().write_all(&[7; 20000]).unwrap();
+        }
+        let to: Vec<SocketAddr> = (0..8).map(|_| server.poll_transmit(&mut buf).unwrap().1).collect();
+        assert!(to.windows(2).all(|w| w[0] != w[1]), "{:?}", to);
+
+        // and so do they in a batch
+        let mut batch = Batch::new();
+        assert!(server.poll_transmit_batch(&mut batch) >= 4);
+        let to: Vec<SocketAddr> = (0..batch.len()).map(|i| batch.get(i).2).collect();
+        assert!(to.windows(2).all(|w| w[0] != w[1]), "{:?}", to);
+    }
+
+    /*
+     * Run Hello/Cookie/Initiate, return the server's answer to Initiate
+     */
+    fn handshake(client: &mut ClientSession, server: &mut Server) -> isize {
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        let n = client.poll_transmit(&mut buf).unwrap();
+        assert!(server.handle_datagram(&buf, n, addr) > 0);
+        let (n, _) = server.poll_transmit(&mut buf).unwrap();
+        assert!(client.handle_datagram(&buf, n) > 0);
+        let n = client.poll_transmit(&mut buf).unwrap();
+        server.handle_datagram(&buf, n, addr)
+    }
+
+    #[test]
+    fn test_authorizer() {
+        let allowed = ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT);
+        let allowedpk = allowed.clientlongtermpk;
+        let authorizer = move |pk: &PublicKey, name: &str, _addr: &SocketAddr| {
+            *pk == allowedpk && name == SERVER_NAME
+        };
+        let config = ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY))
+            .server_ext(SERVER_EXT)
+            .authorizer(Arc::new(authorizer));
+
+        let mut server = Server::new(config.clone());
+        let mut client = ClientSession::new(allowed.clone().server_name(SERVER_NAME));
+        assert!(handshake(&mut client, &mut server) > 0);
+        assert!(server.accept().is_some());
+
+        let mut server = Server::new(config.clone());
+        let mut client = ClientSession::new(allowed.server_name("other.example.com"));
+        assert_eq!(handshake(&mut client, &mut server), -5);
+        assert!(server.accept().is_none());
+
+        let mut server = Server::new(config);
+        let mut client = ClientSession::new(ClientConfig::new(PUBLICKEY)
+                                            .server_ext(SERVER_EXT)
+                                            .server_name(SERVER_NAME));
+        assert_eq!(handshake(&mut client, &mut server), -5);
+        assert!(server.accept().is_none());
+    }
+
+    #[test]
+    fn test_forged_vouch() {
+        // claims PUBLICKEY as its identity without knowing SECRETKEY
+        let config = ClientConfig::new(PUBLICKEY)
+            .client_keypair(PUBLICKEY, SecretKey::new([1; 32]))
+            .server_ext(SERVER_EXT);
+        let mut server = Server::new(ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT));
+        let mut client = ClientSession::new(config);
+        assert_eq!(handshake(&mut client, &mut server), -3);
+        assert!(server.accept().is_none());
+    }
+
+    /*
+     * Box m the way it goes on the wire: authenticator, then ciphertext
+     */
+    fn seal(m: &[u8], prefix: &[u8], n: &[u8], pk: &[u8; 32], sk: &[u8; 32]) -> Vec<u8> {
+        let nonce = [prefix, n].concat();
+        let mut c = vec![0; 16 + m.len()];
+        unsafe {
+            assert_eq!(crypto_box_easy(c.as_mut_ptr(), m.as_ptr(), m.len() as u64,
+                                       nonce.as_ptr(), pk.as_ptr(), sk.as_ptr()), 0);
+        }
+        c
+    }
+
+    #[test]
+    fn test_reinitiate() {
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        let authorizer = |pk: &PublicKey, _name: &str, _addr: &SocketAddr| *pk == PUBLICKEY;
+        let mut server = Server::new(ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY))
+                                     .server_ext(SERVER_EXT)
+                                     .authorizer(Arc::new(authorizer)));
+
+        // a client done by hand, so one short-term key can claim any identity
+        let (mut shortpk, mut shortsk) = ([0; 32], [0; 32]);
+        let (mut otherpk, mut othersk) = ([0; 32], [0; 32]);
+        unsafe {
+            crypto_box_keypair(shortpk.as_mut_ptr(), shortsk.as_mut_ptr());
+            crypto_box_keypair(otherpk.as_mut_ptr(), othersk.as_mut_ptr());
+        }
+        let header = |signature: &[u8]| [signature, &SERVER_EXT, &[0; 16], &shortpk].concat();
+        let hello = [header(b"QvnQ5XlH"), vec![0; 64], 1u64.to_le_bytes().to_vec(),
+                     seal(&[0; 64], b"CurveCP-client-H", &1u64.to_le_bytes(), &PUBLICKEY.0, &shortsk)].concat();
+        buf[..224].copy_from_slice(&hello);
+        assert!(server.handle_datagram(&buf, 224, addr) > 0);
+        let (n, _) = server.poll_transmit(&mut buf).unwrap();
+        assert_eq!(n, 200);
+        let mut cookie = [0; 128];
+        let nonce = [&b"CurveCPK"[..], &buf[40..56]].concat();
+        unsafe {
+            assert_eq!(crypto_box_open_easy(cookie.as_mut_ptr(), buf[56..].as_ptr(), 144,
+                                            nonce.as_ptr(), PUBLICKEY.0.as_ptr(), shortsk.as_ptr()), 0);
+        }
+        let servershortpk = *array_ref![cookie, 0, 32];
+        let initiate = |buf: &mut [u8; CCP_MAX_PACKET_SIZE], pk: &[u8; 32], sk: &[u8; 32], nonce: u64| {
+            let vouchnonce = [nonce as u8; 16];
+            let mut name = nameparse(SERVER_NAME);
+            name.resize(256, 0);
+            let mut msg = [0; 64];
+            let m = Message { id: nonce as u32, acked_id: 0, acked_first: 0, acked_ranges: [(0, 0); 5],
+                              eof: Eof::None, offset: 0, data: b"hello" };
+            let len = m.encode(&mut msg) as usize;
+            let text = [&pk[..], &vouchnonce, &seal(&shortpk, b"CurveCPV", &vouchnonce, &PUBLICKEY.0, sk),
+                        &name, &msg[..len]].concat();
+            let packet = [header(b"QvnQ5XlI"), cookie[32..].to_vec(), nonce.to_le_bytes().to_vec(),
+                          seal(&text, b"CurveCP-client-I", &nonce.to_le_bytes(), &servershortpk, &shortsk)].concat();
+            buf[..packet.len()].copy_from_slice(&packet);
+            packet.len()
+        };
+
+        let n = initiate(&mut buf, &PUBLICKEY.0, &SECRETKEY, 2);
+        assert!(server.handle_datagram(&buf, n, addr) > 0);
+        let id = server.accept().unwrap();
+        let n = initiate(&mut buf, &PUBLICKEY.0, &SECRETKEY, 3);
+        assert!(server.handle_datagram(&buf, n, addr) > 0);
+
+        // once authorized, a later Initiate can't switch identity or drop the vouch
+        let n = initiate(&mut buf, &otherpk, &othersk, 4);
+        assert_eq!(server.handle_datagram(&buf, n, addr), -3);
+        let n = initiate(&mut buf, &PUBLICKEY.0, &othersk, 5);
+        assert_eq!(server.handle_datagram(&buf, n, addr), -3);
+        assert_eq!(server.session(&id).unwrap().clientlongtermpk(), PUBLICKEY);
+    }
+
+    #[test]
+    fn test_keys() {
+        let sk = SecretKey::new(SECRETKEY);
+        assert_eq!(sk.public_key(), PUBLICKEY);
+        assert_eq!(format!("{:?}", sk), "SecretKey(<redacted>)");
+        assert!(format!("{:?}", PUBLICKEY).starts_with("PublicKey(0a0294b7"));
+
+        let (pk, other) = keypair();
+        let shared = SharedKey::precompute(&pk, &sk);
+        assert_eq!(shared.as_bytes(), SharedKey::precompute(&PUBLICKEY, &other).as_bytes());
+        assert_eq!(format!("{:?}", shared), "SharedKey(<redacted>)");
+
+        assert_eq!(base32(&[]), "");
+        assert_eq!(base32(&[0xff, 0x01]), "zh00");
+        assert_eq!(base32(&PUBLICKEY.0).len(), 52);
+    }
+
+    #[test]
+    fn test_keydir() {
+        let path = env::temp_dir().join(format!("curvecp-keydir-{}", ::std::process::id()));
+        let created = KeyDir::create(&path).unwrap();
+        let loaded = KeyDir::load(&path).unwrap();
+        assert_eq!(loaded.publickey, created.publickey);
+        assert_eq!(loaded.secretkey.as_bytes(), created.secretkey.as_bytes());
+        assert_eq!(KeyDir::load_public(&path).unwrap(), created.publickey);
+        assert_eq!(fs::read(path.join(".expertsonly/lock")).unwrap(), vec![0]);
+        assert_eq!(fs::read(path.join(".expertsonly/noncecounter")).unwrap(), vec![0; 8]);
+        assert_eq!(fs::read(path.join(".expertsonly/noncekey")).unwrap().len(), 32);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |p: &str| fs::metadata(path.join(p)).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode("publickey"), 0o644);
+            assert_eq!(mode(".expertsonly"), 0o700);
+            assert_eq!(mode(".expertsonly/secretkey"), 0o600);
+        }
+        assert!(KeyDir::create(&path).is_err());
+        fs::remove_dir_all(&path).unwrap();
+
+        KeyDir::save(&path, &PUBLICKEY, &SecretKey::new(SECRETKEY)).unwrap();
+        assert_eq!(fs::read(path.join("publickey")).unwrap(), PUBLICKEY.0.to_vec());
+        assert_eq!(KeyDir::load(&path).unwrap().publickey, PUBLICKEY);
+        fs::write(path.join("publickey"), [7; 32]).unwrap();
+        assert!(KeyDir::load(&path).is_err());
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    struct Shared(Arc<::std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_relay() {
+        let input: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let output = Arc::new(::std::sync::Mutex::new(vec![]));
+        let mut relay = Relay::new(io::Cursor::new(input.clone()), Shared(output.clone()));
+
+        // session side: what the relay writes lands in tx, rx is what it reads
+        let mut tx = VecDeque::new();
+        let mut rx: VecDeque<u8> = (0..30000).map(|i| (i * 7) as u8).collect();
+        let expected: Vec<u8> = rx.iter().cloned().collect();
+        for _ in 0..1000 {
+            relay.pump(&mut Pipe { rx: &mut rx, tx: &mut tx }).unwrap();
+            if relay.input_done() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(relay.input_done());
+        assert_eq!(tx.into_iter().collect::<Vec<u8>>(), input);
+        assert!(!relay.output_done());
+
+        // the session's data ended: the writer gets everything, then is closed
+        relay.pump(&mut io::Cursor::new(vec![])).unwrap();
+        assert!(relay.output_done());
+        for _ in 0..1000 {
+            if output.lock().unwrap().len() == expected.len() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(*output.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut settings = Settings::from_toml(r#"
+            addr = "127.0.0.1:12345"
+            peerkey = "0a0294b76986304228a334112392709588f2e004f3d8e0dd139b909596e4f948"
+            name = "machine.example.com"
+            serverext = "31415926535897932384626433832795"
+        "#).unwrap();
+        assert_eq!(settings.addr, Some("127.0.0.1:12345".parse().unwrap()));
+        assert_eq!(settings.peerkey, Some(PUBLICKEY));
+        assert_eq!(settings.serverext, Some(SERVER_EXT));
+        assert!(settings.server_config().is_err());
+
+        let config = settings.client_config().unwrap();
+        assert_eq!(config.serverlongtermpk, PUBLICKEY);
+        assert_eq!(config.servername, SERVER_NAME);
+        assert!(config.clientext != [0; 16]);
+        settings.set("clientext", "000102030405060708090a0b0c0d0e0f").unwrap();
+        assert_eq!(settings.client_config().unwrap().clientext[15], 15);
+
+        assert!(settings.server_config_with(PUBLICKEY, SecretKey::new(SECRETKEY)).authorizer.is_none());
+        settings.set("allow", "clients").unwrap();
+        settings.set("target", "127.0.0.1:80").unwrap();
+        assert!(settings.server_config_with(PUBLICKEY, SecretKey::new(SECRETKEY)).authorizer.is_some());
+        assert_eq!(settings.target, Some("127.0.0.1:80".parse().unwrap()));
+        assert!(settings.local.is_none());
+
+        assert!(settings.set("serverext", "3141").is_err());
+        assert!(Settings::from_toml("adress = \"127.0.0.1:1\"").is_err());
+        assert!(Settings::from_toml("addr = 12345").is_err());
+        assert!(Settings::default().client_config().is_err());
+    }
+
+    #[test]
+    fn test_tracer() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let clientlog = log.clone();
+        let serverlog = log.clone();
+        let client_config = ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT)
+            .tracer(Arc::new(move |sent: bool, m: &[u8]| clientlog.lock().unwrap().push(("client", sent, m.to_vec()))));
+        let server_config = ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT)
+            .tracer(Arc::new(move |sent: bool, m: &[u8]| serverlog.lock().unwrap().push(("server", sent, m.to_vec()))));
+        let mut client = ClientSession::new(client_config);
+        let mut server = Server::new(server_config);
+        client.write_all(b"ping").unwrap();
+        assert!(handshake(&mut client, &mut server) > 0);
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].0, "client");
+        assert!(log[0].1);
+        assert_eq!(log[1].0, "server");
+        assert!(!log[1].1);
+        assert_eq!(log[0].2, log[1].2);
+        assert_eq!(Message::decode(&log[1].2).unwrap().data, b"ping");
+
+        let dump = hexdump(b"0123456789abcdef\x00\xff");
+        assert_eq!(dump, "00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+                          00000010  00 ff                                             |..|\n");
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let path = env::temp_dir().join(format!("curvecp-allow-{}", ::std::process::id()));
+        let list = AllowList::new(&path);
+        let other = PublicKey([7; 32]);
+        assert!(!list.contains(&PUBLICKEY));
+
+        let hex: String = PUBLICKEY.0.iter().map(|b| format!("{:02x}", b)).collect();
+        fs::write(&path, format!("# allowed clients\n{} # test key\n", hex)).unwrap();
+        assert!(list.contains(&PUBLICKEY));
+        assert!(!list.contains(&other));
+
+        thread::sleep(Duration::from_millis(20));
+        let hex: String = other.0.iter().map(|b| format!("{:02x}", b)).collect();
+        fs::write(&path, hex).unwrap();
+        assert!(!list.contains(&PUBLICKEY));
+        assert!(list.contains(&other));
+
+        fs::remove_file(&path).unwrap();
+        assert!(!list.contains(&other));
+
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("client"), &PUBLICKEY.0[..]).unwrap();
+        assert!(list.contains(&PUBLICKEY));
+
+        // revoking a key by rewriting its file in place
+        thread::sleep(Duration::from_millis(20));
+        fs::OpenOptions::new().write(true).open(path.join("client")).unwrap().write_all(&other.0).unwrap();
+        assert!(!list.contains(&PUBLICKEY));
+        assert!(list.contains(&other));
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    struct Echo {
+        name: &'static str,
+        log: Rc<RefCell<Vec<(&'static str, PublicKey)>>>
+    }
+
+    impl Handler for Echo {
+        fn accept(&mut self, _id: &SessionId, session: &mut ServerSession) {
+            self.log.borrow_mut().push((self.name, session.clientlongtermpk()));
+        }
+
+        fn ready(&mut self, _id: &SessionId, session: &mut ServerSession) {
+            let mut buf = [0; 100];
+            while let Ok(n) = session.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                session.write_all(&buf[..n]).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_router() {
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let otherext = [9; 16];
+        let (otherpk, othersk) = keypair();
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut router = Router::new();
+        assert!(router.add(ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT),
+                           Box::new(Echo { name: "first", log: log.clone() })));
+        assert!(router.add(ServerConfig::new(otherpk, othersk.clone()).server_ext(otherext),
+                           Box::new(Echo { name: "second", log: log.clone() })));
+        assert!(!router.add(ServerConfig::new(otherpk, othersk).server_ext(otherext),
+                            Box::new(Echo { name: "third", log: log.clone() })));
+
+        let first = ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT);
+        let second = ClientConfig::new(otherpk).server_ext(otherext);
+        let wrongkey = ClientConfig::new(PUBLICKEY).server_ext(otherext);
+        let unknown = ClientConfig::new(PUBLICKEY).server_ext([1; 16]);
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+
+        for &(config, expected) in [(&first, 1), (&second, 1), (&wrongkey, -3), (&unknown, -2)].iter() {
+            let mut client = ClientSession::new(config.clone());
+            client.write_all(b"0123456789").unwrap();
+            let mut received = vec![];
+            for _ in 0..10 {
+                while let Some(n) = client.poll_transmit(&mut buf) {
+                    let ret = router.handle_datagram(&buf, n, addr);
+                    assert!((expected > 0 && ret > 0) || ret == expected);
+                }
+                while let Some((n, _)) = router.poll_transmit(&mut buf) {
+                    assert!(client.handle_datagram(&buf, n) > 0);
+                }
+                let mut data = [0; 100];
+                if let Ok(n) = client.read(&mut data) {
+                    received.extend_from_slice(&data[..n]);
+                }
+            }
+            if expected > 0 {
+                assert_eq!(received, b"0123456789".to_vec());
+            } else {
+                assert!(!client.is_established());
+            }
+        }
+
+        assert_eq!(*log.borrow(), vec![("first", first.clientlongtermpk),
+                                       ("second", second.clientlongtermpk)]);
+    }
+
+    #[test]
+    fn test_client_demux() {
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let clientaddr: SocketAddr = "127.0.0.1:23456".parse().unwrap();
+        let mut client = Client::new();
+        let mut server = Server::new(ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT));
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+
+        let config = ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT);
+        let fixed = client.connect(config.clone().client_ext([5; 16]), addr);
+        let random = client.connect(config.clone(), addr);
+        let clash = client.connect(config.client_ext([5; 16]), addr);
+        assert_eq!(fixed, [5; 16]);
+        assert!(random != [0; 16] && clash != fixed && clash != random);
+
+        for ext in client.clientexts() {
+            client.session(&ext).unwrap().write_all(&ext).unwrap();
+        }
+        for _ in 0..10 {
+            while let Some((n, to)) = client.poll_transmit(&mut buf) {
+                assert_eq!(to, addr);
+                assert!(server.handle_datagram(&buf, n, clientaddr) > 0);
+            }
+            while let Some(id) = server.accept() {
+                let session = server.session(&id).unwrap();
+                let ext = session.clientext();
+                session.write_all(&ext).unwrap();
+            }
+            while let Some((n, to)) = server.poll_transmit(&mut buf) {
+                assert_eq!(to, clientaddr);
+                // no session has these client and server extensions
+                for &i in &[8, 24] {
+                    let mut other = buf;
+                    other[i] ^= 1;
+                    assert_eq!(client.handle_datagram(&other, n), -2);
+                }
+                assert!(client.handle_datagram(&buf, n) > 0);
+            }
+        }
+
+        for ext in client.clientexts() {
+            let mut data = [0; 32];
+            assert_eq!(client.session(&ext).unwrap().read(&mut data).unwrap(), 16);
+            assert_eq!(data[..16], ext[..]);
+        }
+        assert_eq!(server.session_ids().len(), 3);
+    }
+
+    #[test]
+    fn test_connect_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut relay = connect_relay(listener.local_addr().unwrap());
+        let mut rx: VecDeque<u8> = b"ping".iter().cloned().collect();
+        let mut tx = VecDeque::new();
+        relay.pump(&mut Pipe { rx: &mut rx, tx: &mut tx }).unwrap();
+        let (mut target, _) = listener.accept().unwrap();
+        let mut ping = [0; 4];
+        target.read_exact(&mut ping).unwrap();
+        assert_eq!(&ping, b"ping");
+        target.write_all(b"pong").unwrap();
+        drop(target);
+        for _ in 0..1000 {
+            relay.pump(&mut Pipe { rx: &mut rx, tx: &mut tx }).unwrap();
+            if relay.input_done() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(relay.input_done());
+        assert_eq!(tx.into_iter().collect::<Vec<u8>>(), b"pong");
+
+        // nobody listening: pump() returns at once and the input just ends
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+        let mut relay = connect_relay(closed);
+        let mut tx = VecDeque::new();
+        for _ in 0..1000 {
+            relay.pump(&mut Pipe { rx: &mut rx, tx: &mut tx }).unwrap();
+            if relay.input_done() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(relay.input_done());
+        assert!(tx.is_empty());
+    }
+
+    struct Socks {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>
+    }
+
+    impl Read for Socks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Socks {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn socks(input: Vec<u8>) -> (io::Result<::std::net::TcpStream>, Vec<u8>) {
+        let mut stream = Socks { input: Cursor::new(input), output: vec![] };
+        let ret = negotiate(&mut stream);
+        (ret, stream.output)
+    }
+
+    #[test]
+    fn test_socks() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_be_bytes();
+
+        let mut request = vec![5, 2, 2, 0, 5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&port);
+        let (ret, output) = socks(request);
+        let target = ret.unwrap();
+        assert_eq!(target.peer_addr().unwrap(), listener.local_addr().unwrap());
+        assert_eq!(output[..6], [5, 0, 5, 0, 0, 1]);
+        assert_eq!(output.len(), 12);
+
+        let mut request = vec![5, 1, 0, 5, 1, 0, 3, 9];
+        request.extend_from_slice(b"localhost");
+        request.extend_from_slice(&port);
+        assert!(socks(request).0.is_ok());
+
+        // BIND, then no acceptable method, then not SOCKS5 at all
+        let mut request = vec![5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&port);
+        let (ret, output) = socks(request);
+        assert!(ret.is_err());
+        assert_eq!(output[..4], [5, 0, 5, 7]);
+        let (ret, output) = socks(vec![5, 1, 2]);
+        assert!(ret.is_err());
+        assert_eq!(output, [5, 0xff]);
+        assert!(socks(vec![4, 1, 0]).0.is_err());
+    }
+
+    #[test]
+    fn test_dissect() {
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        let mut client = ClientSession::new(ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT));
+        let mut server = Server::new(ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT));
+        client.write_all(b"ping").unwrap();
+        let n = client.poll_transmit(&mut buf).unwrap();
+        let hello = buf[..n].to_vec();
+        server.handle_datagram(&buf, n, addr);
+        let (n, _) = server.poll_transmit(&mut buf).unwrap();
+        let cookie = buf[..n].to_vec();
+        client.handle_datagram(&buf, n);
+        let n = client.poll_transmit(&mut buf).unwrap();
+        let initiate = buf[..n].to_vec();
+
+        let d = dissect(&hello);
+        assert_eq!(d.kind, Some(PacketKind::ClientHello));
+        assert!(d.problems.is_empty() && !d.decrypted());
+        assert_eq!(d.bytes("serverext"), Some(&SERVER_EXT[..]));
+        assert_eq!(d.field("signature"), Some(&Value::Text(String::from("QvnQ5XlH"))));
+        let clientshorttermpk = PublicKey(*array_ref![d.bytes("clientshorttermpk").unwrap(), 0, 32]);
+        assert_eq!(dissect(&initiate).kind, Some(PacketKind::ClientInitiate));
+        assert!(dissect(&initiate).problems.is_empty());
+
+        let keys = Keys {
+            serverlongtermsk: Some(SecretKey::new(SECRETKEY)),
+            clientshorttermpk: Some(clientshorttermpk),
+            ..Keys::default()
+        };
+        let d = dissect_with(&hello, &keys);
+        assert!(d.problems.is_empty() && d.decrypted());
+        let d = dissect_with(&cookie, &keys);
+        assert!(d.problems.is_empty());
+        assert_eq!(d.bytes("servershorttermpk").unwrap().len(), 32);
+
+        let mut forged = hello.clone();
+        forged[200] ^= 1;
+        assert_eq!(dissect_with(&forged, &keys).problems, ["box does not open"]);
+        assert!(!dissect(&hello[..100]).problems.is_empty());
+        assert!(!dissect(&initiate[..initiate.len() - 8]).problems.is_empty());
+        assert_eq!(dissect(b"QvnQ5Xl").problems, ["too short for a signature"]);
+        assert_eq!(dissect(&[0; 64]).problems, ["unknown signature"]);
+
+        // a client Message made by hand, opened with the server short-term key
+        let (cpk, csk) = keypair();
+        let (spk, ssk) = keypair();
+        let mut msg = [0; 64];
+        let m = Message { id: 7, acked_id: 0, acked_first: 0, acked_ranges: [(0, 0); 5], eof: Eof::Success, offset: 3, data: b"hi" };
+        let size = m.encode(&mut msg) as usize;
+        let mut text = vec![0; 32 + size];
+        text[32..].copy_from_slice(&msg[..size]);
+        let mut nonce = *b"CurveCP-client-M\x05\0\0\0\0\0\0\0";
+        unsafe {
+            let key = SharedKey::precompute(&spk, &csk);
+            ::rust_sodium_sys::crypto_box_afternm(text.as_mut_ptr(), text.as_ptr(), text.len() as u64,
+                                                  &nonce[0], &key.as_bytes()[0]);
+        }
+        let mut packet = b"QvnQ5XlM".to_vec();
+        packet.extend_from_slice(&SERVER_EXT);
+        packet.extend_from_slice(&[1; 16]);
+        packet.extend_from_slice(&cpk.0);
+        packet.extend_from_slice(&nonce[16..]);
+        packet.extend_from_slice(&text[16..]);
+        let keys = Keys { servershorttermsk: Some(ssk), ..Keys::default() };
+        let d = dissect_with(&packet, &keys);
+        assert!(d.problems.is_empty());
+        assert_eq!(d.field("noncecounter"), Some(&Value::Number(5)));
+        assert_eq!(d.field("id"), Some(&Value::Number(7)));
+        assert_eq!(d.field("eof"), Some(&Value::Text(String::from("success"))));
+        assert_eq!(d.bytes("data"), Some(&b"hi"[..]));
+        nonce[16] = 6;
+        packet[72] = nonce[16];
+        assert_eq!(dissect_with(&packet, &keys).problems, ["box does not open"]);
+    }
+
+    struct KeyLog(Mutex<Vec<SecretKey>>);
+
+    impl MessageTracer for KeyLog {
+        fn trace(&self, _sent: bool, _message: &[u8]) {}
+
+        fn shortterm_key(&self, sk: &SecretKey) {
+            self.0.lock().unwrap().push(sk.clone());
+        }
+    }
+
+    #[test]
+    fn test_capture() {
+        let clientaddr: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let serveraddr: SocketAddr = "[::1]:12345".parse().unwrap();
+        let keylog = Arc::new(KeyLog(Mutex::new(vec![])));
+        let (clientpk, clientsk) = keypair();
+        let mut client = ClientSession::new(ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT)
+                                            .client_keypair(clientpk, clientsk).tracer(keylog.clone()));
+        let mut server = Server::new(ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT));
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        let mut datagrams = vec![];
+        let mut time = Duration::from_secs(1500000000);
+
+        client.write_all(b"hello capture").unwrap();
+        client.close();
+        for _ in 0..10 {
+            while let Some(n) = client.poll_transmit(&mut buf) {
+                time += Duration::from_millis(1);
+                datagrams.push(Datagram { time, src: clientaddr, dst: serveraddr, payload: buf[..n].to_vec() });
+                server.handle_datagram(&buf, n, clientaddr);
+            }
+            while let Some(id) = server.accept() {
+                let session = server.session(&id).unwrap();
+                session.write_all(b"hello back").unwrap();
+                session.close();
+            }
+            while let Some((n, _)) = server.poll_transmit(&mut buf) {
+                time += Duration::from_millis(1);
+                datagrams.push(Datagram { time, src: serveraddr, dst: clientaddr, payload: buf[..n].to_vec() });
+                client.handle_datagram(&buf, n);
+            }
+        }
+        datagrams.push(Datagram { time, src: clientaddr, dst: serveraddr, payload: b"not curvecp".to_vec() });
+
+        // IPv4 and IPv6 can't share a pcap record, so give the server a v4 address for the file
+        let v4: SocketAddr = "10.0.0.2:12345".parse().unwrap();
+        let datagrams: Vec<Datagram> = datagrams.into_iter().map(|mut d| {
+            if d.src == serveraddr { d.src = v4; } else { d.dst = v4; }
+            d
+        }).collect();
+        let mut w = PcapWriter::new(vec![]).unwrap();
+        for d in &datagrams {
+            w.write(d).unwrap();
+        }
+        let file = w.into_inner();
+        assert_eq!(read_capture(&file).unwrap(), datagrams);
+        assert!(read_capture(&file[..file.len() - 1]).is_err());
+        assert!(read_capture(b"junk").is_err());
+        assert!(mixed_families());
+
+        // the first record again as pcapng, nanosecond timestamps
+        let frame = &file[40..40 + u32::from_le_bytes(*array_ref![file, 32, 4]) as usize];
+        let mut ng = vec![];
+        ng.extend_from_slice(&[0x0a, 0x0d, 0x0d, 0x0a, 28, 0, 0, 0, 0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0]);
+        ng.extend_from_slice(&[0xff; 8]);
+        ng.extend_from_slice(&[28, 0, 0, 0]);
+        ng.extend_from_slice(&[1, 0, 0, 0, 28, 0, 0, 0, 101, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 0, 9, 0, 0, 0, 28, 0, 0, 0]);
+        let padded = (frame.len() + 3) & !3;
+        let len = (32 + padded) as u32;
+        let ns = datagrams[0].time.as_secs() * 1000000000 + datagrams[0].time.subsec_nanos() as u64;
+        ng.extend_from_slice(&6u32.to_le_bytes());
+        ng.extend_from_slice(&len.to_le_bytes());
+        ng.extend_from_slice(&[0; 4]);
+        ng.extend_from_slice(&((ns >> 32) as u32).to_le_bytes());
+        ng.extend_from_slice(&(ns as u32).to_le_bytes());
+        ng.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        ng.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        ng.extend_from_slice(frame);
+        ng.resize(ng.len() + padded - frame.len(), 0);
+        ng.extend_from_slice(&len.to_le_bytes());
+        assert_eq!(read_capture(&ng).unwrap(), &datagrams[..1]);
+
+        let keys = Keys { serverlongtermsk: Some(SecretKey::new(SECRETKEY)), ..Keys::default() };
+        let capture = analyze(&datagrams, keys.clone(), vec![]);
+        assert_eq!(capture.other, 1);
+        assert_eq!(capture.sessions.len(), 1);
+        assert!(capture.anomalies.is_empty());
+        let session = &capture.sessions[0];
+        assert!(session.established() && session.anomalies.is_empty());
+        assert!(session.servershorttermpk.is_some() && session.transcript.is_empty());
+
+        let shortterm = keylog.0.lock().unwrap().clone();
+        let capture = analyze(&datagrams, keys.clone(), shortterm.clone());
+        let session = &capture.sessions[0];
+        assert_eq!(session.clientlongtermpk, Some(clientpk));
+        assert_eq!(session.stream(true), b"hello capture");
+        assert_eq!(session.stream(false), b"hello back");
+        assert!(session.transcript.iter().any(|e| e.eof == Eof::Success));
+
+        // a replayed client Message and a Message for nobody
+        let mut replayed = datagrams.clone();
+        let last = replayed.iter().rposition(|d| d.payload.starts_with(b"QvnQ5XlM")).unwrap();
+        let again = replayed[last].clone();
+        replayed.push(again);
+        let mut orphan = replayed.iter().find(|d| d.payload.starts_with(b"RL3aNMXM")).unwrap().clone();
+        orphan.dst.set_port(1);
+        replayed.push(orphan);
+        let capture = analyze(&replayed, keys, shortterm);
+        assert_eq!(capture.anomalies.len(), 1);
+        assert!(capture.sessions[0].anomalies.iter().any(|a| a.contains("does not grow")));
+    }
+
+    #[test]
+    fn test_vectors() {
+        let serverlongtermsk = SecretKey::new(vector_key("serverlongtermsk"));
+        for &(sk, pk) in &[("clientlongtermsk", "clientlongtermpk"), ("clientshorttermsk", "clientshorttermpk"),
+                           ("serverlongtermsk", "serverlongtermpk"), ("servershorttermsk", "servershorttermpk")] {
+            let sk = SecretKey::new(vector_key(sk));
+            assert_eq!(&sk.public_key().0[..], &vector(pk)[..]);
+        }
+        let pk = |name| PublicKey(vector_key(name));
+        let sk = |name| Some(SecretKey::new(vector_key(name)));
+        let counter = |name| vector(name).iter().fold(0, |n, &b| n << 8 | b as u64);
+
+        // the messages the packets carry
+        let messages = [
+            ("initiatemessage", Message { id: 1, acked_id: 0, acked_first: 0, acked_ranges: [(0, 0); 5],
+                                          eof: Eof::None, offset: 0, data: b"hello from client" }),
+            ("servermessage", Message { id: 1, acked_id: 1, acked_first: 17, acked_ranges: [(0, 0); 5],
+                                        eof: Eof::None, offset: 0, data: b"hello from server" }),
+            ("clientmessage", Message { id: 2, acked_id: 1, acked_first: 17, acked_ranges: [(0, 0); 5],
+                                        eof: Eof::Success, offset: 17, data: b"bye" })
+        ];
+        for &(name, ref m) in &messages {
+            let expected = vector(name);
+            let mut buf = [0; 128];
+            assert_eq!(m.encode(&mut buf), expected.len() as isize);
+            assert_eq!(&buf[..expected.len()], &expected[..]);
+            assert_eq!(Message::decode(&expected).as_ref(), Some(m));
+        }
+
+        // both sides fed the vector keys and nonces make the vector packets
+        let mut serverext = [0; 16];
+        let mut clientext = [0; 16];
+        serverext.copy_from_slice(&vector("serverext"));
+        clientext.copy_from_slice(&vector("clientext"));
+        let servername = String::from_utf8(vector("servername")).unwrap();
+        let mut hellononce = vec![0; 26];
+        hellononce.extend_from_slice(&(counter("hellononce") - 1).to_be_bytes()[2..]);
+        let clientconfig = ClientConfig::new(pk("serverlongtermpk"))
+            .client_keypair(pk("clientlongtermpk"), SecretKey::new(vector_key("clientlongtermsk")))
+            .client_ext(clientext)
+            .server_ext(serverext);
+        let serverconfig = ServerConfig::new(pk("serverlongtermpk"), serverlongtermsk.clone()).server_ext(serverext);
+        let mut client = CCPContext::with_rng(script(&[&hellononce, &vector("clientshorttermsk"), &vector("vouchnonce")]));
+        let mut server = CCPContext::with_rng(script(&[b"cookie-r", &vector("servershorttermsk")]));
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+
+        let n = client.mk_client_hello(&mut buf, &clientconfig) as usize;
+        assert_eq!(&buf[..n], &vector("hello")[..]);
+        assert_eq!(server.parse_client_hello(&buf, n, &serverconfig), n as isize);
+        let n = server.mk_server_cookie(&mut buf) as usize;
+        assert_eq!(&buf[..n], &vector("cookie")[..]);
+        assert_eq!(client.parse_server_cookie(&buf, n), n as isize);
+        let n = client.mk_client_initiate(&mut buf, &servername, &vector("initiatemessage")) as usize;
+        assert_eq!(&buf[..n], &vector("initiate")[..]);
+        assert_eq!(server.parse_client_initiate(&buf, n), n as isize);
+        assert!(server.verify_client_vouch());
+        assert_eq!(server.clientlongtermpk(), pk("clientlongtermpk"));
+        assert_eq!(server.servername(), Some(servername));
+        assert_eq!(server.message(), &vector("initiatemessage")[..]);
+        let n = server.mk_server_message(&mut buf, &vector("servermessage")) as usize;
+        assert_eq!(&buf[..n], &vector("servermessagepacket")[..]);
+        assert_eq!(client.parse_server_message(&buf, n), n as isize);
+        assert_eq!(client.message(), &vector("servermessage")[..]);
+        let n = client.mk_client_message(&mut buf, &vector("clientmessage")) as usize;
+        assert_eq!(&buf[..n], &vector("clientmessagepacket")[..]);
+        assert_eq!(server.parse_client_message(&buf, n), n as isize);
+        assert_eq!(server.message(), &vector("clientmessage")[..]);
+
+        // a server with the vector long-term key takes the Hello, not a forged one
+        let hello = vector("hello");
+        buf[..hello.len()].copy_from_slice(&hello);
+        let mut ctx = CCPContext::new();
+        assert_eq!(ctx.parse_client_hello(&buf, hello.len(), &serverconfig), 224);
+        assert_eq!(ctx.clientshorttermpk(), pk("clientshorttermpk"));
+        assert_eq!(ctx.clientext(), clientext);
+        buf[150] ^= 1;
+        assert_eq!(ctx.parse_client_hello(&buf, hello.len(), &serverconfig), -3);
+
+        // every field of every packet, boxes opened with the vector keys
+        let keys = Keys {
+            serverlongtermpk: Some(pk("serverlongtermpk")),
+            serverlongtermsk: Some(serverlongtermsk),
+            servershorttermpk: Some(pk("servershorttermpk")),
+            servershorttermsk: sk("servershorttermsk"),
+            clientshorttermpk: Some(pk("clientshorttermpk")),
+            clientshorttermsk: None
+        };
+        // the cookie inside the cookie and initiate vectors is this crate's
+        // placeholder, not a real minute-key box, so only its bytes are checked
+        let mut cookie = vector("cookienonce");
+        cookie.extend_from_slice(&[0; 80]);
+        let packets = [
+            ("hello", PacketKind::ClientHello, counter("hellononce"), None),
+            ("cookie", PacketKind::ServerCookie, 0, None),
+            ("initiate", PacketKind::ClientInitiate, counter("hellononce") + 1, Some("initiatemessage")),
+            ("servermessagepacket", PacketKind::ServerMessage, counter("servermessagenonce"), Some("servermessage")),
+            ("clientmessagepacket", PacketKind::ClientMessage, counter("hellononce") + 2, Some("clientmessage"))
+        ];
+        for &(name, kind, nonce, message) in &packets {
+            let packet = vector(name);
+            let d = dissect_with(&packet, &keys);
+            assert_eq!(d.kind, Some(kind), "{}", name);
+            assert!(d.problems.is_empty() && d.decrypted(), "{}: {:?}", name, d.problems);
+            assert_eq!(d.bytes("clientext"), Some(&vector("clientext")[..]));
+            assert_eq!(d.bytes("serverext"), Some(&vector("serverext")[..]));
+            if kind != PacketKind::ServerCookie {
+                assert_eq!(d.field("noncecounter"), Some(&Value::Number(nonce)), "{}", name);
+            }
+            if kind != PacketKind::ServerCookie && kind != PacketKind::ServerMessage {
+                assert_eq!(d.bytes("clientshorttermpk"), Some(&vector("clientshorttermpk")[..]));
+            }
+            assert_eq!(d.message, message.map(vector), "{}", name);
+        }
+        let d = dissect_with(&vector("cookie"), &keys);
+        assert_eq!(d.bytes("nonce"), Some(&vector("cookienonce")[..]));
+        assert_eq!(d.bytes("servershorttermpk"), Some(&vector("servershorttermpk")[..]));
+        assert_eq!(d.bytes("cookie"), Some(&cookie[..]));
+        let d = dissect_with(&vector("initiate"), &keys);
+        assert_eq!(d.bytes("cookie"), Some(&cookie[..]));
+        assert_eq!(d.bytes("clientlongtermpk"), Some(&vector("clientlongtermpk")[..]));
+        assert_eq!(&d.bytes("vouch").unwrap()[..16], &vector("vouchnonce")[..]);
+        assert_eq!(d.field("servername"), Some(&Value::Text(String::from_utf8(vector("servername")).unwrap())));
+    }
+
+    #[test]
+    fn test_rng() {
+        let hello = |rng: Arc<dyn Rng>| {
+            let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+            let config = ClientConfig::new(PUBLICKEY).client_keypair(PUBLICKEY, SecretKey::new(SECRETKEY));
+            let n = CCPContext::with_rng(rng).mk_client_hello(&mut buf, &config) as usize;
+            buf[..n].to_vec()
+        };
+        assert_eq!(hello(Arc::new(SeededRng::new(1))), hello(Arc::new(SeededRng::new(1))));
+        assert!(hello(Arc::new(SeededRng::new(1))) != hello(Arc::new(SeededRng::new(2))));
+        assert!(hello(Arc::new(SodiumRng)) != hello(Arc::new(SodiumRng)));
+
+        let rng = SeededRng::new(7);
+        let (pk, sk) = rng.keypair();
+        assert_eq!(sk.public_key(), pk);
+        assert!(rng.keypair().0 != pk);
+        assert!((0..100).all(|_| rng.randommod(10) < 10));
+        assert_eq!(rng.randommod(1), 0);
+
+        // sessions draw from the config's generator
+        let seeded = |seed| {
+            let config = ClientConfig::new(PUBLICKEY).rng(Arc::new(SeededRng::new(seed))).random_client_ext();
+            let mut session = ClientSession::new(config);
+            let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+            let n = session.poll_transmit(&mut buf).unwrap();
+            buf[..n].to_vec()
+        };
+        assert_eq!(&seeded(3)[..72], &seeded(3)[..72]);
+        assert!(seeded(3)[24..40] != seeded(4)[24..40]);
+    }
+
+    #[test]
+    fn test_clock() {
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        let policy = RetransmitPolicy::default();
+        let clock = Arc::new(ManualClock::new());
+        let client_config = ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT).clock(clock.clone());
+        let server_config = ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY))
+            .server_ext(SERVER_EXT)
+            .clock(clock.clone());
+
+        // Hello is resent only once the clock has moved, then given up on
+        let mut client = ClientSession::new(client_config.clone());
+        assert!(client.poll_transmit(&mut buf).is_some());
+        assert!(client.poll_transmit(&mut buf).is_none());
+        assert_eq!(client.next_timeout(), Some(clock.now() + policy.hello_interval));
+        clock.advance(policy.hello_interval);
+        assert!(client.poll_transmit(&mut buf).is_some());
+        for _ in 2..policy.hello_tries {
+            clock.advance(Duration::from_secs(60));
+            assert!(client.poll_transmit(&mut buf).is_some());
+        }
+        clock.advance(Duration::from_secs(60));
+        assert!(client.poll_transmit(&mut buf).is_none());
+        assert_eq!(client.state(), SessionState::Closed);
+
+        // data is retransmitted after the RTO, the session closes when idle
+        let mut server = Server::new(server_config.clone());
+        let mut client = ClientSession::new(client_config.clone());
+        client.write_all(b"ping").unwrap();
+        assert!(handshake(&mut client, &mut server) > 0);
+        let id = server.accept().unwrap();
+        server.session(&id).unwrap().write_all(b"pong").unwrap();
+        assert!(server.poll_transmit(&mut buf).is_some());
+        assert!(server.poll_transmit(&mut buf).is_none());
+        clock.advance_to(server.next_timeout().unwrap());
+        assert_eq!(clock.now() - server.now(), Duration::from_secs(0));
+        let (n, _) = server.poll_transmit(&mut buf).unwrap();
+        assert!(client.handle_datagram(&buf, n) > 0);
+        let mut pong = [0; 4];
+        assert_eq!(client.read(&mut pong).unwrap(), 4);
+        assert_eq!(&pong, b"pong");
+        clock.advance(Duration::from_secs(60));
+        assert!(server.poll_transmit(&mut buf).is_none());
+        assert!(server.session(&id).is_none_or(|s| s.is_closed()));
+        assert!(client.poll_transmit(&mut buf).is_none());
+        assert_eq!(client.state(), SessionState::Closed);
+
+        // a full pending table makes room by dropping the oldest handshake
+        let limits = Limits { max_pending: 1, ..Limits::default() };
+        let mut server = Server::new(server_config.limits(limits));
+        let mut first = ClientSession::new(client_config.clone());
+        let n = first.poll_transmit(&mut buf).unwrap();
+        assert!(server.handle_datagram(&buf, n, addr) > 0);
+        let (n, _) = server.poll_transmit(&mut buf).unwrap();
+        assert!(first.handle_datagram(&buf, n) > 0);
+        clock.advance(Duration::from_secs(1));
+        let mut second = ClientSession::new(client_config);
+        assert!(handshake(&mut second, &mut server) > 0);
+        let n = first.poll_transmit(&mut buf).unwrap();
+        assert_eq!(server.handle_datagram(&buf, n, addr), -4);
+        assert_eq!(clock.elapsed(), Duration::from_secs(1 + 60 * 8 + 1) + policy.initial_rto);
+    }
+
+    #[test]
+    fn test_simulator() {
+        let data: Vec<u8> = (0..60000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+        let (clientpk, clientsk) = keypair();
+        let client = ClientConfig::new(PUBLICKEY).client_keypair(clientpk, clientsk).server_ext(SERVER_EXT);
+        let server = ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT);
+
+        let mut sim = Simulation::new(client.clone(), server.clone(), Conditions::default(), 1);
+        assert_eq!(echo(&mut sim, &data), data);
+        assert_eq!(sim.stats.lost + sim.stats.corrupted + sim.stats.rebinds, 0);
+        assert!(sim.elapsed() < Duration::from_secs(5));
+
+        let adverse = Conditions {
+            loss: 0.1,
+            duplicate: 0.05,
+            reorder: 0.1,
+            corrupt: 0.05,
+            rebind: 0.02,
+            delay: Duration::from_millis(30),
+            jitter: Duration::from_millis(20)
+        };
+        // the client has to speak up now and then for the server to follow it to a new port
+        let keepalive = RetransmitPolicy { keepalive: Some(Duration::from_secs(2)), ..RetransmitPolicy::default() };
+        let client = client.retransmit(keepalive);
+        for seed in 0..4 {
+            let mut sim = Simulation::new(client.clone(), server.clone(), adverse.clone(), seed);
+            assert_eq!(echo(&mut sim, &data), data, "seed {}", seed);
+            let stats = &sim.stats;
+            assert!(stats.lost > 0 && stats.duplicated > 0 && stats.reordered > 0, "{:?}", stats);
+            assert!(stats.corrupted > 0 && stats.rebinds > 0, "{:?}", stats);
+            let id = sim.server.session_ids()[0];
+            assert_eq!(sim.server.session(&id).unwrap().peer_addr(), sim.client_addr());
+        }
+
+        // one seed, one run; the capture of it makes sense too
+        let run = |seed| {
+            let mut sim = Simulation::new(client.clone(), server.clone(), adverse.clone(), seed);
+            echo(&mut sim, &data[..5000]);
+            (sim.stats.clone(), sim.log.clone(), sim.elapsed())
+        };
+        let (stats, log, elapsed) = run(9);
+        let again = run(9);
+        assert_eq!(stats, again.0);
+        assert_eq!(elapsed, again.2);
+        assert_eq!(log.len(), again.1.len());
+        assert!(log.iter().zip(again.1.iter()).all(|(a, b)| a.payload == b.payload && a.time == b.time));
+        assert!(run(10).0 != stats);
+        let capture = analyze(&log, Keys::default(), vec![]);
+        assert!(capture.sessions.iter().any(|s| s.established()));
+    }
+
+    #[test]
+    fn test_two_party_handshake() {
+        let (clientpk, clientsk) = keypair();
+        let client_config = ClientConfig::new(PUBLICKEY)
+            .client_keypair(clientpk, clientsk)
+            .client_ext([7; 16])
+            .server_ext(SERVER_EXT);
+        let server_config = ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT);
+        let mut client = CCPContext::new();
+        let mut server = CCPContext::new();
+        let mut up = Wire::default();
+        let mut down = Wire::default();
+
+        // every packet is first offered with a bit of its box flipped, then intact
+        up.send(|buf| client.mk_client_hello(buf, &client_config));
+        assert_eq!(up.tampered(200, |buf, n| server.parse_client_hello(buf, n, &server_config)), -3);
+        assert_eq!(up.deliver(|buf, n| server.parse_client_hello(buf, n, &server_config)), 224);
+        assert_eq!(server.clientshorttermpk(), client.clientshorttermpk());
+        assert_eq!(server.clientext(), [7; 16]);
+
+        down.send(|buf| server.mk_server_cookie(buf));
+        assert_eq!(down.tampered(100, |buf, n| client.parse_server_cookie(buf, n)), -3);
+        assert_eq!(down.deliver(|buf, n| client.parse_server_cookie(buf, n)), 200);
+
+        let hello = [b'h'; 32];
+        up.send(|buf| client.mk_client_initiate(buf, SERVER_NAME, &hello));
+        assert_eq!(up.tampered(300, |buf, n| server.parse_client_initiate(buf, n)), -3);
+        assert_eq!(up.deliver(|buf, n| server.parse_client_initiate(buf, n)), 544 + 32);
+        assert!(server.verify_client_vouch());
+        assert_eq!(server.clientlongtermpk(), clientpk);
+        assert_eq!(server.servername().as_ref().map(|s| &s[..]), Some(SERVER_NAME));
+        assert_eq!(server.message(), &hello[..]);
+
+        for &size in &[16, 256, 1024, CCP_MAX_MESSAGE_SIZE] {
+            let down_msg: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            down.send(|buf| server.mk_server_message(buf, &down_msg));
+            assert_eq!(down.tampered(64 + size - 1, |buf, n| client.parse_server_message(buf, n)), -3);
+            assert_eq!(down.deliver(|buf, n| client.parse_server_message(buf, n)), 64 + size as isize);
+            assert_eq!(client.message(), &down_msg[..]);
+
+            let up_msg: Vec<u8> = (0..size).map(|i| !i as u8).collect();
+            up.send(|buf| client.mk_client_message(buf, &up_msg));
+            assert_eq!(up.tampered(90, |buf, n| server.parse_client_message(buf, n)), -3);
+            assert_eq!(up.deliver(|buf, n| server.parse_client_message(buf, n)), 96 + size as isize);
+            assert_eq!(server.message(), &up_msg[..]);
+        }
+        assert!(up.0.is_empty() && down.0.is_empty());
+    }
+
+    #[test]
+    fn test_two_party_mismatch() {
+        let client_config = ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT);
+        let server_config = ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT);
+        let mut wire = Wire::default();
+
+        // Hello for another server key or extension
+        let (otherpk, othersk) = keypair();
+        let other_server = ServerConfig::new(otherpk, othersk).server_ext(SERVER_EXT);
+        let mut client = CCPContext::new();
+        wire.send(|buf| client.mk_client_hello(buf, &ClientConfig::new(otherpk).server_ext(SERVER_EXT)));
+        assert_eq!(wire.deliver(|buf, n| CCPContext::new().parse_client_hello(buf, n, &server_config)), -3);
+        wire.send(|buf| client.mk_client_hello(buf, &client_config));
+        assert_eq!(wire.deliver(|buf, n| CCPContext::new().parse_client_hello(buf, n, &other_server)), -3);
+        wire.send(|buf| client.mk_client_hello(buf, &client_config.clone().server_ext([9; 16])));
+        assert_eq!(wire.deliver(|buf, n| CCPContext::new().parse_client_hello(buf, n, &server_config)), -2);
+
+        // a Cookie only opens for the client that sent the Hello
+        let mut scratch: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        let mut client = CCPContext::new();
+        let mut server = CCPContext::new();
+        wire.send(|buf| client.mk_client_hello(buf, &client_config));
+        assert!(wire.deliver(|buf, n| server.parse_client_hello(buf, n, &server_config)) > 0);
+        wire.send(|buf| server.mk_server_cookie(buf));
+        let mut stranger = CCPContext::new();
+        stranger.mk_client_hello(&mut scratch, &client_config);
+        assert_eq!(wire.offer(|buf, n| stranger.parse_server_cookie(buf, n)), -3);
+        let mut other_ext = CCPContext::new();
+        other_ext.mk_client_hello(&mut scratch, &client_config.clone().client_ext([1; 16]));
+        assert_eq!(wire.offer(|buf, n| other_ext.parse_server_cookie(buf, n)), -2);
+        assert_eq!(wire.offer(|buf, n| client.parse_client_hello(buf, n, &server_config)), -1);
+        assert_eq!(wire.deliver(|buf, n| client.parse_server_cookie(buf, n)), 200);
+
+        // an Initiate only opens for the server that made the Cookie
+        let mut impostor = CCPContext::new();
+        let n = CCPContext::new().mk_client_hello(&mut scratch, &client_config) as usize;
+        assert!(impostor.parse_client_hello(&scratch, n, &server_config) > 0);
+        impostor.mk_server_cookie(&mut scratch);
+        wire.send(|buf| client.mk_client_initiate(buf, SERVER_NAME, &[0; 16]));
+        assert_eq!(wire.offer(|buf, n| impostor.parse_client_initiate(buf, n)), -3);
+        assert_eq!(wire.deliver(|buf, n| server.parse_client_initiate(buf, n)), 560);
+
+        // Messages of one session don't open in another
+        wire.send(|buf| impostor.mk_server_message(buf, &[0; 16]));
+        assert_eq!(wire.deliver(|buf, n| client.parse_server_message(buf, n)), -3);
+        wire.send(|buf| server.mk_server_message(buf, &[0; 16]));
+        assert_eq!(wire.deliver(|buf, n| client.parse_server_message(buf, n)), 80);
+    }
+
+    #[test]
+    fn test_short_packets() {
+        let client_config = ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT);
+        let server_config = ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT);
+        let mut client = CCPContext::new();
+        let mut server = CCPContext::new();
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        client.mk_client_hello(&mut buf, &client_config);
+        buf[144..224].copy_from_slice(&[0; 80]);
+
+        // right signature and extensions, any size: rejected, never a panic
+        for &sig in &[b"QvnQ5XlH", b"RL3aNMXK", b"QvnQ5XlI", b"QvnQ5XlM", b"RL3aNMXM", b"\xff\xfe\0\0\0\0\0\0"] {
+            buf[..8].copy_from_slice(sig);
+            for size in (0..CCP_MAX_PACKET_SIZE + 2).chain(vec![usize::MAX]) {
+                assert!(server.parse_client_hello(&buf, size, &server_config) < 0);
+                assert!(client.parse_server_cookie(&buf, size) < 0);
+                assert!(server.parse_client_initiate(&buf, size) < 0);
+                assert!(server.parse_client_message(&buf, size) < 0);
+                assert!(client.parse_server_message(&buf, size) < 0);
+            }
+        }
+        for &size in &[0, 8, 48, 64, 79, 80, 95, 176, 543, 559, 1185] {
+            assert_eq!(client.parse_server_message(&buf, size), -1, "{}", size);
+            assert_eq!(server.parse_client_message(&buf, size), -1, "{}", size);
+            assert_eq!(server.parse_client_initiate(&buf, size), -1, "{}", size);
+        }
+    }
+
+    #[test]
+    fn test_in_place() {
+        let client_config = ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT);
+        let server_config = ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT);
+        let mut client = CCPContext::new();
+        let mut server = CCPContext::new();
+        let mut up = Wire::default();
+        let mut down = Wire::default();
+        up.send(|buf| client.mk_client_hello(buf, &client_config));
+        assert_eq!(up.deliver(|buf, n| server.parse_client_hello(buf, n, &server_config)), 224);
+        down.send(|buf| server.mk_server_cookie(buf));
+        assert_eq!(down.deliver(|buf, n| client.parse_server_cookie(buf, n)), 200);
+        up.send(|buf| client.mk_client_initiate(buf, SERVER_NAME, &[0; 16]));
+        assert_eq!(up.deliver(|buf, n| server.parse_client_initiate(buf, n)), 560);
+
+        for &size in &[16, 100, CCP_MAX_MESSAGE_SIZE] {
+            let msg: Vec<u8> = (0..size).map(|i| i as u8).collect();
+
+            // sealed where it lies, then parsed from a copy and opened where it lies
+            let mut packet = vec![0; CCP_CLIENT_MESSAGE_OFFSET + size];
+            packet[CCP_CLIENT_MESSAGE_OFFSET..].copy_from_slice(&msg);
+            assert_eq!(client.seal_client_message(&mut packet, size), packet.len() as isize);
+            assert!(packet[CCP_CLIENT_MESSAGE_OFFSET..] != msg[..]);
+            up.send(|buf| {
+                buf[..packet.len()].copy_from_slice(&packet);
+                packet.len() as isize
+            });
+            assert_eq!(up.deliver(|buf, n| server.parse_client_message(buf, n)), packet.len() as isize);
+            assert_eq!(server.message(), &msg[..]);
+            let mut tampered = packet.clone();
+            tampered[CCP_CLIENT_MESSAGE_OFFSET - 1] ^= 1;
+            assert_eq!(server.open_client_message(&mut tampered), -3);
+            assert_eq!(tampered[CCP_CLIENT_MESSAGE_OFFSET..], packet[CCP_CLIENT_MESSAGE_OFFSET..]);
+            assert_eq!(server.open_client_message(&mut packet), packet.len() as isize);
+            assert_eq!(&packet[CCP_CLIENT_MESSAGE_OFFSET..], &msg[..]);
+
+            down.send(|buf| server.mk_server_message(buf, &msg));
+            let mut packet = down.0.pop_front().unwrap();
+            assert_eq!(client.open_server_message(&mut packet), (CCP_SERVER_MESSAGE_OFFSET + size) as isize);
+            assert_eq!(&packet[CCP_SERVER_MESSAGE_OFFSET..], &msg[..]);
+        }
+
+        // too short, too long, someone else's
+        let mut packet = [0; CCP_MAX_PACKET_SIZE];
+        assert_eq!(client.seal_client_message(&mut packet, 15), -1);
+        assert_eq!(client.seal_client_message(&mut packet[..CCP_CLIENT_MESSAGE_OFFSET + 15], 16), -1);
+        assert_eq!(server.seal_server_message(&mut packet, CCP_MAX_MESSAGE_SIZE + 1), -1);
+        let n = client.seal_client_message(&mut packet, 16) as usize;
+        assert_eq!(server.open_client_message(&mut packet[..n - 1]), -1);
+        assert_eq!(client.open_server_message(&mut packet[..n]), -1);
+        packet[8] ^= 1;
+        assert_eq!(server.open_client_message(&mut packet[..n]), -2);
+    }
+
+    #[test]
+    fn test_batch() {
+        // more than a batch over loopback, in order
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        b.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut batch = Batch::new();
+        let mut received = vec![];
+        for round in 0..2 {
+            for i in 0..BATCH_SIZE + 1 {
+                let data = vec![(round * 100 + i) as u8; 16 + i];
+                assert_eq!(batch.push(&data, b.local_addr().unwrap()), i < BATCH_SIZE);
+            }
+            assert!(batch.is_full());
+            batch.send(&a).unwrap();
+            assert!(batch.is_empty());
+        }
+        while received.len() < 2 * BATCH_SIZE {
+            let n = batch.recv(&b).unwrap();
+            assert!(n > 0 && n == batch.len());
+            for i in 0..n {
+                let (buf, size, from) = batch.get(i);
+                assert_eq!(from, a.local_addr().unwrap());
+                received.push(buf[..size].to_vec());
+            }
+        }
+        for (i, data) in received.iter().enumerate() {
+            let (round, i) = (i / BATCH_SIZE, i % BATCH_SIZE);
+            assert_eq!(data, &vec![(round * 100 + i) as u8; 16 + i]);
+        }
+
+        // a server fed and drained a batch at a time
+        let from: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let mut client = ClientSession::new(ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT));
+        let mut server = Server::new(ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT));
+        let data: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let mut written = 0;
+        let mut echoed = vec![];
+        let mut buf = [0; 4096];
+        for _ in 0..200 {
+            if let Ok(n) = client.write(&data[written..]) {
+                written += n;
+            }
+            while batch.push_with(|buf| client.poll_transmit(buf).map(|n| (n, from))) {}
+            server.handle_batch(&batch);
+            batch.clear();
+
+            while server.accept().is_some() {}
+            for id in server.session_ids() {
+                let session = server.session(&id).unwrap();
+                while let Ok(n) = session.read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                    session.write_all(&buf[..n]).unwrap();
+                }
+            }
+            while server.poll_transmit_batch(&mut batch) > 0 {
+                for i in 0..batch.len() {
+                    let (buf, size, to) = batch.get(i);
+                    assert_eq!(to, from);
+                    client.handle_datagram(buf, size);
+                }
+                batch.clear();
+            }
+            while let Ok(n) = client.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                echoed.extend_from_slice(&buf[..n]);
+            }
+            if echoed.len() == data.len() {
+                break;
+            }
+        }
+        assert_eq!(echoed, data);
+    }
+
+    #[test]
+    fn test_serve_replies() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let config = ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT);
+        let server = thread::spawn(move || {
+            let mut server = Server::new(config);
+            serve(&mut server, &socket, Some(1), |_, _| Some(Relay::new(Cursor::new(b"pong".to_vec()), io::sink())))
+        });
+
+        // only server packets come back, the client's are never reflected
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client_socket.connect(addr).unwrap();
+        client_socket.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let mut client = ClientSession::new(ClientConfig::new(PUBLICKEY).server_ext(SERVER_EXT));
+        client.write_all(b"ping").unwrap();
+        client.close();
+        let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+        let mut kinds = vec![];
+        let mut received = vec![];
+        for _ in 0..1000 {
+            if client.is_closed() {
+                break;
+            }
+            while let Some(n) = client.poll_transmit(&mut buf) {
+                client_socket.send(&buf[..n]).unwrap();
+            }
+            if let Ok(n) = client_socket.recv(&mut buf) {
+                kinds.push(packet_kind(&buf, n));
+                client.handle_datagram(&buf, n);
+            }
+            while let Ok(n) = client.read(&mut buf) {
+                received.extend_from_slice(&buf[..n]);
+                if n == 0 {
+                    break;
+                }
+            }
+        }
+        assert!(client.is_closed());
+        assert_eq!(received, b"pong");
+        server.join().unwrap().unwrap();
+        assert_eq!(kinds[0], Some(PacketKind::ServerCookie));
+        assert!(kinds[1..].iter().all(|&kind| kind == Some(PacketKind::ServerMessage)));
+    }
+
+    proptest! {
+        // packets made from arbitrary keys, extensions, names, nonces and payloads open as made
+        #[test]
+        fn test_roundtrip_packets(keys in any::<[[u8; 32]; 4]>(),
+                                  exts in any::<([u8; 16], [u8; 16])>(),
+                                  name in servername(),
+                                  nonce in 0..1u64 << 48,
+                                  nonces in any::<([u8; 8], [u8; 16])>(),
+                                  initiate in prop::collection::vec(any::<u8>(), 16..=CCP_MAX_CLIENT_INIT_PAYLOAD_SIZE),
+                                  messages in prop::collection::vec(prop::collection::vec(any::<u8>(), 16..=CCP_MAX_MESSAGE_SIZE), 1..4)) {
+            let (serverpk, serversk) = keypair_of(keys[0]);
+            let (clientpk, clientsk) = keypair_of(keys[1]);
+            let client_config = ClientConfig::new(serverpk)
+                .client_keypair(clientpk, clientsk)
+                .client_ext(exts.0)
+                .server_ext(exts.1);
+            let server_config = ServerConfig::new(serverpk, serversk).server_ext(exts.1);
+            // randommod reads 32 bytes as a big-endian number
+            let mut client = CCPContext::with_rng(script(&[&[0; 24], &nonce.to_be_bytes(), &keys[2], &nonces.1]));
+            let mut server = CCPContext::with_rng(script(&[&nonces.0, &keys[3]]));
+            let mut up = Wire::default();
+            let mut down = Wire::default();
+
+            up.send(|buf| client.mk_client_hello(buf, &client_config));
+            prop_assert_eq!(&up.0[0][136..144], &(nonce + 1).to_le_bytes()[..]);
+            prop_assert_eq!(up.deliver(|buf, n| server.parse_client_hello(buf, n, &server_config)), 224);
+            prop_assert_eq!(server.clientshorttermpk(), keypair_of(keys[2]).0);
+            prop_assert_eq!(server.clientext(), exts.0);
+            prop_assert_eq!(server.serverext(), exts.1);
+
+            down.send(|buf| server.mk_server_cookie(buf));
+            prop_assert_eq!(&down.0[0][48..56], &nonces.0[..]);
+            prop_assert_eq!(down.deliver(|buf, n| client.parse_server_cookie(buf, n)), 200);
+
+            if nameparse(&name).len() > 256 {
+                let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+                prop_assert_eq!(client.mk_client_initiate(&mut buf, &name, &initiate), -2);
+                return Ok(());
+            }
+            up.send(|buf| client.mk_client_initiate(buf, &name, &initiate));
+            prop_assert_eq!(&up.0[0][168..176], &(nonce + 2).to_le_bytes()[..]);
+            prop_assert_eq!(up.deliver(|buf, n| server.parse_client_initiate(buf, n)), 544 + initiate.len() as isize);
+            prop_assert!(server.verify_client_vouch());
+            prop_assert_eq!(server.clientlongtermpk(), clientpk);
+            prop_assert_eq!(server.servername(), Some(name.clone()));
+            prop_assert_eq!(server.message(), &initiate[..]);
+
+            for msg in &messages {
+                down.send(|buf| server.mk_server_message(buf, msg));
+                prop_assert_eq!(down.deliver(|buf, n| client.parse_server_message(buf, n)), 64 + msg.len() as isize);
+                prop_assert_eq!(client.message(), &msg[..]);
+                up.send(|buf| client.mk_client_message(buf, msg));
+                prop_assert_eq!(up.deliver(|buf, n| server.parse_client_message(buf, n)), 96 + msg.len() as isize);
+                prop_assert_eq!(server.message(), &msg[..]);
+            }
+        }
+
+        // a single flipped bit anywhere in the box of any packet is caught
+        #[test]
+        fn test_flipped_box(seed in any::<u64>(), size in 16..=CCP_MAX_MESSAGE_SIZE, bits in any::<[usize; 5]>()) {
+            let rng: Arc<dyn Rng> = Arc::new(SeededRng::new(seed));
+            let (clientpk, clientsk) = rng.keypair();
+            let client_config = ClientConfig::new(PUBLICKEY)
+                .client_keypair(clientpk, clientsk)
+                .server_ext(SERVER_EXT)
+                .rng(rng.clone());
+            let server_config = ServerConfig::new(PUBLICKEY, SecretKey::new(SECRETKEY)).server_ext(SERVER_EXT);
+            let mut client = CCPContext::with_rng(rng.clone());
+            let mut server = CCPContext::with_rng(rng);
+            let mut up = Wire::default();
+            let mut down = Wire::default();
+            let msg = vec![0x5a; size];
+            // bit number within the box of the next datagram, which starts at offset start
+            let flip = |wire: &Wire, start: usize, bit: usize| start * 8 + bit % ((wire.front_len() - start) * 8);
+
+            up.send(|buf| client.mk_client_hello(buf, &client_config));
+            let bit = flip(&up, 144, bits[0]);
+            prop_assert_eq!(up.flipped(bit, |buf, n| server.parse_client_hello(buf, n, &server_config)), -3);
+            prop_assert_eq!(up.deliver(|buf, n| server.parse_client_hello(buf, n, &server_config)), 224);
+
+            down.send(|buf| server.mk_server_cookie(buf));
+            let bit = flip(&down, 56, bits[1]);
+            prop_assert_eq!(down.flipped(bit, |buf, n| client.parse_server_cookie(buf, n)), -3);
+            prop_assert_eq!(down.deliver(|buf, n| client.parse_server_cookie(buf, n)), 200);
+
+            up.send(|buf| client.mk_client_initiate(buf, SERVER_NAME, &msg[..cmp::min(size, CCP_MAX_CLIENT_INIT_PAYLOAD_SIZE)]));
+            let bit = flip(&up, 176, bits[2]);
+            prop_assert_eq!(up.flipped(bit, |buf, n| server.parse_client_initiate(buf, n)), -3);
+            prop_assert!(up.deliver(|buf, n| server.parse_client_initiate(buf, n)) > 0);
+
+            down.send(|buf| server.mk_server_message(buf, &msg));
+            let bit = flip(&down, 48, bits[3]);
+            prop_assert_eq!(down.flipped(bit, |buf, n| client.parse_server_message(buf, n)), -3);
+            prop_assert!(down.deliver(|buf, n| client.parse_server_message(buf, n)) > 0);
+
+            up.send(|buf| client.mk_client_message(buf, &msg));
+            let bit = flip(&up, 80, bits[4]);
+            prop_assert_eq!(up.flipped(bit, |buf, n| server.parse_client_message(buf, n)), -3);
+            prop_assert!(up.deliver(|buf, n| server.parse_client_message(buf, n)) > 0);
+        }
+
+        // messages with arbitrary acknowledgements and stream offsets decode as encoded
+        #[test]
+        fn test_roundtrip_message(ids in any::<(u32, u32)>(),
+                                  acked_first in prop_oneof![any::<u64>(), u64::MAX - 100000..],
+                                  acked_ranges in any::<[(u32, u16); 5]>(),
+                                  eof in prop_oneof![Just(Eof::None), Just(Eof::Success), Just(Eof::Failure)],
+                                  offset in prop_oneof![any::<u64>(), u64::MAX - 2048..],
+                                  data in prop::collection::vec(any::<u8>(), 0..=MSG_MAX_BLOCK_SIZE)) {
+            let msg = Message { id: ids.0, acked_id: ids.1, acked_first, acked_ranges, eof, offset, data: &data };
+            let mut buf = [0xa5; MSG_HEADER_SIZE + MSG_MAX_BLOCK_SIZE + 16];
+            let n = msg.encode(&mut buf);
+            prop_assert_eq!(n, msg.size() as isize);
+            let decoded = Message::decode(&buf[..n as usize]);
+            if offset.checked_add(data.len() as u64).is_some() {
+                prop_assert_eq!(decoded.as_ref(), Some(&msg));
+            } else {
+                prop_assert_eq!(decoded, None);
+            }
+
+            // ranges follow each other up to the first empty one
+            let acked = msg.acked();
+            prop_assert_eq!(acked[0], (0, acked_first));
+            prop_assert_eq!(acked.len(), 1 + acked_ranges.iter().take_while(|r| r.1 != 0).count());
+            for pair in acked.windows(2) {
+                prop_assert!(pair[0].1 <= pair[1].0 && pair[1].0 <= pair[1].1);
+            }
+        }
+    }
+
+    // a datagram from IPv4 to IPv6 can't be written
+    fn mixed_families() -> bool {
+        let mut w = PcapWriter::new(vec![]).unwrap();
+        let d = Datagram {
+            time: Duration::from_secs(0),
+            src: "10.0.0.1:1".parse().unwrap(),
+            dst: "[::1]:1".parse().unwrap(),
+            payload: vec![]
+        };
+        w.write(&d).is_err()
+    }
+
+    // data sent by the client and echoed back by the server, as the client got it
+    fn echo(sim: &mut Simulation, data: &[u8]) -> Vec<u8> {
+        let mut sent = 0;
+        let mut echoed = vec![];
+        let mut received = vec![];
+        let done = sim.run(Duration::from_secs(600), |client, server| {
+            if let Ok(n) = client.write(&data[sent..]) {
+                sent += n;
+            }
+            let mut buf = [0; 4096];
+            while let Ok(n) = client.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..n]);
+            }
+            while server.accept().is_some() {}
+            for id in server.session_ids() {
+                let session = server.session(&id).unwrap();
+                while let Ok(n) = session.read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                    echoed.extend_from_slice(&buf[..n]);
+                }
+                if let Ok(n) = session.write(&echoed) {
+                    echoed.drain(..n);
+                }
+            }
+            received.len() >= data.len()
+        });
+        assert!(done, "echo stalled after {:?}: {:?}", sim.elapsed(), sim.stats);
+        received
+    }
+
+    // datagrams in flight in one direction between two contexts
+    #[derive(Default)]
+    struct Wire(VecDeque<Vec<u8>>);
+
+    impl Wire {
+        fn send<F>(&mut self, make: F)
+            where F: FnOnce(&mut [u8; CCP_MAX_PACKET_SIZE]) -> isize {
+            let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+            let n = make(&mut buf);
+            assert!(n > 0, "packet not made: {}", n);
+            self.0.push_back(buf[..n as usize].to_vec());
+        }
+
+        // next datagram taken off the wire and parsed
+        fn deliver<F>(&mut self, parse: F) -> isize
+            where F: FnOnce(&[u8; CCP_MAX_PACKET_SIZE], usize) -> isize {
+            let ret = self.offer(parse);
+            self.0.pop_front();
+            ret
+        }
+
+        // next datagram parsed, but left on the wire
+        fn offer<F>(&self, parse: F) -> isize
+            where F: FnOnce(&[u8; CCP_MAX_PACKET_SIZE], usize) -> isize {
+            self.tampered(CCP_MAX_PACKET_SIZE, parse)
+        }
+
+        // next datagram parsed with a bit flipped at offset, left on the wire
+        fn tampered<F>(&self, offset: usize, parse: F) -> isize
+            where F: FnOnce(&[u8; CCP_MAX_PACKET_SIZE], usize) -> isize {
+            self.flipped(offset * 8 + 2, parse)
+        }
+
+        // next datagram parsed with bit number bit flipped, left on the wire
+        fn flipped<F>(&self, bit: usize, parse: F) -> isize
+            where F: FnOnce(&[u8; CCP_MAX_PACKET_SIZE], usize) -> isize {
+            let packet = self.0.front().expect("nothing on the wire");
+            let mut buf: [u8; CCP_MAX_PACKET_SIZE] = [0; CCP_MAX_PACKET_SIZE];
+            buf[..packet.len()].copy_from_slice(packet);
+            if bit / 8 < packet.len() {
+                buf[bit / 8] ^= 1 << (bit & 7);
+            }
+            parse(&buf, packet.len())
+        }
+
+        fn front_len(&self) -> usize {
+            self.0.front().expect("nothing on the wire").len()
+        }
+    }
+
+    // a value from vectors/curvecp.txt
+    fn vector(name: &str) -> Vec<u8> {
+        include_str!("../vectors/curvecp.txt").lines()
+            .filter_map(|line| {
+                let mut kv = line.splitn(2, " = ");
+                match (kv.next(), kv.next()) {
+                    (Some(key), Some(value)) if key == name => Some(value.from_hex().unwrap()),
+                    _ => None
+                }
+            })
+            .next()
+            .unwrap_or_else(|| panic!("no vector {}", name))
+    }
+
+    // hands out the given bytes in order, then panics
+    struct Script(Mutex<VecDeque<u8>>);
+
+    impl Rng for Script {
+        fn fill(&self, buf: &mut [u8]) {
+            let mut bytes = self.0.lock().unwrap();
+            for b in buf.iter_mut() {
+                *b = bytes.pop_front().expect("script ran out of bytes");
+            }
+        }
+    }
+
+    fn script(parts: &[&[u8]]) -> Arc<dyn Rng> {
+        Arc::new(Script(Mutex::new(parts.iter().flat_map(|p| p.iter().cloned()).collect())))
+    }
+
+    // dotted names of up to five labels, or of 62-byte labels around the
+    // 256 bytes an Initiate has for the encoded name
+    fn servername() -> impl Strategy<Value = String> {
+        prop_oneof![
+            prop::collection::vec("[a-zA-Z0-9-]{1,63}", 0..6).prop_map(|labels| labels.join(".")),
+            (253..260usize).prop_map(|len| (0..len).map(|i| if i % 63 == 62 { '.' } else { 'x' }).collect())
+        ]
+    }
+
+    fn keypair_of(sk: [u8; 32]) -> (PublicKey, SecretKey) {
+        keypair_from(|buf| buf.copy_from_slice(&sk))
+    }
+
+    fn vector_key(name: &str) -> [u8; 32] {
+        let mut key = [0; 32];
+        key.copy_from_slice(&vector(name));
+        key
+    }
 }
@@ -0,0 +1,133 @@
+/*
+ * Forwarding TCP connections through CurveCP.
+ *
+ * The local end listens on a TCP port and opens a client session per
+ * accepted connection; the remote end is a server that connects every
+ * session it lets in onward to a fixed TCP target. The local end pins
+ * the remote's long-term key through its ClientConfig, the remote end
+ * decides who may use the tunnel through its ServerConfig's authorizer.
+ */
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+use std::time::Duration;
+use config::ClientConfig;
+use relay::{run_client, serve, Relay};
+use session::{ClientSession, Server};
+
+// how long the remote end waits for the target, only that session waits meanwhile
+const TUNNEL_CONNECT_TIMEOUT:u64 = 5;
+
+fn tcp_relay(stream: &TcpStream) -> io::Result<Relay> {
+    Ok(Relay::socket(stream.try_clone()?, stream.try_clone()?))
+}
+
+/*
+ * Local end: every connection accepted on listener gets its own session
+ * to the remote end at addr, run on its own thread. Returns only on an
+ * accept error.
+ */
+pub fn forward_local(listener: &TcpListener, addr: SocketAddr, config: &ClientConfig) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept()?;
+        let config = config.clone().random_client_ext();
+        thread::spawn(move || {
+            let _ = forward_connection(&stream, addr, config);
+            // wakes the reader thread if the TCP side is still open
+            let _ = stream.shutdown(Shutdown::Read);
+        });
+    }
+}
+
+/*
+ * Relay one TCP connection through a new session, Ok(false) if the
+ * remote end never answered
+ */
+pub fn forward_connection(stream: &TcpStream, addr: SocketAddr, config: ClientConfig) -> io::Result<bool> {
+    let bind = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(addr)?;
+    let mut session = ClientSession::new(config);
+    let mut relay = tcp_relay(stream)?;
+    let established = run_client(&mut session, &socket, &mut relay)?;
+    relay.finish();
+    Ok(established)
+}
+
+/*
+ * Remote end: connect every session the server lets in to target,
+ * sessions whose target connection fails see EOF
+ */
+pub fn forward_remote(server: &mut Server, socket: &UdpSocket, target: SocketAddr) -> io::Result<()> {
+    serve(server, socket, None, |_, _| Some(connect_relay(target)))
+}
+
+/*
+ * Relay over a TCP connection to target. The connection is made on the
+ * relay's reader thread, so the packet loop goes on while it is pending.
+ */
+pub fn connect_relay(target: SocketAddr) -> Relay {
+    let (connected, stream) = sync_channel(1);
+    Relay::new(ConnectReader { target, stream: None, connected: Some(connected) },
+               ConnectWriter { connected: stream, stream: None })
+}
+
+// connects on the first read and hands the writer its own handle
+struct ConnectReader {
+    target: SocketAddr,
+    stream: Option<TcpStream>,
+    connected: Option<SyncSender<TcpStream>>
+}
+
+impl Read for ConnectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(connected) = self.connected.take() {
+            let timeout = Duration::from_secs(TUNNEL_CONNECT_TIMEOUT);
+            let stream = TcpStream::connect_timeout(&self.target, timeout)?;
+            let _ = connected.send(stream.try_clone()?);
+            self.stream = Some(stream);
+        }
+        match self.stream {
+            Some(ref mut stream) => stream.read(buf),
+            None => Ok(0)
+        }
+    }
+}
+
+// waits for the reader's connection, sends EOF to the target when dropped
+struct ConnectWriter {
+    connected: Receiver<TcpStream>,
+    stream: Option<TcpStream>
+}
+
+impl ConnectWriter {
+    fn stream(&mut self) -> io::Result<&mut TcpStream> {
+        if self.stream.is_none() {
+            let stream = self.connected.recv()
+                .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "target unreachable"))?;
+            self.stream = Some(stream);
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+}
+
+impl Write for ConnectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream()?.flush()
+    }
+}
+
+impl Drop for ConnectWriter {
+    fn drop(&mut self) {
+        if let Ok(stream) = self.stream() {
+            let _ = stream.shutdown(Shutdown::Write);
+        }
+    }
+}
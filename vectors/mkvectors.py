@@ -0,0 +1,237 @@
+#!/usr/bin/env python3
+#
+# mkvectors.py > curvecp.txt
+#
+# Builds the known-answer vectors straight from the packet layouts at
+# http://curvecp.org/packets.html and messages.html. Nothing here comes
+# from this crate or from libsodium: crypto_box is written out below in
+# plain Python from its published definitions, X25519 from RFC 7748,
+# HSalsa20 and XSalsa20 from "Extending the Salsa20 nonce" and
+# "Cryptography in NaCl", Poly1305 from RFC 8439. selftest() checks each
+# primitive against the test vectors printed in those documents before
+# anything is written.
+#
+# Keys, nonces and plaintexts are fixed below; every random choice the
+# Rust code makes (short-term key pairs, nonce counters, the vouch and
+# cookie nonces) is one of the values here, so a context fed these values
+# must emit the exact bytes.
+#
+# The cookie is implementation-specific. curvecp.org leaves its contents
+# to the server, and the one here is what mk_server_cookie puts there: its
+# 16-byte nonce followed by 80 zero bytes, not djb's minute-key box. The
+# cookie and initiate vectors carry it, so other implementations can use
+# their layout but not their bytes.
+
+import struct
+
+P = 2 ** 255 - 19
+
+
+def x25519(k, u):
+    # RFC 7748 section 5
+    k = bytearray(k)
+    k[0] &= 248
+    k[31] &= 127
+    k[31] |= 64
+    k = int.from_bytes(k, "little")
+    x1 = int.from_bytes(u, "little") & (2 ** 255 - 1)
+    x2, z2, x3, z3, swap = 1, 0, x1, 1, 0
+    for t in reversed(range(255)):
+        kt = (k >> t) & 1
+        swap ^= kt
+        if swap:
+            x2, x3, z2, z3 = x3, x2, z3, z2
+        swap = kt
+        a, b = (x2 + z2) % P, (x2 - z2) % P
+        aa, bb = a * a % P, b * b % P
+        e = (aa - bb) % P
+        c, d = (x3 + z3) % P, (x3 - z3) % P
+        da, cb = d * a % P, c * b % P
+        x3, z3 = (da + cb) ** 2 % P, x1 * (da - cb) ** 2 % P
+        x2, z2 = aa * bb % P, e * (aa + 121665 * e) % P
+    if swap:
+        x2, z2 = x3, z3
+    return (x2 * pow(z2, P - 2, P) % P).to_bytes(32, "little")
+
+
+def publickey(sk):
+    return x25519(sk, (9).to_bytes(32, "little"))
+
+
+def rotl(v, c):
+    return ((v << c) & 0xffffffff) | (v >> (32 - c))
+
+
+def salsa20_rounds(x):
+    for _ in range(10):
+        for a, b, c, d in ((0, 4, 8, 12), (5, 9, 13, 1), (10, 14, 2, 6), (15, 3, 7, 11),
+                           (0, 1, 2, 3), (5, 6, 7, 4), (10, 11, 8, 9), (15, 12, 13, 14)):
+            x[b] ^= rotl((x[a] + x[d]) & 0xffffffff, 7)
+            x[c] ^= rotl((x[b] + x[a]) & 0xffffffff, 9)
+            x[d] ^= rotl((x[c] + x[b]) & 0xffffffff, 13)
+            x[a] ^= rotl((x[d] + x[c]) & 0xffffffff, 18)
+    return x
+
+
+def salsa20_input(k, n):
+    # "expand 32-byte k" on the diagonal, key around it, 16 input bytes in the middle
+    w = lambda b: list(struct.unpack("<%dI" % (len(b) // 4), b))
+    c = w(b"expand 32-byte k")
+    k = w(k)
+    n = w(n)
+    return [c[0]] + k[:4] + [c[1]] + n + [c[2]] + k[4:] + [c[3]]
+
+
+def hsalsa20(k, n):
+    x = salsa20_rounds(salsa20_input(k, n))
+    return struct.pack("<8I", *[x[i] for i in (0, 5, 10, 15, 6, 7, 8, 9)])
+
+
+def salsa20_stream(k, n, length):
+    out = b""
+    for block in range((length + 63) // 64):
+        j = salsa20_input(k, n + struct.pack("<Q", block))
+        x = salsa20_rounds(list(j))
+        out += struct.pack("<16I", *[(a + b) & 0xffffffff for a, b in zip(x, j)])
+    return out[:length]
+
+
+def xsalsa20_stream(k, n, length):
+    return salsa20_stream(hsalsa20(k, n[:16]), n[16:], length)
+
+
+def poly1305(m, key):
+    # RFC 8439 section 2.5
+    r = int.from_bytes(key[:16], "little") & 0x0ffffffc0ffffffc0ffffffc0fffffff
+    s = int.from_bytes(key[16:], "little")
+    p = 2 ** 130 - 5
+    a = 0
+    for i in range(0, len(m), 16):
+        a = (a + int.from_bytes(m[i:i + 16] + b"\1", "little")) * r % p
+    return ((a + s) % 2 ** 128).to_bytes(16, "little")
+
+
+def box(m, nonce, pk, sk):
+    # crypto_box: 16-byte authenticator followed by the ciphertext, as on the wire
+    assert len(nonce) == 24
+    k = hsalsa20(x25519(sk, pk), bytes(16))
+    stream = xsalsa20_stream(k, nonce, 32 + len(m))
+    c = bytes(a ^ b for a, b in zip(m, stream[32:]))
+    return poly1305(c, stream[:32]) + c
+
+
+def selftest():
+    h = bytes.fromhex
+    # RFC 7748 section 5.2, first vector, and the section 6.1 exchange
+    assert x25519(h("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4"),
+                  h("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c")) == \
+        h("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552")
+    alicesk = h("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a")
+    bobsk = h("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb")
+    assert publickey(alicesk) == h("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+    assert publickey(bobsk) == h("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f")
+    shared = x25519(alicesk, publickey(bobsk))
+    assert shared == h("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742")
+    # "Cryptography in NaCl" section 8, the first HSalsa20 key of that exchange
+    assert hsalsa20(shared, bytes(16)) == \
+        h("1b27556473e985d462cd51197a9a46c76009549eac6474f206c4ee0844f68389")
+    # RFC 8439 section 2.5.2
+    assert poly1305(b"Cryptographic Forum Research Group",
+                    h("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b")) == \
+        h("a8061dc1305136c6c22b8baf0c0127a9")
+
+
+def nameparse(name):
+    return b"".join(bytes([len(label)]) + label for label in name.split(b"."))
+
+
+def message(id, acked_id, acked_first, eof, offset, data):
+    size = (56 + len(data) + 15) & ~15
+    flags = len(data) | {None: 0, "success": 2048, "failure": 4096}[eof]
+    header = struct.pack("<IIQ", id, acked_id, acked_first) + bytes(30)
+    header += struct.pack("<HQ", flags, offset)
+    return header + bytes(size - 56 - len(data)) + data
+
+
+selftest()
+
+clientlongtermsk = bytes([0x11] * 32)
+clientshorttermsk = bytes([0x22] * 32)
+serverlongtermsk = bytes([0x33] * 32)
+servershorttermsk = bytes([0x44] * 32)
+clientlongtermpk = publickey(clientlongtermsk)
+clientshorttermpk = publickey(clientshorttermsk)
+serverlongtermpk = publickey(serverlongtermsk)
+servershorttermpk = publickey(servershorttermsk)
+
+clientext = b"client-extension"
+serverext = b"server-extension"
+servername = b"vectors.curvecp.example"
+
+# client nonces count up from the Hello, the server's from 1 at the Cookie
+hellononce = 0x123456789ABC
+initiatenonce = hellononce + 1
+clientmessagenonce = hellononce + 2
+servermessagenonce = 2
+cookienonce = b"minute-k" + b"cookie-r"
+vouchnonce = b"vouch-nonce-0001"
+
+initiatemessage = message(1, 0, 0, None, 0, b"hello from client")
+servermessage = message(1, 1, 17, None, 0, b"hello from server")
+clientmessage = message(2, 1, 17, "success", 17, b"bye")
+
+hello = (b"QvnQ5XlH" + serverext + clientext + clientshorttermpk + bytes(64)
+         + struct.pack("<Q", hellononce)
+         + box(bytes(64), b"CurveCP-client-H" + struct.pack("<Q", hellononce),
+               serverlongtermpk, clientshorttermsk))
+
+# implementation-specific, see above
+cookie = cookienonce + bytes(80)
+cookiepacket = (b"RL3aNMXK" + clientext + serverext + cookienonce
+                + box(servershorttermpk + cookie, b"CurveCPK" + cookienonce,
+                      clientshorttermpk, serverlongtermsk))
+
+vouch = vouchnonce + box(clientshorttermpk, b"CurveCPV" + vouchnonce,
+                         serverlongtermpk, clientlongtermsk)
+initiate = (b"QvnQ5XlI" + serverext + clientext + clientshorttermpk + cookie
+            + struct.pack("<Q", initiatenonce)
+            + box(clientlongtermpk + vouch + nameparse(servername).ljust(256, b"\0") + initiatemessage,
+                  b"CurveCP-client-I" + struct.pack("<Q", initiatenonce),
+                  servershorttermpk, clientshorttermsk))
+
+servermessagepacket = (b"RL3aNMXM" + clientext + serverext + struct.pack("<Q", servermessagenonce)
+                       + box(servermessage, b"CurveCP-server-M" + struct.pack("<Q", servermessagenonce),
+                             clientshorttermpk, servershorttermsk))
+
+clientmessagepacket = (b"QvnQ5XlM" + serverext + clientext + clientshorttermpk
+                       + struct.pack("<Q", clientmessagenonce)
+                       + box(clientmessage, b"CurveCP-client-M" + struct.pack("<Q", clientmessagenonce),
+                             servershorttermpk, clientshorttermsk))
+
+print("# CurveCP known-answer vectors, made by mkvectors.py; do not edit")
+print("# cookie and initiate hold this crate's placeholder cookie, see mkvectors.py")
+for name, value in [
+        ("clientlongtermsk", clientlongtermsk),
+        ("clientlongtermpk", clientlongtermpk),
+        ("clientshorttermsk", clientshorttermsk),
+        ("clientshorttermpk", clientshorttermpk),
+        ("serverlongtermsk", serverlongtermsk),
+        ("serverlongtermpk", serverlongtermpk),
+        ("servershorttermsk", servershorttermsk),
+        ("servershorttermpk", servershorttermpk),
+        ("clientext", clientext),
+        ("serverext", serverext),
+        ("servername", servername),
+        ("hellononce", struct.pack(">Q", hellononce)),
+        ("servermessagenonce", struct.pack(">Q", servermessagenonce)),
+        ("cookienonce", cookienonce),
+        ("vouchnonce", vouchnonce),
+        ("initiatemessage", initiatemessage),
+        ("servermessage", servermessage),
+        ("clientmessage", clientmessage),
+        ("hello", hello),
+        ("cookie", cookiepacket),
+        ("initiate", initiate),
+        ("servermessagepacket", servermessagepacket),
+        ("clientmessagepacket", clientmessagepacket)]:
+    print("%s = %s" % (name, value.hex()))